@@ -0,0 +1,131 @@
+use inkwell::module::Module;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+};
+use inkwell::OptimizationLevel;
+
+use std::io::{self, Write};
+use std::path::Path;
+
+/// ネイティブコード生成の設定を定義
+#[derive(Debug, Clone)]
+pub struct TargetOptions {
+    /// ターゲットトリプル。Noneの場合はホストのトリプルを使用する
+    pub triple: Option<String>,
+    pub cpu: String,
+    pub features: String,
+    pub reloc_mode: RelocMode,
+    pub code_model: CodeModel,
+}
+
+impl Default for TargetOptions {
+    fn default() -> TargetOptions {
+        TargetOptions {
+            triple: None,
+            cpu: "generic".to_string(),
+            features: String::new(),
+            reloc_mode: RelocMode::Default,
+            code_model: CodeModel::Default,
+        }
+    }
+}
+
+/// '--reloc='に指定された名前からリロケーションモデルを取得
+pub fn parse_reloc_mode(name: &str) -> Option<RelocMode> {
+    match name {
+        "default" => Some(RelocMode::Default),
+        "static" => Some(RelocMode::Static),
+        "pic" => Some(RelocMode::PIC),
+        "dynamic-no-pic" => Some(RelocMode::DynamicNoPic),
+        _ => None,
+    }
+}
+
+/// '--code-model='に指定された名前からコードモデルを取得
+pub fn parse_code_model(name: &str) -> Option<CodeModel> {
+    match name {
+        "default" => Some(CodeModel::Default),
+        "small" => Some(CodeModel::Small),
+        "kernel" => Some(CodeModel::Kernel),
+        "medium" => Some(CodeModel::Medium),
+        "large" => Some(CodeModel::Large),
+        _ => None,
+    }
+}
+
+/// '-O'の数値をLLVMの最適化レベルに変換
+pub fn optimization_level(opt_level: u32) -> OptimizationLevel {
+    match opt_level {
+        0 => OptimizationLevel::None,
+        1 => OptimizationLevel::Less,
+        2 => OptimizationLevel::Default,
+        _ => OptimizationLevel::Aggressive,
+    }
+}
+
+/// 設定に従ってターゲットを初期化し、TargetMachineを作成
+pub fn create_target_machine(
+    options: &TargetOptions,
+    opt_level: u32,
+) -> Result<TargetMachine, String> {
+    let config = InitializationConfig::default();
+
+    // クロスコンパイルの場合は全てのターゲットを初期化する
+    let triple = match options.triple {
+        Some(ref triple) => {
+            Target::initialize_all(&config);
+
+            TargetTriple::create(triple)
+        }
+        None => {
+            Target::initialize_native(&config)?;
+
+            TargetMachine::get_default_triple()
+        }
+    };
+
+    let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+
+    target
+        .create_target_machine(
+            &triple,
+            &options.cpu,
+            &options.features,
+            optimization_level(opt_level),
+            options.reloc_mode,
+            options.code_model,
+        )
+        .ok_or_else(|| {
+            format!(
+                "could not create a target machine for CPU '{}' with features '{}'",
+                options.cpu, options.features
+            )
+        })
+}
+
+/// モジュールをオブジェクトファイル、またはアセンブリとして書き込む
+/// パスが'-'の場合は標準出力に書き込む
+pub fn write_module(
+    machine: &TargetMachine,
+    module: &Module,
+    file_type: FileType,
+    path: &str,
+) -> Result<(), String> {
+    // モジュールのターゲット情報をTargetMachineに合わせる
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    if path == "-" {
+        let buffer = machine
+            .write_to_memory_buffer(module, file_type)
+            .map_err(|err| err.to_string())?;
+
+        return io::stdout()
+            .write_all(buffer.as_slice())
+            .map_err(|err| err.to_string());
+    }
+
+    machine
+        .write_to_file(module, file_type, Path::new(path))
+        .map_err(|err| err.to_string())
+}
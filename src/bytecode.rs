@@ -0,0 +1,1264 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::*;
+use crate::vm::Builtin;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+/// '.ksc'ファイルの先頭に置かれる識別子
+const MAGIC: &[u8; 4] = b"KSC\0";
+
+/// '.ksc'形式のバージョン
+/// 命令やレイアウトを変更した場合は増やす
+const VERSION: u8 = 1;
+
+/// スタックマシンの命令
+/// 値は全てf64で、boolは0か1として表される
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    /// 定数を積む
+    Const(f64),
+    /// ローカル変数の値を積む
+    Load(u16),
+    /// 値を取り出してローカル変数に格納する
+    Store(u16),
+    LoadGlobal(u16),
+    StoreGlobal(u16),
+    /// 先頭の値を複製する
+    Dup,
+    /// 指定された数の値を捨てる
+    Pop(u16),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// JITと同じく、NaNを含む比較は1となる
+    Lt,
+    Gt,
+    /// 指定された位置の命令に移る
+    Jump(u32),
+    /// 値を取り出し、偽(0かNaN)の場合に移る
+    JumpIfFalse(u32),
+    /// 関数を呼び出し、引数を戻り値に置き換える
+    Call(u16),
+    /// 外部関数を呼び出し、引数を戻り値に置き換える
+    CallExtern(u16),
+    /// 値を取り出し、呼び出し元に返す
+    Return,
+}
+
+impl Instr {
+    /// 取り出す値と積む値の数を返す
+    /// 呼び出し先が存在しない場合はNoneとなる
+    pub fn stack_effect(self, program: &Program) -> Option<(usize, usize)> {
+        let effect = match self {
+            Instr::Const(_) | Instr::Load(_) | Instr::LoadGlobal(_) => (0, 1),
+            Instr::Store(_) | Instr::StoreGlobal(_) | Instr::JumpIfFalse(_) | Instr::Return => {
+                (1, 0)
+            }
+            Instr::Dup => (1, 2),
+            Instr::Pop(count) => (count as usize, 0),
+            Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Lt | Instr::Gt => (2, 1),
+            Instr::Jump(_) => (0, 0),
+            Instr::Call(chunk) => (program.chunks.get(chunk as usize)?.arity as usize, 1),
+            Instr::CallExtern(index) => (program.externs.get(index as usize)?.1 as usize, 1),
+        };
+
+        Some(effect)
+    }
+}
+
+/// バイトコードにコンパイルされた関数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub name: String,
+    pub arity: u16,
+    /// 引数を含むローカル変数のスロット数
+    pub locals: u16,
+    pub code: Vec<Instr>,
+}
+
+/// プログラムの読み込み時に、宣言順に実行される処理
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entry {
+    /// トップレベルの式を評価し、その値を返す
+    Expr(u16),
+    /// グローバル変数の初期化式を評価し、格納する
+    Global { chunk: u16, global: u16 },
+}
+
+/// バイトコードのプログラム
+/// 構文解析をせずに実行できるよう、'.ksc'ファイルとして保存される
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub chunks: Vec<Chunk>,
+    pub globals: Vec<String>,
+    /// 外部関数の名前と引数の数
+    pub externs: Vec<(String, u16)>,
+    pub entries: Vec<Entry>,
+}
+
+impl Program {
+    /// '.ksc'形式で書き出す
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+
+        put_u32(&mut buf, self.globals.len() as u32);
+
+        for name in &self.globals {
+            put_str(&mut buf, name);
+        }
+
+        put_u32(&mut buf, self.externs.len() as u32);
+
+        for &(ref name, arity) in &self.externs {
+            put_str(&mut buf, name);
+            put_u16(&mut buf, arity);
+        }
+
+        put_u32(&mut buf, self.chunks.len() as u32);
+
+        for chunk in &self.chunks {
+            put_str(&mut buf, &chunk.name);
+            put_u16(&mut buf, chunk.arity);
+            put_u16(&mut buf, chunk.locals);
+            put_u32(&mut buf, chunk.code.len() as u32);
+
+            for &instr in &chunk.code {
+                put_instr(&mut buf, instr);
+            }
+        }
+
+        put_u32(&mut buf, self.entries.len() as u32);
+
+        for &entry in &self.entries {
+            match entry {
+                Entry::Expr(chunk) => {
+                    buf.push(0);
+                    put_u16(&mut buf, chunk);
+                }
+                Entry::Global { chunk, global } => {
+                    buf.push(1);
+                    put_u16(&mut buf, chunk);
+                    put_u16(&mut buf, global);
+                }
+            }
+        }
+
+        out.write_all(&buf)
+    }
+
+    /// '.ksc'形式から読み込む
+    /// 形式が正しくない場合はInvalidDataのエラーとなる
+    /// 命令の検証は行わないため、実行前に'verify'で検査する
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Program> {
+        let mut bytes = Vec::new();
+
+        input.read_to_end(&mut bytes)?;
+
+        let mut reader = Reader {
+            bytes: &bytes,
+            pos: 0,
+        };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid_data("not a compiled Kaleidoscope program"));
+        }
+
+        let version = reader.u8()?;
+
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported format version {} (expected {})",
+                version, VERSION
+            )));
+        }
+
+        let mut program = Program::default();
+
+        for _ in 0..reader.u32()? {
+            program.globals.push(reader.string()?);
+        }
+
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+
+            program.externs.push((name, reader.u16()?));
+        }
+
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let arity = reader.u16()?;
+            let locals = reader.u16()?;
+            let mut code = Vec::new();
+
+            for _ in 0..reader.u32()? {
+                code.push(reader.instr()?);
+            }
+
+            program.chunks.push(Chunk {
+                name: name,
+                arity: arity,
+                locals: locals,
+                code: code,
+            });
+        }
+
+        for _ in 0..reader.u32()? {
+            let entry = match reader.u8()? {
+                0 => Entry::Expr(reader.u16()?),
+                1 => Entry::Global {
+                    chunk: reader.u16()?,
+                    global: reader.u16()?,
+                },
+                tag => return Err(invalid_data(&format!("invalid entry kind {}", tag))),
+            };
+
+            program.entries.push(entry);
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(invalid_data("trailing data after the program"));
+        }
+
+        Ok(program)
+    }
+
+    /// 全ての命令の参照先と、スタックの深さが正しいことを検証する
+    /// VMは検証されたプログラムのみを実行する
+    pub fn verify(&self) -> Result<(), String> {
+        for chunk in &self.chunks {
+            self.verify_chunk(chunk)
+                .map_err(|msg| format!("in function '{}': {}", chunk.name, msg))?;
+        }
+
+        for &entry in &self.entries {
+            let (chunk, global) = match entry {
+                Entry::Expr(chunk) => (chunk, None),
+                Entry::Global { chunk, global } => (chunk, Some(global)),
+            };
+
+            match self.chunks.get(chunk as usize) {
+                Some(chunk) if chunk.arity == 0 => (),
+                _ => return Err(format!("invalid top-level function {}", chunk)),
+            }
+
+            if global.map_or(false, |global| global as usize >= self.globals.len()) {
+                return Err("invalid global variable in top-level entry".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 関数の命令を分岐に沿ってたどり、各位置のスタックの深さが一致することを確かめる
+    fn verify_chunk(&self, chunk: &Chunk) -> Result<(), String> {
+        if chunk.arity > chunk.locals {
+            return Err("more parameters than local slots".to_string());
+        }
+
+        let len = chunk.code.len();
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        let mut pending = vec![(0, 0)];
+
+        while let Some((ip, depth)) = pending.pop() {
+            let instr = match chunk.code.get(ip) {
+                Some(&instr) => instr,
+                None => return Err(format!("control reaches the end of the code at {}", ip)),
+            };
+
+            match depths[ip] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    return Err(format!(
+                        "inconsistent stack depth at {} ({} and {})",
+                        ip, known, depth
+                    ))
+                }
+                None => depths[ip] = Some(depth),
+            }
+
+            let valid = match instr {
+                Instr::Load(slot) | Instr::Store(slot) => slot < chunk.locals,
+                Instr::LoadGlobal(global) | Instr::StoreGlobal(global) => {
+                    (global as usize) < self.globals.len()
+                }
+                Instr::Jump(target) | Instr::JumpIfFalse(target) => (target as usize) < len,
+                _ => true,
+            };
+
+            let (pops, pushes) = match instr.stack_effect(self) {
+                Some(effect) if valid => effect,
+                _ => return Err(format!("invalid operand in {:?} at {}", instr, ip)),
+            };
+
+            if pops > depth {
+                return Err(format!("stack underflow in {:?} at {}", instr, ip));
+            }
+
+            let depth = depth - pops + pushes;
+
+            match instr {
+                Instr::Return => (),
+                Instr::Jump(target) => pending.push((target as usize, depth)),
+                Instr::JumpIfFalse(target) => {
+                    pending.push((target as usize, depth));
+                    pending.push((ip + 1, depth));
+                }
+                _ => pending.push((ip + 1, depth)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 呼び出せる関数
+#[derive(Debug, Clone, Copy)]
+enum Callee {
+    Chunk(u16),
+    Extern(u16),
+}
+
+/// 構文木の要素からバイトコードのプログラムを組み立てる
+///
+/// LLVMのコンパイラと同じく、全ての要素を'declare'で宣言してから'compile'する。
+/// 扱えるのはf64の値のみで、文字列、配列、構造体、関数値を使う要素はエラーとなる。
+pub struct ProgramBuilder {
+    program: Program,
+    functions: HashMap<String, Callee>,
+    globals: HashMap<String, u16>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder {
+            program: Program::default(),
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+        }
+    }
+
+    /// 関数とグローバル変数を、後続の要素から参照できるように宣言する
+    /// 関数は全ての要素を実行する前にコンパイルされるため、同じ名前の関数を再び定義することはできない
+    pub fn declare(&mut self, item: &Item) -> Result<(), Diagnostic> {
+        match *item {
+            Item::Function(ref fun) if fun.is_anon => Ok(()),
+            Item::Function(ref fun) => self.declare_function(fun),
+            Item::Global(ref global) => {
+                if !self.globals.contains_key(&global.name) {
+                    let index =
+                        index_u16(self.program.globals.len(), "global variables", global.span)?;
+
+                    self.program.globals.push(global.name.clone());
+                    self.globals.insert(global.name.clone(), index);
+                }
+
+                Ok(())
+            }
+            Item::Struct(ref def) => Err(unsupported("Structs", def.span)),
+        }
+    }
+
+    fn declare_function(&mut self, fun: &Function) -> Result<(), Diagnostic> {
+        let proto = &fun.prototype;
+
+        if let Some(ty) = proto
+            .arg_types
+            .iter()
+            .chain(Some(&proto.ret_type))
+            .find(|ty| **ty != Type::F64)
+        {
+            return Err(unsupported_type(ty, proto.span));
+        }
+
+        let arity = proto.args.len() as u16;
+
+        let callee = match fun.body {
+            Some(_) => match self.functions.get(&proto.name) {
+                Some(&Callee::Chunk(_)) => {
+                    return Err(Diagnostic::error(format!(
+                        "Redefining function '{}' is not supported by the bytecode compiler.",
+                        proto.name
+                    ))
+                    .with_code("E0310")
+                    .with_primary(proto.span, "redefined here")
+                    .with_note("earlier calls would run the last definition in the bytecode VM"))
+                }
+                _ => Callee::Chunk(self.add_chunk(&proto.name, arity, proto.span)?),
+            },
+            // 定義された関数の前方宣言や、組み込み関数として提供されない外部関数は登録しない
+            // 後者は'compile'でエラーとなる
+            None => match self.functions.get(&proto.name) {
+                Some(_) => return Ok(()),
+                None if Builtin::from_name(&proto.name).map(Builtin::arity)
+                    == Some(proto.args.len()) =>
+                {
+                    let index =
+                        index_u16(self.program.externs.len(), "external functions", proto.span)?;
+
+                    self.program.externs.push((proto.name.clone(), arity));
+
+                    Callee::Extern(index)
+                }
+                None => return Ok(()),
+            },
+        };
+
+        self.functions.insert(proto.name.clone(), callee);
+
+        Ok(())
+    }
+
+    /// 宣言済みの要素をコンパイルする
+    /// トップレベルの式とグローバル変数の初期化式は、要素の順に実行される
+    pub fn compile(&mut self, item: &Item) -> Result<(), Diagnostic> {
+        match *item {
+            Item::Function(ref fun) => {
+                let body = match fun.body {
+                    Some(ref body) => body,
+                    None if self.functions.contains_key(&fun.prototype.name) => return Ok(()),
+                    None => {
+                        return Err(Diagnostic::error(format!(
+                            "External function '{}' is not available in the bytecode VM.",
+                            fun.prototype.name
+                        ))
+                        .with_code("E0310")
+                        .with_primary(fun.prototype.span, "declared here")
+                        .with_note(
+                            "only 'putchard', 'printd' and common math functions can be called",
+                        ))
+                    }
+                };
+
+                let chunk = if fun.is_anon {
+                    let chunk = self.add_chunk(&fun.prototype.name, 0, fun.span)?;
+
+                    self.program.entries.push(Entry::Expr(chunk));
+
+                    chunk
+                } else {
+                    match self.functions.get(&fun.prototype.name) {
+                        Some(&Callee::Chunk(chunk)) => chunk,
+                        _ => unreachable!("function '{}' was not declared", fun.prototype.name),
+                    }
+                };
+
+                self.compile_chunk(chunk, &fun.prototype.args, body)
+            }
+            Item::Global(ref global) => {
+                let chunk = self.add_chunk(&global.name, 0, global.span)?;
+
+                self.program.entries.push(Entry::Global {
+                    chunk: chunk,
+                    global: self.globals[&global.name],
+                });
+
+                self.compile_chunk(chunk, &[], &global.init)
+            }
+            Item::Struct(ref def) => Err(unsupported("Structs", def.span)),
+        }
+    }
+
+    /// 組み立てたプログラムを返す
+    pub fn finish(self) -> Program {
+        self.program
+    }
+
+    fn add_chunk(&mut self, name: &str, arity: u16, span: Span) -> Result<u16, Diagnostic> {
+        let index = index_u16(self.program.chunks.len(), "functions", span)?;
+
+        self.program.chunks.push(Chunk {
+            name: name.to_string(),
+            arity: arity,
+            locals: arity,
+            code: Vec::new(),
+        });
+
+        Ok(index)
+    }
+
+    fn compile_chunk(
+        &mut self,
+        chunk: u16,
+        params: &[String],
+        body: &Expr,
+    ) -> Result<(), Diagnostic> {
+        let mut compiler = FunctionCompiler {
+            builder: self,
+            code: Vec::new(),
+            variables: Vec::new(),
+            next_slot: 0,
+            max_slots: 0,
+            depth: 0,
+            loops: Vec::new(),
+        };
+
+        for param in params {
+            let slot = compiler.alloc_slot(body.span)?;
+
+            compiler.variables.push((param.clone(), slot));
+        }
+
+        compiler.compile_expr(body)?;
+        compiler.emit(Instr::Return);
+
+        let code = compiler.code;
+        let locals = compiler.max_slots;
+        let chunk = &mut self.program.chunks[chunk as usize];
+
+        chunk.code = code;
+        chunk.locals = locals;
+
+        Ok(())
+    }
+}
+
+/// コンパイル中のループ
+struct Loop {
+    /// ループに入る前のスタックの深さ
+    base: usize,
+    /// 'continue'の移動先
+    /// forループでは後から決まるため、Noneの間は'continues'に集める
+    continue_target: Option<u32>,
+    continues: Vec<usize>,
+    breaks: Vec<usize>,
+}
+
+/// 1つの関数の本体をバイトコードにコンパイルする
+/// 全ての式はちょうど1つの値を積む
+struct FunctionCompiler<'a> {
+    builder: &'a ProgramBuilder,
+    code: Vec<Instr>,
+    /// スコープ内のローカル変数とそのスロット
+    /// 内側の束縛ほど後ろに積まれる
+    variables: Vec<(String, u16)>,
+    next_slot: u16,
+    max_slots: u16,
+    /// 静的に追跡する、オペランドスタックの深さ
+    depth: usize,
+    loops: Vec<Loop>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn emit(&mut self, instr: Instr) -> usize {
+        let (pops, pushes) = instr
+            .stack_effect(&self.builder.program)
+            .expect("instruction refers to an undeclared function");
+
+        self.depth = self.depth - pops + pushes;
+        self.code.push(instr);
+
+        self.code.len() - 1
+    }
+
+    /// 分岐命令の移動先を、次に追加される命令の位置に設定する
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+
+        match self.code[at] {
+            Instr::Jump(ref mut dest) | Instr::JumpIfFalse(ref mut dest) => *dest = target,
+            _ => unreachable!("patching a non-jump instruction"),
+        }
+    }
+
+    fn alloc_slot(&mut self, span: Span) -> Result<u16, Diagnostic> {
+        let slot = self.next_slot;
+
+        self.next_slot = index_u16(slot as usize + 1, "local variables", span)?;
+        self.max_slots = self.max_slots.max(self.next_slot);
+
+        Ok(slot)
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<u16> {
+        self.variables
+            .iter()
+            .rev()
+            .find(|&&(ref var, _)| var == name)
+            .map(|&(_, slot)| slot)
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        match expr.kind {
+            ExprKind::Number(nb) => {
+                self.emit(Instr::Const(nb));
+            }
+
+            ExprKind::Variable(ref name) => {
+                let instr = self.variable(name, expr.span)?;
+
+                self.emit(instr);
+            }
+
+            ExprKind::Binary {
+                op: '=',
+                ref left,
+                ref right,
+            } => {
+                let store = match left.kind {
+                    ExprKind::Variable(ref name) => match self.variable(name, left.span)? {
+                        Instr::Load(slot) => Instr::Store(slot),
+                        Instr::LoadGlobal(global) => Instr::StoreGlobal(global),
+                        _ => unreachable!(),
+                    },
+                    ExprKind::Index { .. } => return Err(unsupported("Arrays", left.span)),
+                    ExprKind::Field { .. } => return Err(unsupported("Structs", left.span)),
+                    _ => {
+                        return Err(Diagnostic::error(
+                            "Expected variable as left-hand operator of assignement.",
+                        )
+                        .with_code("E0301")
+                        .with_primary(left.span, "cannot assign to this expression"))
+                    }
+                };
+
+                self.compile_expr(right)?;
+                self.emit(Instr::Dup);
+                self.emit(store);
+            }
+
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => {
+                let instr = match op {
+                    '+' => Some(Instr::Add),
+                    '-' => Some(Instr::Sub),
+                    '*' => Some(Instr::Mul),
+                    '/' => Some(Instr::Div),
+                    '<' => Some(Instr::Lt),
+                    '>' => Some(Instr::Gt),
+                    _ => None,
+                };
+
+                match instr {
+                    Some(instr) => {
+                        self.compile_expr(left)?;
+                        self.compile_expr(right)?;
+                        self.emit(instr);
+                    }
+                    None => {
+                        let name = format!("binary{}", op);
+
+                        self.compile_call(&name, &[&**left, &**right], expr.span)?;
+                    }
+                }
+            }
+
+            ExprKind::Call {
+                ref fn_name,
+                ref args,
+                ..
+            } => {
+                if self.lookup_local(fn_name).is_some() {
+                    return Err(unsupported("Function values", expr.span));
+                }
+
+                if intrinsic_arity(fn_name).is_some() {
+                    return Err(unsupported("Arrays", expr.span));
+                }
+
+                let args: Vec<&Expr> = args.iter().collect();
+
+                self.compile_call(fn_name, &args, expr.span)?;
+            }
+
+            // boolは数値として表されるため、f64への変換は不要
+            ExprKind::Cast(_) if expr.ty != Type::F64 => {
+                return Err(unsupported_type(&expr.ty, expr.span))
+            }
+            ExprKind::Cast(ref value) => self.compile_expr(value)?,
+
+            ExprKind::Conditional {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                self.compile_expr(cond)?;
+
+                let else_jump = self.emit(Instr::JumpIfFalse(0));
+
+                self.compile_expr(consequence)?;
+
+                let end_jump = self.emit(Instr::Jump(0));
+
+                self.depth -= 1;
+                self.patch(else_jump);
+                self.compile_expr(alternative)?;
+                self.patch(end_jump);
+            }
+
+            ExprKind::VarIn {
+                ref variables,
+                ref body,
+            } => {
+                let scope = self.variables.len();
+                let slots = self.next_slot;
+
+                // 初期化式からは、先に束縛された変数を参照できる
+                for &(ref name, ref ty, ref init) in variables {
+                    check_type(ty, expr.span)?;
+
+                    match *init {
+                        Some(ref init) => self.compile_expr(init)?,
+                        None => {
+                            self.emit(Instr::Const(0.0));
+                        }
+                    }
+
+                    let slot = self.alloc_slot(expr.span)?;
+
+                    self.emit(Instr::Store(slot));
+                    self.variables.push((name.clone(), slot));
+                }
+
+                self.compile_expr(body)?;
+                self.variables.truncate(scope);
+                self.next_slot = slots;
+            }
+
+            ExprKind::For {
+                ref var_name,
+                ref var_type,
+                ref start,
+                ref end,
+                ref step,
+                ref body,
+            } => {
+                check_type(var_type, expr.span)?;
+
+                self.compile_expr(start)?;
+
+                let slots = self.next_slot;
+                let var = self.alloc_slot(expr.span)?;
+                let step_slot = self.alloc_slot(expr.span)?;
+
+                self.emit(Instr::Store(var));
+                self.variables.push((var_name.clone(), var));
+
+                // JITと同じく、本体の後にステップと終了条件を評価してから変数を更新する
+                let top = self.code.len() as u32;
+
+                self.push_loop(None);
+                self.compile_expr(body)?;
+                self.emit(Instr::Pop(1));
+
+                let continues = self.loops.last_mut().unwrap().continues.split_off(0);
+
+                for at in continues {
+                    self.patch(at);
+                }
+
+                match *step {
+                    Some(ref step) => self.compile_expr(step)?,
+                    None => {
+                        self.emit(Instr::Const(1.0));
+                    }
+                }
+
+                self.emit(Instr::Store(step_slot));
+                self.compile_expr(end)?;
+                self.emit(Instr::Load(var));
+                self.emit(Instr::Load(step_slot));
+                self.emit(Instr::Add);
+                self.emit(Instr::Store(var));
+
+                let exit_jump = self.emit(Instr::JumpIfFalse(0));
+
+                self.emit(Instr::Jump(top));
+                self.patch(exit_jump);
+                self.pop_loop();
+                self.variables.pop();
+                self.next_slot = slots;
+            }
+
+            ExprKind::While { ref cond, ref body } => {
+                let top = self.code.len() as u32;
+
+                self.push_loop(Some(top));
+                self.compile_expr(cond)?;
+
+                let exit_jump = self.emit(Instr::JumpIfFalse(0));
+
+                self.compile_expr(body)?;
+                self.emit(Instr::Pop(1));
+                self.emit(Instr::Jump(top));
+                self.patch(exit_jump);
+                self.pop_loop();
+            }
+
+            ExprKind::Block(ref exprs) => {
+                if exprs.is_empty() {
+                    self.emit(Instr::Const(0.0));
+                }
+
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Instr::Pop(1));
+                    }
+
+                    self.compile_expr(expr)?;
+                }
+            }
+
+            ExprKind::Break(ref value) => {
+                let depth = self.depth;
+
+                self.unwind_to_loop();
+
+                match *value {
+                    Some(ref value) => self.compile_expr(value)?,
+                    None => {
+                        self.emit(Instr::Const(0.0));
+                    }
+                }
+
+                let at = self.emit(Instr::Jump(0));
+
+                self.loops.last_mut().unwrap().breaks.push(at);
+
+                // 以降の命令には到達しないが、式として値を積んだものとして扱う
+                self.depth = depth + 1;
+            }
+
+            ExprKind::Continue => {
+                let depth = self.depth;
+
+                self.unwind_to_loop();
+
+                let target = self.loops.last().unwrap().continue_target;
+                let at = self.emit(Instr::Jump(target.unwrap_or(0)));
+
+                if target.is_none() {
+                    self.loops.last_mut().unwrap().continues.push(at);
+                }
+
+                self.depth = depth + 1;
+            }
+
+            ExprKind::Return(ref value) => {
+                let depth = self.depth;
+
+                self.compile_expr(value)?;
+                self.emit(Instr::Return);
+                self.depth = depth + 1;
+            }
+
+            ExprKind::Str(_) => return Err(unsupported("Strings", expr.span)),
+
+            ExprKind::Array(_) | ExprKind::Index { .. } => {
+                return Err(unsupported("Arrays", expr.span))
+            }
+
+            ExprKind::Construct { .. } | ExprKind::Field { .. } => {
+                return Err(unsupported("Structs", expr.span))
+            }
+
+            ExprKind::Lambda { .. } => return Err(unsupported("Lambdas", expr.span)),
+        }
+
+        Ok(())
+    }
+
+    /// 変数の値を積む命令を返す
+    fn variable(&self, name: &str, span: Span) -> Result<Instr, Diagnostic> {
+        if let Some(slot) = self.lookup_local(name) {
+            return Ok(Instr::Load(slot));
+        }
+
+        if let Some(&global) = self.builder.globals.get(name) {
+            return Ok(Instr::LoadGlobal(global));
+        }
+
+        if self.builder.functions.contains_key(name) {
+            return Err(unsupported("Function values", span));
+        }
+
+        Err(Diagnostic::error("Could not find a matching variable.")
+            .with_code("E0300")
+            .with_primary(span, "not found in this scope"))
+    }
+
+    /// 引数を積み、定義された関数か外部関数を呼び出す
+    fn compile_call(&mut self, name: &str, args: &[&Expr], span: Span) -> Result<(), Diagnostic> {
+        let callee = match self.builder.functions.get(name) {
+            Some(&callee) => callee,
+            None => {
+                return Err(Diagnostic::error("Unknown function.")
+                    .with_code("E0302")
+                    .with_primary(span, "no function with this name"))
+            }
+        };
+
+        let (instr, arity) = match callee {
+            Callee::Chunk(chunk) => (
+                Instr::Call(chunk),
+                self.builder.program.chunks[chunk as usize].arity,
+            ),
+            Callee::Extern(index) => (
+                Instr::CallExtern(index),
+                self.builder.program.externs[index as usize].1,
+            ),
+        };
+
+        if arity as usize != args.len() {
+            return Err(Diagnostic::error(format!(
+                "Function '{}' takes {} argument(s) but {} were supplied.",
+                name,
+                arity,
+                args.len()
+            ))
+            .with_code("E0307")
+            .with_primary(span, "wrong number of arguments"));
+        }
+
+        for arg in args {
+            self.compile_expr(arg)?;
+        }
+
+        self.emit(instr);
+
+        Ok(())
+    }
+
+    fn push_loop(&mut self, continue_target: Option<u32>) {
+        self.loops.push(Loop {
+            base: self.depth,
+            continue_target: continue_target,
+            continues: Vec::new(),
+            breaks: Vec::new(),
+        });
+    }
+
+    /// ループの値を積み、'break'の移動先をループの後に設定する
+    /// 'break'で抜けた場合は、その値が代わりに積まれている
+    fn pop_loop(&mut self) {
+        let lp = self.loops.pop().unwrap();
+
+        self.emit(Instr::Const(0.0));
+
+        for at in lp.breaks {
+            self.patch(at);
+        }
+    }
+
+    /// 'break'と'continue'のために、ループの中で積まれた値を捨てる
+    fn unwind_to_loop(&mut self) {
+        let base = self.loops.last().expect("loop jump outside of a loop").base;
+
+        if self.depth > base {
+            let count = self.depth - base;
+
+            self.emit(Instr::Pop(count as u16));
+        }
+    }
+}
+
+/// バイトコードで扱えない型の注釈を拒否する
+/// boolは0か1の数値として表される
+fn check_type(ty: &Option<Type>, span: Span) -> Result<(), Diagnostic> {
+    match *ty {
+        Some(ref ty) if *ty != Type::F64 && *ty != Type::Bool => Err(unsupported_type(ty, span)),
+        _ => Ok(()),
+    }
+}
+
+fn unsupported(what: &str, span: Span) -> Diagnostic {
+    Diagnostic::error(format!(
+        "{} are not supported by the bytecode compiler.",
+        what
+    ))
+    .with_code("E0310")
+    .with_primary(span, "used here")
+    .with_note("the bytecode VM only handles 'f64' values")
+}
+
+fn unsupported_type(ty: &Type, span: Span) -> Diagnostic {
+    Diagnostic::error(format!(
+        "Type '{}' is not supported by the bytecode compiler.",
+        ty
+    ))
+    .with_code("E0310")
+    .with_primary(span, "used here")
+    .with_note("the bytecode VM only handles 'f64' values")
+}
+
+/// 命令のオペランドに収まる位置に変換する
+fn index_u16(index: usize, what: &str, span: Span) -> Result<u16, Diagnostic> {
+    if index > u16::max_value() as usize {
+        return Err(
+            Diagnostic::error(format!("Too many {} for the bytecode format.", what))
+                .with_code("E0324")
+                .with_primary(span, "limit exceeded here"),
+        );
+    }
+
+    Ok(index as u16)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// 命令を1バイトの命令コードとリトルエンディアンのオペランドとして書き出す
+fn put_instr(buf: &mut Vec<u8>, instr: Instr) {
+    match instr {
+        Instr::Const(value) => {
+            buf.push(0);
+            buf.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+        Instr::Load(slot) => {
+            buf.push(1);
+            put_u16(buf, slot);
+        }
+        Instr::Store(slot) => {
+            buf.push(2);
+            put_u16(buf, slot);
+        }
+        Instr::LoadGlobal(global) => {
+            buf.push(3);
+            put_u16(buf, global);
+        }
+        Instr::StoreGlobal(global) => {
+            buf.push(4);
+            put_u16(buf, global);
+        }
+        Instr::Dup => buf.push(5),
+        Instr::Pop(count) => {
+            buf.push(6);
+            put_u16(buf, count);
+        }
+        Instr::Add => buf.push(7),
+        Instr::Sub => buf.push(8),
+        Instr::Mul => buf.push(9),
+        Instr::Div => buf.push(10),
+        Instr::Lt => buf.push(11),
+        Instr::Gt => buf.push(12),
+        Instr::Jump(target) => {
+            buf.push(13);
+            put_u32(buf, target);
+        }
+        Instr::JumpIfFalse(target) => {
+            buf.push(14);
+            put_u32(buf, target);
+        }
+        Instr::Call(chunk) => {
+            buf.push(15);
+            put_u16(buf, chunk);
+        }
+        Instr::CallExtern(index) => {
+            buf.push(16);
+            put_u16(buf, index);
+        }
+        Instr::Return => buf.push(17),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// '.ksc'形式のバイト列を先頭から読み出す
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(invalid_data("unexpected end of file"));
+        }
+
+        let bytes = &self.bytes[self.pos..self.pos + len];
+
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        let mut bits = [0; 8];
+
+        bits.copy_from_slice(self.take(8)?);
+
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("invalid UTF-8 in a name"))
+    }
+
+    fn instr(&mut self) -> io::Result<Instr> {
+        let instr = match self.u8()? {
+            0 => Instr::Const(self.f64()?),
+            1 => Instr::Load(self.u16()?),
+            2 => Instr::Store(self.u16()?),
+            3 => Instr::LoadGlobal(self.u16()?),
+            4 => Instr::StoreGlobal(self.u16()?),
+            5 => Instr::Dup,
+            6 => Instr::Pop(self.u16()?),
+            7 => Instr::Add,
+            8 => Instr::Sub,
+            9 => Instr::Mul,
+            10 => Instr::Div,
+            11 => Instr::Lt,
+            12 => Instr::Gt,
+            13 => Instr::Jump(self.u32()?),
+            14 => Instr::JumpIfFalse(self.u32()?),
+            15 => Instr::Call(self.u16()?),
+            16 => Instr::CallExtern(self.u16()?),
+            17 => Instr::Return,
+            opcode => return Err(invalid_data(&format!("invalid opcode {}", opcode))),
+        };
+
+        Ok(instr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operators::OperatorTable;
+
+    /// ソースコードを構文解析し、バイトコードのプログラムにコンパイルする
+    fn compile(text: &str) -> Program {
+        let mut operators = OperatorTable::new();
+        let (items, diagnostics) = Parser::new(text.to_string(), &mut operators).parse_program();
+
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        let mut builder = ProgramBuilder::new();
+
+        for item in &items {
+            builder.declare(item).unwrap();
+        }
+
+        for item in &items {
+            builder.compile(item).unwrap();
+        }
+
+        builder.finish()
+    }
+
+    /// 指定された命令の関数のみを持つプログラムを作成する
+    fn single_chunk(locals: u16, code: Vec<Instr>) -> Program {
+        Program {
+            chunks: vec![Chunk {
+                name: "f".to_string(),
+                arity: 0,
+                locals: locals,
+                code: code,
+            }],
+            ..Program::default()
+        }
+    }
+
+    fn serialize(program: &Program) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        program.write_to(&mut bytes).unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = compile(
+            "extern printd(x)
+global g = 2
+def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2)
+printd(fib(10) + g)",
+        );
+        let bytes = serialize(&program);
+        let read = Program::read_from(&mut &bytes[..]).unwrap();
+
+        assert!(read.verify().is_ok());
+        assert_eq!(read, program);
+        assert_eq!(serialize(&read), bytes);
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let bytes = serialize(&compile("def f(x) x * 2\nf(3)"));
+
+        for len in 0..bytes.len() {
+            let err = Program::read_from(&mut &bytes[..len]).unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn bad_opcode_is_rejected() {
+        let mut bytes = serialize(&single_chunk(0, vec![Instr::Const(1.0), Instr::Return]));
+        // 最後の命令は、末尾にある要素の数(u32)の直前に置かれる
+        let last = bytes.len() - 5;
+
+        assert_eq!(bytes[last], 17);
+
+        bytes[last] = 0xff;
+
+        let err = Program::read_from(&mut &bytes[..]).unwrap_err();
+
+        assert!(err.to_string().contains("invalid opcode 255"), "{}", err);
+    }
+
+    #[test]
+    fn stack_underflow_is_rejected() {
+        let program = single_chunk(0, vec![Instr::Const(1.0), Instr::Add, Instr::Return]);
+        let err = program.verify().unwrap_err();
+
+        assert!(err.contains("stack underflow"), "{}", err);
+    }
+
+    #[test]
+    fn out_of_range_jump_is_rejected() {
+        let program = single_chunk(0, vec![Instr::Jump(5), Instr::Return]);
+        let err = program.verify().unwrap_err();
+
+        assert!(err.contains("invalid operand in Jump(5)"), "{}", err);
+    }
+
+    #[test]
+    fn out_of_range_slot_is_rejected() {
+        let program = single_chunk(1, vec![Instr::Load(1), Instr::Return]);
+        let err = program.verify().unwrap_err();
+
+        assert!(err.contains("invalid operand in Load(1)"), "{}", err);
+    }
+}
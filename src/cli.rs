@@ -0,0 +1,235 @@
+/// コマンドラインの使い方
+pub const USAGE: &str = "\
+Usage: kaleidscope [options] [<input>...]
+       kaleidscope -a [--dl] [--dp] [--dc]
+
+Compiles Kaleidoscope source files. Use '-' to read from standard input.
+When no input is given, 'input.ks' is compiled.
+
+Options:
+    -o <path>        Write output to <path> ('-' writes to standard output)
+    --emit=<kind>    Output kind: tokens, ast, llvm-ir, bitcode, ksc, asm, obj,
+                     exe (default: llvm-ir). 'ksc' is bytecode for --vm. 'exe'
+                     links the program with a small runtime using the system
+                     C compiler ('cc', or $CC)
+    --vm             Run the program on the bytecode VM instead of compiling it.
+                     The input may also be a single '.ksc' file
+    -O0 .. -O3       Optimization level (default: -O2)
+    --checks         Insert runtime checks for division by zero, NaN results,
+                     array indices and call depth, aborting with the failing
+                     location. Integer division by zero is always checked
+    --no-checks      Do not insert runtime checks (default)
+    --target=<triple>      Target triple for asm/obj output (default: host)
+    --cpu=<name>           Target CPU (default: generic)
+    --features=<list>      Target features, e.g. '+avx2,-sse4.1'
+    --reloc=<model>        Relocation model: default, static, pic, dynamic-no-pic
+    --code-model=<model>   Code model: default, small, kernel, medium, large
+    -a, --repl       Start the interactive REPL
+    --interp         Evaluate REPL input with the tree-walking interpreter
+                     instead of the LLVM JIT
+    --difftest       Run the built-in corpus and the given files through both
+                     the JIT and the interpreter, reporting any mismatch
+    --fuzz[=<count>] Compile <count> random programs (default: 100), reporting
+                     any that panic or fail to parse, compile or verify
+    --seed=<n>       Seed for --fuzz (default: current time)
+    --dl, --dp, --dc Display lexer, parser or compiler output in the REPL
+    -h, --help       Print this message
+
+Exit status:
+    0  success
+    1  invalid arguments, I/O failure, or a --difftest or --fuzz failure
+    2  lexing or parsing failed
+    3  code generation failed";
+
+use crate::backend::{self, TargetOptions};
+
+/// '--fuzz'で生成するプログラムの既定の数
+const DEFAULT_FUZZ_COUNT: u64 = 100;
+
+/// コンパイラの出力形式を定義
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    LlvmIr,
+    Bitcode,
+    /// VMで実行するバイトコード
+    Ksc,
+    Asm,
+    Obj,
+    Exe,
+}
+
+impl Emit {
+    /// '--emit='に指定された名前から出力形式を取得
+    fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "tokens" => Some(Emit::Tokens),
+            "ast" => Some(Emit::Ast),
+            "llvm-ir" => Some(Emit::LlvmIr),
+            "bitcode" => Some(Emit::Bitcode),
+            "ksc" => Some(Emit::Ksc),
+            "asm" => Some(Emit::Asm),
+            "obj" => Some(Emit::Obj),
+            "exe" => Some(Emit::Exe),
+            _ => None,
+        }
+    }
+
+    /// 出力ファイルのデフォルトの拡張子を返す
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::Tokens => "tokens",
+            Emit::Ast => "ast",
+            Emit::LlvmIr => "ll",
+            Emit::Bitcode => "bc",
+            Emit::Ksc => "ksc",
+            Emit::Asm => "s",
+            Emit::Obj => "o",
+            Emit::Exe => "",
+        }
+    }
+}
+
+/// コマンドライン引数から得られたオプションを定義
+#[derive(Debug)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub emit: Emit,
+    pub opt_level: u32,
+    pub target: TargetOptions,
+    pub repl: bool,
+    pub help: bool,
+    pub display_lexer_output: bool,
+    pub display_parser_output: bool,
+    pub display_compiler_output: bool,
+    pub checks: bool,
+    pub interpret: bool,
+    pub difftest: bool,
+    pub vm: bool,
+    /// 生成して検査するプログラムの数
+    pub fuzz: Option<u64>,
+    pub seed: Option<u64>,
+}
+
+impl Options {
+    /// コマンドライン引数(プログラム名を除く)を解析
+    /// 不正な引数の場合はErrに表示すべきメッセージを返す
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options = Options {
+            inputs: Vec::new(),
+            output: None,
+            emit: Emit::LlvmIr,
+            opt_level: 2,
+            target: TargetOptions::default(),
+            repl: false,
+            help: false,
+            display_lexer_output: false,
+            display_parser_output: false,
+            display_compiler_output: false,
+            checks: false,
+            interpret: false,
+            difftest: false,
+            vm: false,
+            fuzz: None,
+            seed: None,
+        };
+
+        let mut args = args;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-a" | "--repl" => options.repl = true,
+                "--dl" => options.display_lexer_output = true,
+                "--dp" => options.display_parser_output = true,
+                "--dc" => options.display_compiler_output = true,
+                "-h" | "--help" => options.help = true,
+                "--checks" => options.checks = true,
+                "--no-checks" => options.checks = false,
+                "--interp" => options.interpret = true,
+                "--difftest" => options.difftest = true,
+                "--vm" => options.vm = true,
+                "--fuzz" => options.fuzz = Some(DEFAULT_FUZZ_COUNT),
+
+                "-o" => match args.next() {
+                    Some(path) => options.output = Some(path),
+                    None => return Err("Expected a path after '-o'.".to_string()),
+                },
+
+                "-O0" => options.opt_level = 0,
+                "-O1" => options.opt_level = 1,
+                "-O2" => options.opt_level = 2,
+                "-O3" => options.opt_level = 3,
+
+                // 標準入力
+                "-" => options.inputs.push(arg),
+
+                arg if arg.starts_with("--emit=") => {
+                    let name = &arg["--emit=".len()..];
+
+                    options.emit = Emit::from_name(name)
+                        .ok_or_else(|| format!("Unknown emit kind '{}'.", name))?;
+                }
+
+                arg if arg.starts_with("--fuzz=") => {
+                    let count = &arg["--fuzz=".len()..];
+
+                    options.fuzz = Some(
+                        count
+                            .parse()
+                            .map_err(|_| format!("Invalid program count '{}'.", count))?,
+                    );
+                }
+
+                arg if arg.starts_with("--seed=") => {
+                    let seed = &arg["--seed=".len()..];
+
+                    options.seed = Some(
+                        seed.parse()
+                            .map_err(|_| format!("Invalid seed '{}'.", seed))?,
+                    );
+                }
+
+                arg if arg.starts_with("--target=") => {
+                    options.target.triple = Some(arg["--target=".len()..].to_string());
+                }
+
+                arg if arg.starts_with("--cpu=") => {
+                    options.target.cpu = arg["--cpu=".len()..].to_string();
+                }
+
+                arg if arg.starts_with("--features=") => {
+                    options.target.features = arg["--features=".len()..].to_string();
+                }
+
+                arg if arg.starts_with("--reloc=") => {
+                    let name = &arg["--reloc=".len()..];
+
+                    options.target.reloc_mode = backend::parse_reloc_mode(name)
+                        .ok_or_else(|| format!("Unknown relocation model '{}'.", name))?;
+                }
+
+                arg if arg.starts_with("--code-model=") => {
+                    let name = &arg["--code-model=".len()..];
+
+                    options.target.code_model = backend::parse_code_model(name)
+                        .ok_or_else(|| format!("Unknown code model '{}'.", name))?;
+                }
+
+                arg if arg.starts_with('-') => {
+                    return Err(format!("Unknown option '{}'.\n\n{}", arg, USAGE));
+                }
+
+                _ => options.inputs.push(arg),
+            }
+        }
+
+        // 差分テストでは、ファイルは組み込みのコーパスに追加して検査される
+        if options.inputs.is_empty() && !options.difftest && options.fuzz.is_none() {
+            options.inputs.push("input.ks".to_string());
+        }
+
+        Ok(options)
+    }
+}
@@ -1,14 +1,48 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
 use crate::parser::*;
+use crate::typeck::{Declarations, StructTable, TypeChecker};
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
+use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassManager;
-use inkwell::types::BasicTypeEnum;
-use inkwell::values::{BasicValueEnum, FloatValue, FunctionValue, PointerValue};
-use inkwell::FloatPredicate;
-use std::borrow::Borrow;
+use inkwell::types::{BasicTypeEnum, FunctionType, StructType};
+use inkwell::values::{BasicValueEnum, FunctionValue, GlobalValue, IntValue, PointerValue};
+use inkwell::AddressSpace;
+use inkwell::{FloatPredicate, IntPredicate};
 
 use std::collections::HashMap;
+use std::mem;
+
+/// 定数に畳み込めないグローバル変数の初期化関数の接頭辞
+pub const GLOBAL_INIT_PREFIX: &str = "__ks_init_";
+
+/// 配列を確保するランタイム関数のシンボル名
+/// 配列は'{ i64 len, [0 x double] data }'へのポインタとして表現される
+pub const ARRAY_NEW_SYMBOL: &str = "ks_array_new";
+
+/// 全てのグローバル変数の初期化関数を呼び出す、モジュールのコンストラクタの名前
+pub const GLOBAL_CTOR_NAME: &str = "__ks_init_globals";
+
+/// 実行時検査に失敗した場所を表示して異常終了するランタイム関数のシンボル名
+pub const TRAP_SYMBOL: &str = "ks_trap";
+
+/// 関数呼び出しの深さを数えるランタイムのグローバル変数のシンボル名
+pub const CALL_DEPTH_SYMBOL: &str = "ks_call_depth";
+
+/// 実行時検査が有効な場合に許される、関数呼び出しの最大の深さ
+pub const MAX_CALL_DEPTH: u64 = 10_000;
+
+/// コンパイル中のループで、'break'と'continue'の分岐先を表す
+struct LoopContext<'ctx> {
+    /// 'continue'で分岐する、次の繰り返しを始めるブロック
+    continue_bb: BasicBlock<'ctx>,
+    /// 'break'で分岐する、ループの後のブロック
+    break_bb: BasicBlock<'ctx>,
+    /// 'break'で渡された値と、分岐元のブロック
+    break_values: Vec<(BasicValueEnum<'ctx>, BasicBlock<'ctx>)>,
+}
 
 /// 式コンパイラの定義
 pub struct Compiler<'a, 'ctx> {
@@ -17,9 +51,14 @@ pub struct Compiler<'a, 'ctx> {
     pub fpm: &'a PassManager<FunctionValue<'ctx>>,
     pub module: &'a Module<'ctx>,
     pub function: &'a Function,
+    /// 除算、NaN、配列の添字、呼び出しの深さの実行時検査を挿入するかどうか
+    /// 整数の0除算は、この設定に関係なく常に検査される
+    pub checks: bool,
 
     variables: HashMap<String, PointerValue<'ctx>>,
     fn_value_opt: Option<FunctionValue<'ctx>>,
+    /// コンパイル中のループ。内側のループほど後ろに積まれる
+    loops: Vec<LoopContext<'ctx>>,
 }
 
 impl<'a, 'ctx> Compiler<'a, 'ctx> {
@@ -35,8 +74,20 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         self.fn_value_opt.unwrap()
     }
 
+    /// 指定された名前の変数のポインタを取得
+    /// ローカル変数が見つからない場合は、モジュールのグローバル変数を探す
+    fn lookup_variable(&self, name: &str) -> Option<PointerValue<'ctx>> {
+        match self.variables.get(name) {
+            Some(var) => Some(*var),
+            None => self
+                .module
+                .get_global(name)
+                .map(|global| global.as_pointer_value()),
+        }
+    }
+
     /// 関数のエントリーブロックに新たなstack alloca 命令を作成
-    fn create_entry_block_alloca(&self, name: &str) -> PointerValue<'ctx> {
+    fn create_entry_block_alloca(&self, name: &str, ty: &Type) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
 
         let entry = self.fn_value().get_first_basic_block().unwrap();
@@ -46,189 +97,930 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
             None => builder.position_at_end(&entry),
         }
 
-        builder.build_alloca(self.context.f64_type(), name)
+        builder.build_alloca(llvm_type(self.context, self.module, ty), name)
+    }
+
+    /// 指定された型の定数を作成
+    /// 文字列の場合は空文字列、配列の場合は空の配列となる
+    fn const_number(&self, ty: &Type, nb: f64) -> BasicValueEnum<'ctx> {
+        match *ty {
+            Type::Str => self.const_string(""),
+            Type::Array => {
+                let len = self.context.i64_type().const_int(0, false);
+
+                self.build_array_alloc(len).into()
+            }
+            Type::F64 => self.context.f64_type().const_float(nb).into(),
+            Type::Bool => self
+                .context
+                .bool_type()
+                .const_int((nb != 0.0) as u64, false)
+                .into(),
+            Type::I32 | Type::I64 => llvm_type(self.context, self.module, ty)
+                .into_int_type()
+                .const_int(nb as i64 as u64, true)
+                .into(),
+            // 構造体と関数値の変数は初期化が必須のため、到達しない'break'の後でのみ使われる
+            Type::Struct(_) | Type::Fn(..) => llvm_type(self.context, self.module, ty)
+                .into_pointer_type()
+                .const_null()
+                .into(),
+        }
+    }
+
+    /// 配列を確保するランタイム関数を取得し、宣言されていなければ宣言する
+    fn array_new_fn(&self) -> FunctionValue<'ctx> {
+        match self.module.get_function(ARRAY_NEW_SYMBOL) {
+            Some(fun) => fun,
+            None => {
+                let fn_type = llvm_type(self.context, self.module, &Type::Array)
+                    .fn_type(&[self.context.i64_type().into()], false);
+
+                self.module.add_function(ARRAY_NEW_SYMBOL, fn_type, None)
+            }
+        }
+    }
+
+    /// 長さ'len'の配列をヒープに確保する
+    /// 要素は0で初期化される
+    fn build_array_alloc(&self, len: IntValue<'ctx>) -> PointerValue<'ctx> {
+        self.builder
+            .build_call(self.array_new_fn(), &[len.into()], "array")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    /// 配列の長さを格納するフィールドへのポインタを返す
+    fn len_ptr(&self, array: PointerValue<'ctx>) -> PointerValue<'ctx> {
+        let zero = self.context.i32_type().const_int(0, false);
+
+        unsafe {
+            self.builder
+                .build_in_bounds_gep(array, &[zero, zero], "lenptr")
+        }
     }
 
-    /// 指定された式'Expr'をLLVM FloatValueにコンパイル
-    fn compile_expr(&mut self, expr: &Expr) -> Result<FloatValue<'ctx>, &'static str> {
-        match *expr {
-            Expr::Number(nb) => Ok(self.context.f64_type().const_float(nb)),
+    /// 配列の'index'番目の要素へのポインタを返す
+    fn element_ptr(&self, array: PointerValue<'ctx>, index: IntValue<'ctx>) -> PointerValue<'ctx> {
+        let zero = self.context.i32_type().const_int(0, false);
+        let data = self.context.i32_type().const_int(1, false);
 
-            Expr::Variable(ref name) => match self.variables.get(name.as_str()) {
-                Some(var) => Ok(self
+        unsafe {
+            self.builder
+                .build_in_bounds_gep(array, &[zero, data, index], "elemptr")
+        }
+    }
+
+    /// 構造体の'index'番目のフィールドへのポインタを返す
+    fn field_ptr(&self, value: PointerValue<'ctx>, index: usize) -> PointerValue<'ctx> {
+        let zero = self.context.i32_type().const_int(0, false);
+        let field = self.context.i32_type().const_int(index as u64, false);
+
+        unsafe {
+            self.builder
+                .build_in_bounds_gep(value, &[zero, field], "fieldptr")
+        }
+    }
+
+    /// 構造体の式をコンパイルし、'index'番目のフィールドへのポインタを返す
+    fn compile_field_ptr(
+        &mut self,
+        value: &Expr,
+        index: usize,
+    ) -> Result<PointerValue<'ctx>, Diagnostic> {
+        let value = self.compile_expr(value)?.into_pointer_value();
+
+        Ok(self.field_ptr(value, index))
+    }
+
+    /// 配列と添字の式をコンパイルし、要素へのポインタを返す
+    /// 実行時検査が有効な場合は、添字が範囲外であれば異常終了する
+    fn compile_element_ptr(
+        &mut self,
+        array: &Expr,
+        index: &Expr,
+    ) -> Result<PointerValue<'ctx>, Diagnostic> {
+        let array = self.compile_expr(array)?.into_pointer_value();
+        let index_val = self.compile_expr(index)?.into_int_value();
+
+        if self.checks {
+            let len = self
+                .builder
+                .build_load(self.len_ptr(array), "len")
+                .into_int_value();
+            // 符号なしで比較するため、負の添字も範囲外となる
+            let out_of_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::UGE, index_val, len, "outofbounds");
+
+            self.build_check(out_of_bounds, "array index out of bounds", index.span);
+        }
+
+        Ok(self.element_ptr(array, index_val))
+    }
+
+    /// 組み込み関数の呼び出しをコンパイル
+    fn compile_intrinsic(
+        &mut self,
+        fn_name: &str,
+        args: &[Expr],
+    ) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        let arg = self.compile_expr(&args[0])?;
+
+        match fn_name {
+            "array" => Ok(self.build_array_alloc(arg.into_int_value()).into()),
+            _ => Ok(self
+                .builder
+                .build_load(self.len_ptr(arg.into_pointer_value()), "len")),
+        }
+    }
+
+    /// 文字列定数をモジュールに追加し、その先頭を指すポインタを返す
+    fn const_string(&self, value: &str) -> BasicValueEnum<'ctx> {
+        self.builder
+            .build_global_string_ptr(value, "str")
+            .as_pointer_value()
+            .into()
+    }
+
+    /// 条件として使われる値をi1に変換
+    /// boolはそのまま使い、数値は0と比較する
+    fn build_truth(&self, value: BasicValueEnum<'ctx>, ty: &Type, name: &str) -> IntValue<'ctx> {
+        match *ty {
+            Type::Bool => value.into_int_value(),
+            Type::F64 => self.builder.build_float_compare(
+                FloatPredicate::ONE,
+                value.into_float_value(),
+                self.context.f64_type().const_float(0.0),
+                name,
+            ),
+            Type::I32 | Type::I64 => self.builder.build_int_compare(
+                IntPredicate::NE,
+                value.into_int_value(),
+                self.const_number(ty, 0.0).into_int_value(),
+                name,
+            ),
+            Type::Str | Type::Array | Type::Struct(_) | Type::Fn(..) => {
+                unreachable!("only booleans and numbers are accepted as conditions")
+            }
+        }
+    }
+
+    /// 値を'from'型から'to'型に変換
+    fn build_cast(
+        &self,
+        value: BasicValueEnum<'ctx>,
+        from: &Type,
+        to: &Type,
+    ) -> BasicValueEnum<'ctx> {
+        let to_type = llvm_type(self.context, self.module, to);
+
+        match (from, to) {
+            _ if from == to => value,
+            (Type::Bool, Type::F64) => self
+                .builder
+                .build_unsigned_int_to_float(
+                    value.into_int_value(),
+                    to_type.into_float_type(),
+                    "booltmp",
+                )
+                .into(),
+            // trueは-1ではなく1となるように、符号拡張はしない
+            (Type::Bool, _) => self
+                .builder
+                .build_int_z_extend(value.into_int_value(), to_type.into_int_type(), "booltmp")
+                .into(),
+            (_, Type::F64) => self
+                .builder
+                .build_signed_int_to_float(
+                    value.into_int_value(),
+                    to_type.into_float_type(),
+                    "casttmp",
+                )
+                .into(),
+            (Type::F64, _) => self
+                .builder
+                .build_float_to_signed_int(
+                    value.into_float_value(),
+                    to_type.into_int_type(),
+                    "casttmp",
+                )
+                .into(),
+            (_, _) => self
+                .builder
+                .build_int_cast(value.into_int_value(), to_type.into_int_type(), "casttmp")
+                .into(),
+        }
+    }
+
+    /// 関数を呼び出し、その戻り値を返す
+    fn build_call(
+        &mut self,
+        fun: FunctionValue<'ctx>,
+        args: &[&Expr],
+        name: &str,
+        span: Span,
+        label: &str,
+    ) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        let mut compiled_args = Vec::with_capacity(args.len());
+
+        for arg in args {
+            compiled_args.push(self.compile_expr(arg)?);
+        }
+
+        match self
+            .builder
+            .build_call(fun, compiled_args.as_slice(), name)
+            .try_as_basic_value()
+            .left()
+        {
+            Some(value) => Ok(value),
+            None => Err(Diagnostic::error("Invalid call produced.")
+                .with_code("E0322")
+                .with_primary(span, label)),
+        }
+    }
+
+    /// 関数へのポインタと環境から、関数値'{ code*, i8* env }'をヒープに作成する
+    fn build_closure(
+        &self,
+        code: FunctionValue<'ctx>,
+        env: PointerValue<'ctx>,
+        ty: &Type,
+    ) -> PointerValue<'ctx> {
+        let closure_type = llvm_type(self.context, self.module, ty)
+            .into_pointer_type()
+            .get_element_type()
+            .into_struct_type();
+        let closure = self.builder.build_malloc(closure_type, "closure");
+
+        self.builder.build_store(
+            self.field_ptr(closure, 0),
+            code.as_global_value().as_pointer_value(),
+        );
+        self.builder.build_store(self.field_ptr(closure, 1), env);
+
+        closure
+    }
+
+    /// 定義済みの関数を関数値として返す
+    /// 環境を受け取って関数を呼び出すラッパーを作成し、空の環境と組にする
+    fn build_function_value(&mut self, fun: FunctionValue<'ctx>, ty: &Type) -> PointerValue<'ctx> {
+        let name = format!("{}.closure", fun.get_name().to_str().unwrap());
+
+        let wrapper = match self.module.get_function(&name) {
+            Some(wrapper) => wrapper,
+            None => {
+                let code_type = closure_code_type(self.context, self.module, ty);
+                let wrapper = self
+                    .module
+                    .add_function(&name, code_type, Some(Linkage::Internal));
+                let current = self.builder.get_insert_block().unwrap();
+                let entry = self.context.append_basic_block(wrapper, "entry");
+
+                self.builder.position_at_end(&entry);
+
+                let args: Vec<BasicValueEnum> = wrapper.get_param_iter().skip(1).collect();
+                let result = self
                     .builder
-                    .build_load(*var, name.as_str())
-                    .into_float_value()),
-                None => Err("Could not find a matching variable."),
+                    .build_call(fun, &args, "tmp")
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap();
+
+                self.builder.build_return(Some(&result));
+                self.builder.position_at_end(&current);
+
+                wrapper
+            }
+        };
+
+        let env = self
+            .context
+            .i8_type()
+            .ptr_type(AddressSpace::Generic)
+            .const_null();
+
+        self.build_closure(wrapper, env, ty)
+    }
+
+    /// 関数値を呼び出し、その戻り値を返す
+    /// 関数へのポインタには、環境が最初の引数として渡される
+    fn build_closure_call(
+        &mut self,
+        closure: PointerValue<'ctx>,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        let code = self
+            .builder
+            .build_load(self.field_ptr(closure, 0), "code")
+            .into_pointer_value();
+        let env = self.builder.build_load(self.field_ptr(closure, 1), "env");
+
+        let mut compiled_args = Vec::with_capacity(args.len() + 1);
+
+        compiled_args.push(env);
+
+        for arg in args {
+            compiled_args.push(self.compile_expr(arg)?);
+        }
+
+        match self
+            .builder
+            .build_call(code, compiled_args.as_slice(), "tmp")
+            .try_as_basic_value()
+            .left()
+        {
+            Some(value) => Ok(value),
+            None => Err(Diagnostic::error("Invalid call produced.")
+                .with_code("E0322")
+                .with_primary(span, "in this call")),
+        }
+    }
+
+    /// 無名関数を別の関数としてコンパイルし、キャプチャした変数の環境と組にした関数値を返す
+    fn compile_lambda(
+        &mut self,
+        params: &[(String, Option<Type>)],
+        body: &Expr,
+        captures: &[(String, Type)],
+        ty: &Type,
+        span: Span,
+    ) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        // キャプチャした変数は、作成時の値を環境の構造体にコピーする
+        let capture_types = captures
+            .iter()
+            .map(|&(_, ref ty)| llvm_type(self.context, self.module, ty))
+            .collect::<Vec<BasicTypeEnum>>();
+        let env_type = self.context.struct_type(&capture_types, false);
+
+        let env = if captures.is_empty() {
+            i8_ptr_type.const_null()
+        } else {
+            let env = self.builder.build_malloc(env_type, "env");
+
+            for (i, &(ref name, _)) in captures.iter().enumerate() {
+                let value = self.builder.build_load(self.variables[name], name);
+
+                self.builder.build_store(self.field_ptr(env, i), value);
+            }
+
+            self.builder.build_pointer_cast(env, i8_ptr_type, "envptr")
+        };
+
+        let code_type = closure_code_type(self.context, self.module, ty);
+        let lambda = self
+            .module
+            .add_function("lambda", code_type, Some(Linkage::Internal));
+
+        // 外側の関数の状態を退避し、無名関数の本体をコンパイルする
+        let current = self.builder.get_insert_block().unwrap();
+        let outer_fn = self.fn_value_opt.replace(lambda);
+        let outer_variables = mem::replace(&mut self.variables, HashMap::new());
+        let outer_loops = mem::replace(&mut self.loops, Vec::new());
+
+        let entry = self.context.append_basic_block(lambda, "entry");
+
+        self.builder.position_at_end(&entry);
+
+        let mut lambda_params = lambda.get_param_iter();
+        let env_param = lambda_params.next().unwrap().into_pointer_value();
+
+        for (&(ref name, ref param_type), arg) in params.iter().zip(lambda_params) {
+            let param_type = param_type.clone().unwrap_or(Type::F64);
+            let alloca = self.create_entry_block_alloca(name, &param_type);
+
+            self.builder.build_store(alloca, arg);
+            self.variables.insert(name.clone(), alloca);
+        }
+
+        if !captures.is_empty() {
+            let env = self.builder.build_pointer_cast(
+                env_param,
+                env_type.ptr_type(AddressSpace::Generic),
+                "env",
+            );
+
+            for (i, &(ref name, ref capture_type)) in captures.iter().enumerate() {
+                let value = self.builder.build_load(self.field_ptr(env, i), name);
+                let alloca = self.create_entry_block_alloca(name, capture_type);
+
+                self.builder.build_store(alloca, value);
+                self.variables.insert(name.clone(), alloca);
+            }
+        }
+
+        if self.checks {
+            self.build_enter_check();
+        }
+
+        let result = self.compile_expr(body);
+
+        if let Ok(value) = result {
+            if self.checks {
+                self.build_leave();
+            }
+
+            self.builder.build_return(Some(&value));
+        }
+
+        self.fn_value_opt = outer_fn;
+        self.variables = outer_variables;
+        self.loops = outer_loops;
+        self.builder.position_at_end(&current);
+
+        result?;
+
+        if !lambda.verify(true) {
+            unsafe {
+                lambda.delete();
+            }
+
+            return Err(Diagnostic::error("Invalid generated function.")
+                .with_code("E0304")
+                .with_primary(span, "LLVM verification failed for this lambda"));
+        }
+
+        self.fpm.run_on(&lambda);
+
+        Ok(self.build_closure(lambda, env, ty).into())
+    }
+
+    /// ループの本体をコンパイルする間、'break'と'continue'の分岐先を積む
+    fn push_loop(&mut self, continue_bb: BasicBlock<'ctx>, break_bb: BasicBlock<'ctx>) {
+        self.loops.push(LoopContext {
+            continue_bb: continue_bb,
+            break_bb: break_bb,
+            break_values: Vec::new(),
+        });
+    }
+
+    /// ループを取り除き、'break_bb'に位置してループの値を返す
+    /// 'exit_bb'は条件が偽となってループを抜けるブロックで、その場合の値は0となる
+    fn pop_loop(&mut self, exit_bb: BasicBlock<'ctx>) -> BasicValueEnum<'ctx> {
+        let context = self.loops.pop().unwrap();
+        let zero = self.context.f64_type().const_float(0.0);
+
+        self.builder.position_at_end(&context.break_bb);
+
+        if context.break_values.is_empty() {
+            return zero.into();
+        }
+
+        let phi = self.builder.build_phi(self.context.f64_type(), "loopval");
+
+        phi.add_incoming(&[(&zero, &exit_bb)]);
+
+        for &(ref value, ref block) in &context.break_values {
+            phi.add_incoming(&[(value, block)]);
+        }
+
+        phi.as_basic_value()
+    }
+
+    /// 'break'か'continue'で分岐し、それ以降の到達しないコードのためのブロックに位置する
+    /// 'break_value'がNoneの場合は'continue'として扱われる
+    fn build_loop_jump(&mut self, break_value: Option<BasicValueEnum<'ctx>>) {
+        let current = self.builder.get_insert_block().unwrap();
+        let context = self.loops.last_mut().unwrap();
+
+        let target = match break_value {
+            Some(value) => {
+                context.break_values.push((value, current));
+                context.break_bb
+            }
+            None => context.continue_bb,
+        };
+
+        self.builder.build_unconditional_branch(&target);
+        self.position_after_terminator("afterjump");
+    }
+
+    /// 終端命令の後に続くコードのために、到達しない新しいブロックに位置する
+    /// 終端命令の後に命令を追加すると、関数の検証に失敗するため
+    fn position_after_terminator(&self, name: &str) {
+        let unreachable_bb = self.context.append_basic_block(self.fn_value(), name);
+
+        self.builder.position_at_end(&unreachable_bb);
+    }
+
+    /// 実行時検査に失敗した際に呼び出すランタイム関数を取得し、宣言されていなければ宣言する
+    fn trap_fn(&self) -> FunctionValue<'ctx> {
+        match self.module.get_function(TRAP_SYMBOL) {
+            Some(fun) => fun,
+            None => {
+                let str_type = llvm_type(self.context, self.module, &Type::Str);
+                let i32_type = self.context.i32_type();
+                let fn_type = self.context.void_type().fn_type(
+                    &[str_type, str_type, i32_type.into(), i32_type.into()],
+                    false,
+                );
+
+                self.module.add_function(TRAP_SYMBOL, fn_type, None)
+            }
+        }
+    }
+
+    /// 呼び出しの深さを数えるランタイムのグローバル変数へのポインタを返す
+    fn call_depth_ptr(&self) -> PointerValue<'ctx> {
+        let global = match self.module.get_global(CALL_DEPTH_SYMBOL) {
+            Some(global) => global,
+            None => self
+                .module
+                .add_global(self.context.i64_type(), None, CALL_DEPTH_SYMBOL),
+        };
+
+        global.as_pointer_value()
+    }
+
+    /// 'failed'が真の場合に、'message'と関数名、'span'の位置を表示して異常終了する分岐を作成
+    /// 以降の命令は検査に成功した場合のブロックに追加される
+    fn build_check(&self, failed: IntValue<'ctx>, message: &str, span: Span) {
+        let parent = self.fn_value();
+        let trap_bb = self.context.append_basic_block(parent, "trap");
+        let cont_bb = self.context.append_basic_block(parent, "checked");
+
+        self.builder
+            .build_conditional_branch(failed, &trap_bb, &cont_bb);
+        self.builder.position_at_end(&trap_bb);
+
+        let i32_type = self.context.i32_type();
+
+        self.builder.build_call(
+            self.trap_fn(),
+            &[
+                self.const_string(message),
+                self.const_string(&self.function.prototype.name),
+                i32_type.const_int(span.line as u64, false).into(),
+                i32_type.const_int(span.column as u64, false).into(),
+            ],
+            "",
+        );
+        self.builder.build_unreachable();
+        self.builder.position_at_end(&cont_bb);
+    }
+
+    /// 除数が0の場合に異常終了する検査を挿入
+    fn build_division_check(&self, rhs: BasicValueEnum<'ctx>, ty: &Type, span: Span) {
+        let is_zero = match *ty {
+            Type::F64 => self.builder.build_float_compare(
+                FloatPredicate::OEQ,
+                rhs.into_float_value(),
+                self.context.f64_type().const_float(0.0),
+                "iszero",
+            ),
+            _ => self.builder.build_int_compare(
+                IntPredicate::EQ,
+                rhs.into_int_value(),
+                self.const_number(ty, 0.0).into_int_value(),
+                "iszero",
+            ),
+        };
+
+        self.build_check(is_zero, "division by zero", span);
+    }
+
+    /// 関数の開始時に呼び出しの深さを増やし、上限を超えた場合に異常終了する検査を挿入
+    fn build_enter_check(&self) {
+        let depth_ptr = self.call_depth_ptr();
+        let depth = self.builder.build_load(depth_ptr, "depth").into_int_value();
+        let one = self.context.i64_type().const_int(1, false);
+        let depth = self.builder.build_int_add(depth, one, "depth");
+
+        self.builder.build_store(depth_ptr, depth);
+
+        let limit = self.context.i64_type().const_int(MAX_CALL_DEPTH, false);
+        let exceeded = self
+            .builder
+            .build_int_compare(IntPredicate::SGT, depth, limit, "toodeep");
+
+        self.build_check(
+            exceeded,
+            "maximum call depth exceeded",
+            self.function.prototype.span,
+        );
+    }
+
+    /// 関数から戻る前に呼び出しの深さを減らす
+    fn build_leave(&self) {
+        let depth_ptr = self.call_depth_ptr();
+        let depth = self.builder.build_load(depth_ptr, "depth").into_int_value();
+        let one = self.context.i64_type().const_int(1, false);
+        let depth = self.builder.build_int_sub(depth, one, "depth");
+
+        self.builder.build_store(depth_ptr, depth);
+    }
+
+    /// 型検査済みの式'Expr'をLLVMの値にコンパイル
+    fn compile_expr(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>, Diagnostic> {
+        match expr.kind {
+            ExprKind::Number(nb) => Ok(self.const_number(&expr.ty, nb)),
+
+            ExprKind::Str(ref value) => Ok(self.const_string(value)),
+
+            ExprKind::Array(ref elements) => {
+                let len = self
+                    .context
+                    .i64_type()
+                    .const_int(elements.len() as u64, false);
+                let array = self.build_array_alloc(len);
+
+                for (i, element) in elements.iter().enumerate() {
+                    let index = self.context.i64_type().const_int(i as u64, false);
+                    let value = self.compile_expr(element)?;
+
+                    self.builder
+                        .build_store(self.element_ptr(array, index), value);
+                }
+
+                Ok(array.into())
+            }
+
+            ExprKind::Index {
+                ref array,
+                ref index,
+            } => {
+                let elem_ptr = self.compile_element_ptr(array, index)?;
+
+                Ok(self.builder.build_load(elem_ptr, "elem"))
+            }
+
+            ExprKind::Construct {
+                ref name,
+                ref fields,
+            } => {
+                // 構造体はヒープに確保され、ポインタとして受け渡される
+                let value = self
+                    .builder
+                    .build_malloc(struct_type(self.module, name), name);
+
+                for (index, &(_, ref field)) in fields.iter().enumerate() {
+                    let field_val = self.compile_expr(field)?;
+
+                    self.builder
+                        .build_store(self.field_ptr(value, index), field_val);
+                }
+
+                Ok(value.into())
+            }
+
+            ExprKind::Field {
+                ref value,
+                ref field,
+                index,
+            } => {
+                let field_ptr = self.compile_field_ptr(value, index)?;
+
+                Ok(self.builder.build_load(field_ptr, field))
+            }
+
+            ExprKind::Variable(ref name) => match self.lookup_variable(name) {
+                Some(var) => Ok(self.builder.build_load(var, name.as_str())),
+                // 関数の名前は関数値として参照される
+                None => match self.get_function(name) {
+                    Some(fun) => Ok(self.build_function_value(fun, &expr.ty).into()),
+                    None => Err(Diagnostic::error("Could not find a matching variable.")
+                        .with_code("E0300")
+                        .with_primary(expr.span, "not found in this scope")),
+                },
             },
 
-            Expr::VarIn {
+            ExprKind::Lambda {
+                ref params,
+                ref body,
+                ref captures,
+            } => self.compile_lambda(params, body, captures, &expr.ty, expr.span),
+
+            ExprKind::VarIn {
                 ref variables,
                 ref body,
             } => {
                 let mut old_bindings = Vec::new();
 
-                for &(ref var_name, ref initializer) in variables {
+                for &(ref var_name, ref var_type, ref initializer) in variables {
                     let var_name = var_name.as_str();
+                    let var_type = var_type.clone().unwrap_or(Type::F64);
 
                     let initial_val = match *initializer {
                         Some(ref init) => self.compile_expr(init)?,
-                        None => self.context.f64_type().const_float(0.),
+                        None => self.const_number(&var_type, 0.),
                     };
 
-                    let alloca = self.create_entry_block_alloca(var_name);
+                    let alloca = self.create_entry_block_alloca(var_name, &var_type);
 
                     self.builder.build_store(alloca, initial_val);
 
-                    if let Some(old_binding) = self.variables.remove(var_name) {
-                        old_bindings.push(old_binding);
-                    }
+                    let old_binding = self.variables.insert(var_name.to_string(), alloca);
 
-                    self.variables.insert(var_name.to_string(), alloca);
+                    old_bindings.push((var_name, old_binding));
                 }
 
                 let body = self.compile_expr(body)?;
 
-                for binding in old_bindings {
-                    self.variables
-                        .insert(binding.get_name().to_str().unwrap().to_string(), binding);
+                // 導入した変数を取り除き、隠していた外側の束縛を戻す
+                for (var_name, old_binding) in old_bindings.into_iter().rev() {
+                    match old_binding {
+                        Some(binding) => self.variables.insert(var_name.to_string(), binding),
+                        None => self.variables.remove(var_name),
+                    };
                 }
 
                 Ok(body)
             }
 
-            Expr::Binary {
+            ExprKind::Binary {
                 op,
                 ref left,
                 ref right,
             } => {
                 if op == '=' {
                     // handle assignement
-                    let var_name = match *left.borrow() {
-                        Expr::Variable(ref var_name) => var_name,
+                    let var_name = match left.kind {
+                        ExprKind::Variable(ref var_name) => var_name,
+                        ExprKind::Index {
+                            ref array,
+                            ref index,
+                        } => {
+                            let elem_ptr = self.compile_element_ptr(array, index)?;
+                            let value = self.compile_expr(right)?;
+
+                            self.builder.build_store(elem_ptr, value);
+
+                            return Ok(value);
+                        }
+                        ExprKind::Field {
+                            ref value, index, ..
+                        } => {
+                            let field_ptr = self.compile_field_ptr(value, index)?;
+                            let value = self.compile_expr(right)?;
+
+                            self.builder.build_store(field_ptr, value);
+
+                            return Ok(value);
+                        }
                         _ => {
-                            return Err("Expected variable as left-hand operator of assignement.");
+                            return Err(Diagnostic::error(
+                                "Expected variable, array element or field as left-hand operator of assignement.",
+                            )
+                            .with_code("E0301")
+                            .with_primary(left.span, "cannot assign to this expression"));
                         }
                     };
 
                     let var_val = self.compile_expr(right)?;
-                    let var = self
-                        .variables
-                        .get(var_name.as_str())
-                        .ok_or("Undefined variable.")?;
+                    let var = self.lookup_variable(var_name).ok_or_else(|| {
+                        Diagnostic::error("Undefined variable.")
+                            .with_code("E0300")
+                            .with_primary(left.span, "not found in this scope")
+                    })?;
 
-                    self.builder.build_store(*var, var_val);
+                    self.builder.build_store(var, var_val);
 
                     Ok(var_val)
-                } else {
+                } else if op == '+' || op == '-' || op == '*' || op == '/' || op == '<' || op == '>'
+                {
                     let lhs = self.compile_expr(left)?;
                     let rhs = self.compile_expr(right)?;
 
-                    match op {
-                        '+' => Ok(self.builder.build_float_add(lhs, rhs, "tmpadd")),
-                        '-' => Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub")),
-                        '*' => Ok(self.builder.build_float_mul(lhs, rhs, "tmpmul")),
-                        '/' => Ok(self.builder.build_float_div(lhs, rhs, "tmpdiv")),
-                        '<' => Ok({
-                            let cmp = self.builder.build_float_compare(
-                                FloatPredicate::ULT,
-                                lhs,
-                                rhs,
-                                "tmpcmp",
-                            );
+                    // 比較演算子の結果はboolのため、両辺の型で演算を選択する
+                    let is_arithmetic = op != '<' && op != '>';
 
-                            self.builder.build_unsigned_int_to_float(
-                                cmp,
-                                self.context.f64_type(),
-                                "tmpbool",
-                            )
-                        }),
-                        '>' => Ok({
-                            let cmp = self.builder.build_float_compare(
-                                FloatPredicate::ULT,
-                                rhs,
-                                lhs,
-                                "tmpcmp",
+                    // 整数の0除算はLLVMでは未定義動作となるため、インタプリタと同じく常に検査する
+                    // f64の0除算は無限大かNaNとなり、実行時検査が有効な場合のみ異常終了する
+                    if op == '/' && (self.checks || left.ty != Type::F64) {
+                        self.build_division_check(rhs, &left.ty, expr.span);
+                    }
+
+                    if left.ty == Type::F64 {
+                        let (lhs, rhs) = (lhs.into_float_value(), rhs.into_float_value());
+
+                        let value: BasicValueEnum = match op {
+                            '+' => self.builder.build_float_add(lhs, rhs, "tmpadd").into(),
+                            '-' => self.builder.build_float_sub(lhs, rhs, "tmpsub").into(),
+                            '*' => self.builder.build_float_mul(lhs, rhs, "tmpmul").into(),
+                            '/' => self.builder.build_float_div(lhs, rhs, "tmpdiv").into(),
+                            '<' => self
+                                .builder
+                                .build_float_compare(FloatPredicate::ULT, lhs, rhs, "tmpcmp")
+                                .into(),
+                            _ => self
+                                .builder
+                                .build_float_compare(FloatPredicate::ULT, rhs, lhs, "tmpcmp")
+                                .into(),
+                        };
+
+                        if self.checks && is_arithmetic {
+                            let value = value.into_float_value();
+                            let is_nan = self.builder.build_float_compare(
+                                FloatPredicate::UNO,
+                                value,
+                                value,
+                                "isnan",
                             );
 
-                            self.builder.build_unsigned_int_to_float(
-                                cmp,
-                                self.context.f64_type(),
-                                "tmpbool",
-                            )
-                        }),
-
-                        custom => {
-                            let mut name = String::from("binary");
-
-                            name.push(custom);
-
-                            match self.get_function(name.as_str()) {
-                                Some(fun) => {
-                                    match self
-                                        .builder
-                                        .build_call(fun, &[lhs.into(), rhs.into()], "tmpbin")
-                                        .try_as_basic_value()
-                                        .left()
-                                    {
-                                        Some(value) => Ok(value.into_float_value()),
-                                        None => Err("Invalid call produced."),
-                                    }
-                                }
-
-                                None => Err("Undefined binary operator."),
-                            }
+                            self.build_check(is_nan, "arithmetic produced NaN", expr.span);
                         }
+
+                        Ok(value)
+                    } else {
+                        let (lhs, rhs) = (lhs.into_int_value(), rhs.into_int_value());
+
+                        Ok(match op {
+                            '+' => self.builder.build_int_add(lhs, rhs, "tmpadd").into(),
+                            '-' => self.builder.build_int_sub(lhs, rhs, "tmpsub").into(),
+                            '*' => self.builder.build_int_mul(lhs, rhs, "tmpmul").into(),
+                            '/' => self.builder.build_int_signed_div(lhs, rhs, "tmpdiv").into(),
+                            '<' => self
+                                .builder
+                                .build_int_compare(IntPredicate::SLT, lhs, rhs, "tmpcmp")
+                                .into(),
+                            _ => self
+                                .builder
+                                .build_int_compare(IntPredicate::SGT, lhs, rhs, "tmpcmp")
+                                .into(),
+                        })
+                    }
+                } else {
+                    let mut name = String::from("binary");
+
+                    name.push(op);
+
+                    match self.get_function(name.as_str()) {
+                        Some(fun) => self.build_call(
+                            fun,
+                            &[left, right],
+                            "tmpbin",
+                            expr.span,
+                            "in this operation",
+                        ),
+
+                        None => Err(Diagnostic::error("Undefined binary operator.")
+                            .with_code("E0323")
+                            .with_primary(expr.span, "no operator definition found")
+                            .with_note(format!(
+                                "define it with 'def binary{} <precedence> (lhs, rhs)'",
+                                op
+                            ))),
                     }
                 }
             }
 
-            Expr::Call {
+            ExprKind::Call {
                 ref fn_name,
                 ref args,
-            } => match self.get_function(fn_name.as_str()) {
-                Some(fun) => {
-                    let mut compiled_args = Vec::with_capacity(args.len());
+                ..
+            } if intrinsic_arity(fn_name).is_some() => self.compile_intrinsic(fn_name, args),
 
-                    for arg in args {
-                        compiled_args.push(self.compile_expr(arg)?);
-                    }
+            // ローカル変数に格納された関数値の呼び出し
+            ExprKind::Call {
+                ref fn_name,
+                ref args,
+                local: true,
+            } => {
+                let closure = self
+                    .builder
+                    .build_load(self.variables[fn_name], fn_name)
+                    .into_pointer_value();
 
-                    let argsv: Vec<BasicValueEnum> = compiled_args
-                        .iter()
-                        .by_ref()
-                        .map(|&val| val.into())
-                        .collect();
+                self.build_closure_call(closure, args, expr.span)
+            }
 
-                    match self
-                        .builder
-                        .build_call(fun, argsv.as_slice(), "tmp")
-                        .try_as_basic_value()
-                        .left()
-                    {
-                        Some(value) => Ok(value.into_float_value()),
-                        None => Err("Invalid call produced."),
-                    }
+            ExprKind::Call {
+                ref fn_name,
+                ref args,
+                ..
+            } => match self.get_function(fn_name.as_str()) {
+                Some(fun) => {
+                    let args: Vec<&Expr> = args.iter().collect();
+
+                    self.build_call(fun, &args, "tmp", expr.span, "in this call")
                 }
-                None => Err("Unknown function."),
+                None => Err(Diagnostic::error("Unknown function.")
+                    .with_code("E0302")
+                    .with_primary(expr.span, "no function with this name")),
             },
 
-            Expr::Conditional {
+            ExprKind::Cast(ref value) => {
+                let compiled = self.compile_expr(value)?;
+
+                Ok(self.build_cast(compiled, &value.ty, &expr.ty))
+            }
+
+            ExprKind::Conditional {
                 ref cond,
                 ref consequence,
                 ref alternative,
             } => {
                 let parent = self.fn_value();
-                let zero_const = self.context.f64_type().const_float(0.0);
 
                 // create condition by comparing without 0.0 and returning an int
-                let cond = self.compile_expr(cond)?;
-                let cond = self.builder.build_float_compare(
-                    FloatPredicate::ONE,
-                    cond,
-                    zero_const,
-                    "ifcond",
-                );
+                let cond_val = self.compile_expr(cond)?;
+                let cond = self.build_truth(cond_val, &cond.ty, "ifcond");
 
                 // build branch
                 let then_bb = self.context.append_basic_block(parent, "then");
@@ -255,29 +1047,35 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                 // emit merge block
                 self.builder.position_at_end(&cont_bb);
 
-                let phi = self.builder.build_phi(self.context.f64_type(), "iftmp");
+                let phi = self
+                    .builder
+                    .build_phi(llvm_type(self.context, self.module, &expr.ty), "iftmp");
 
                 phi.add_incoming(&[(&then_val, &then_bb), (&else_val, &else_bb)]);
 
-                Ok(phi.as_basic_value().into_float_value())
+                Ok(phi.as_basic_value())
             }
 
-            Expr::For {
+            ExprKind::For {
                 ref var_name,
                 ref start,
                 ref end,
                 ref step,
                 ref body,
+                ..
             } => {
                 let parent = self.fn_value();
+                let var_type = start.ty.clone();
 
-                let start_alloca = self.create_entry_block_alloca(var_name);
+                let start_alloca = self.create_entry_block_alloca(var_name, &var_type);
                 let start = self.compile_expr(start)?;
 
                 self.builder.build_store(start_alloca, start);
 
                 // go from current block to loop block
                 let loop_bb = self.context.append_basic_block(parent, "loop");
+                let step_bb = self.context.append_basic_block(parent, "step");
+                let after_bb = self.context.append_basic_block(parent, "afterloop");
 
                 self.builder.build_unconditional_branch(&loop_bb);
                 self.builder.position_at_end(&loop_bb);
@@ -286,36 +1084,49 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
 
                 self.variables.insert(var_name.to_owned(), start_alloca);
 
+                // 'continue'はステップの加算に、'break'はループの後に分岐する
+                self.push_loop(step_bb, after_bb);
+
                 // emit body
                 self.compile_expr(body)?;
 
+                self.builder.build_unconditional_branch(&step_bb);
+                self.builder.position_at_end(&step_bb);
+
                 // emit step
                 let step = match *step {
                     Some(ref step) => self.compile_expr(step)?,
-                    None => self.context.f64_type().const_float(1.0),
+                    None => self.const_number(&var_type, 1.0),
                 };
 
                 // compile end condition
-                let end_cond = self.compile_expr(end)?;
+                let end_val = self.compile_expr(end)?;
 
                 let curr_var = self.builder.build_load(start_alloca, var_name);
-                let next_var =
-                    self.builder
-                        .build_float_add(curr_var.into_float_value(), step, "nextvar");
+                let next_var: BasicValueEnum = match var_type {
+                    Type::F64 => self
+                        .builder
+                        .build_float_add(
+                            curr_var.into_float_value(),
+                            step.into_float_value(),
+                            "nextvar",
+                        )
+                        .into(),
+                    _ => self
+                        .builder
+                        .build_int_add(curr_var.into_int_value(), step.into_int_value(), "nextvar")
+                        .into(),
+                };
 
                 self.builder.build_store(start_alloca, next_var);
 
-                let end_cond = self.builder.build_float_compare(
-                    FloatPredicate::ONE,
-                    end_cond,
-                    self.context.f64_type().const_float(0.0),
-                    "loopcond",
-                );
-                let after_bb = self.context.append_basic_block(parent, "afterloop");
+                let end_cond = self.build_truth(end_val, &end.ty, "loopcond");
+                let exit_bb = self.builder.get_insert_block().unwrap();
 
                 self.builder
                     .build_conditional_branch(end_cond, &loop_bb, &after_bb);
-                self.builder.position_at_end(&after_bb);
+
+                let value = self.pop_loop(exit_bb);
 
                 self.variables.remove(var_name);
 
@@ -323,34 +1134,123 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                     self.variables.insert(var_name.to_owned(), val);
                 }
 
-                Ok(self.context.f64_type().const_float(0.0))
+                Ok(value)
+            }
+
+            ExprKind::Block(ref exprs) => {
+                let mut value = self.context.f64_type().const_float(0.0).into();
+
+                for expr in exprs {
+                    value = self.compile_expr(expr)?;
+                }
+
+                Ok(value)
+            }
+
+            ExprKind::While { ref cond, ref body } => {
+                let parent = self.fn_value();
+
+                let cond_bb = self.context.append_basic_block(parent, "whilecond");
+                let body_bb = self.context.append_basic_block(parent, "whilebody");
+                let after_bb = self.context.append_basic_block(parent, "afterwhile");
+
+                self.builder.build_unconditional_branch(&cond_bb);
+                self.builder.position_at_end(&cond_bb);
+
+                // 'continue'は条件の評価に、'break'はループの後に分岐する
+                self.push_loop(cond_bb, after_bb);
+
+                let cond_val = self.compile_expr(cond)?;
+                let cond = self.build_truth(cond_val, &cond.ty, "whilecond");
+                let exit_bb = self.builder.get_insert_block().unwrap();
+
+                self.builder
+                    .build_conditional_branch(cond, &body_bb, &after_bb);
+                self.builder.position_at_end(&body_bb);
+
+                self.compile_expr(body)?;
+
+                self.builder.build_unconditional_branch(&cond_bb);
+
+                Ok(self.pop_loop(exit_bb))
+            }
+
+            ExprKind::Break(ref value) => {
+                let value = match *value {
+                    Some(ref value) => self.compile_expr(value)?,
+                    None => self.context.f64_type().const_float(0.0).into(),
+                };
+
+                self.build_loop_jump(Some(value));
+
+                // 'break'の値は使われることがない
+                Ok(self.const_number(&expr.ty, 0.0))
+            }
+
+            ExprKind::Continue => {
+                self.build_loop_jump(None);
+
+                Ok(self.const_number(&expr.ty, 0.0))
+            }
+
+            ExprKind::Return(ref value) => {
+                let value = self.compile_expr(value)?;
+
+                if self.checks {
+                    self.build_leave();
+                }
+
+                self.builder.build_return(Some(&value));
+                self.position_after_terminator("afterreturn");
+
+                Ok(self.const_number(&expr.ty, 0.0))
             }
         }
     }
 
     /// 指定されたPrototypeをLLVM FunctionValueにコンパイル
-    fn compile_prototype(&self, proto: &Prototype) -> Result<FunctionValue<'ctx>, &'static str> {
-        let ret_type = self.context.f64_type();
-        let args_types = std::iter::repeat(ret_type)
-            .take(proto.args.len())
-            .map(|f| f.into())
+    fn compile_prototype(&self, proto: &Prototype) -> Result<FunctionValue<'ctx>, Diagnostic> {
+        Ok(Compiler::declare(self.context, self.module, proto))
+    }
+
+    /// 指定されたPrototypeを本体のない関数としてモジュールに宣言
+    /// externなどで既に宣言のみされている場合は、その宣言を再利用する
+    pub fn declare(
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+        proto: &Prototype,
+    ) -> FunctionValue<'ctx> {
+        if let Some(fn_val) = module.get_function(proto.name.as_str()) {
+            if fn_val.get_first_basic_block().is_none() {
+                return fn_val;
+            }
+        }
+
+        let args_types = proto
+            .arg_types
+            .iter()
+            .map(|ty| llvm_type(context, module, ty))
             .collect::<Vec<BasicTypeEnum>>();
         let args_types = args_types.as_slice();
 
-        let fn_type = self.context.f64_type().fn_type(args_types, false);
-        let fn_val = self.module.add_function(proto.name.as_str(), fn_type, None);
+        let fn_type = llvm_type(context, module, &proto.ret_type).fn_type(args_types, false);
+        let fn_val = module.add_function(proto.name.as_str(), fn_type, None);
 
         // 引数名をセット
         for (i, arg) in fn_val.get_param_iter().enumerate() {
-            arg.into_float_value().set_name(proto.args[i].as_str());
+            match arg {
+                BasicValueEnum::FloatValue(value) => value.set_name(proto.args[i].as_str()),
+                BasicValueEnum::IntValue(value) => value.set_name(proto.args[i].as_str()),
+                _ => (),
+            }
         }
 
         // ビルドされたプロトタイプを返す
-        Ok(fn_val)
+        fn_val
     }
 
     /// 指定されたFunctionをLLVM FunctionValueにコンパイル
-    fn compile_fn(&mut self) -> Result<FunctionValue<'ctx>, &'static str> {
+    fn compile_fn(&mut self) -> Result<FunctionValue<'ctx>, Diagnostic> {
         let proto = &self.function.prototype;
         let function = self.compile_prototype(proto)?;
 
@@ -371,16 +1271,24 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
 
         for (i, arg) in function.get_param_iter().enumerate() {
             let arg_name = proto.args[i].as_str();
-            let alloca = self.create_entry_block_alloca(arg_name);
+            let alloca = self.create_entry_block_alloca(arg_name, &proto.arg_types[i]);
 
             self.builder.build_store(alloca, arg);
 
             self.variables.insert(proto.args[i].clone(), alloca);
         }
 
+        if self.checks {
+            self.build_enter_check();
+        }
+
         // body部のコンパイル
         let body = self.compile_expr(self.function.body.as_ref().unwrap())?;
 
+        if self.checks {
+            self.build_leave();
+        }
+
         self.builder.build_return(Some(&body));
 
         // 検証と最適化後に返す
@@ -393,7 +1301,9 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
                 function.delete();
             }
 
-            Err("Invalid generated function.")
+            Err(Diagnostic::error("Invalid generated function.")
+                .with_code("E0304")
+                .with_primary(self.function.span, "LLVM verification failed here"))
         }
     }
 
@@ -404,18 +1314,261 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         builder: &'a Builder<'ctx>,
         pass_manager: &'a PassManager<FunctionValue<'ctx>>,
         module: &'a Module<'ctx>,
+        structs: &StructTable,
         function: &Function,
-    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        checks: bool,
+    ) -> Result<FunctionValue<'ctx>, Diagnostic> {
+        // コード生成の前に型検査を行い、全ての式の型を決定する
+        let function =
+            TypeChecker::check_function(Declarations::Module(module), structs, function)?;
+
         let mut compiler = Compiler {
             context: context,
             builder: builder,
             fpm: pass_manager,
             module: module,
-            function: function,
+            function: &function,
+            checks: checks,
             fn_value_opt: None,
             variables: HashMap::new(),
+            loops: Vec::new(),
         };
 
         compiler.compile_fn()
     }
+
+    /// 構造体をLLVMの名前付き構造体型として宣言する
+    /// 互いを参照する構造体のため、全ての型を作成してからフィールドを設定する
+    pub fn declare_structs(context: &'ctx Context, module: &Module<'ctx>, defs: &[&StructDef]) {
+        for def in defs {
+            context.opaque_struct_type(&def.name);
+        }
+
+        for def in defs {
+            let fields = def
+                .fields
+                .iter()
+                .map(|&(_, ref ty)| llvm_type(context, module, ty))
+                .collect::<Vec<BasicTypeEnum>>();
+
+            struct_type(module, &def.name).set_body(&fields, false);
+        }
+    }
+
+    /// グローバル変数をモジュールに宣言
+    /// 既に宣言されている場合は、その宣言を再利用する
+    pub fn declare_global(
+        context: &'ctx Context,
+        module: &Module<'ctx>,
+        name: &str,
+    ) -> GlobalValue<'ctx> {
+        match module.get_global(name) {
+            Some(global) => global,
+            None => module.add_global(context.f64_type(), None, name),
+        }
+    }
+
+    /// 指定されたグローバル変数をモジュールに定義する
+    /// 初期化式が定数に畳み込めない場合は、初期化を行う関数をコンパイルして返す
+    pub fn compile_global(
+        context: &'ctx Context,
+        builder: &'a Builder<'ctx>,
+        pass_manager: &'a PassManager<FunctionValue<'ctx>>,
+        module: &'a Module<'ctx>,
+        structs: &StructTable,
+        global: &GlobalVar,
+        checks: bool,
+    ) -> Result<Option<FunctionValue<'ctx>>, Diagnostic> {
+        let var = Compiler::declare_global(context, module, &global.name);
+
+        // f64のグローバル変数に格納できない初期化式は、コード生成の前に型検査で拒否する
+        let global = TypeChecker::check_global(Declarations::Module(module), structs, global)?;
+
+        if let Some(value) = fold_constant(&global.init) {
+            var.set_initializer(&context.f64_type().const_float(value));
+
+            return Ok(None);
+        }
+
+        var.set_initializer(&context.f64_type().const_float(0.0));
+
+        // 'name = init'を本体とする関数を生成し、初期化を実行時に行う
+        let span = global.span;
+        let target = Expr {
+            kind: ExprKind::Variable(global.name.clone()),
+            span: span,
+            ty: Type::F64,
+        };
+        let init = Function {
+            prototype: Prototype {
+                name: format!("{}{}", GLOBAL_INIT_PREFIX, global.name),
+                args: vec![],
+                arg_types: vec![],
+                ret_type: Type::F64,
+                is_op: false,
+                prec: 0,
+                span: span,
+            },
+            body: Some(Expr {
+                kind: ExprKind::Binary {
+                    op: '=',
+                    left: Box::new(target),
+                    right: Box::new(global.init.clone()),
+                },
+                span: span,
+                ty: Type::F64,
+            }),
+            is_anon: false,
+            span: span,
+        };
+
+        Compiler::compile(
+            context,
+            builder,
+            pass_manager,
+            module,
+            structs,
+            &init,
+            checks,
+        )
+        .map(Some)
+    }
+
+    /// 初期化関数を順に呼び出すコンストラクタを生成し、'llvm.global_ctors'に登録する
+    pub fn compile_global_ctor(
+        context: &'ctx Context,
+        builder: &'a Builder<'ctx>,
+        module: &'a Module<'ctx>,
+        initializers: &[FunctionValue<'ctx>],
+    ) -> FunctionValue<'ctx> {
+        let ctor = module.add_function(
+            GLOBAL_CTOR_NAME,
+            context.void_type().fn_type(&[], false),
+            None,
+        );
+        let entry = context.append_basic_block(ctor, "entry");
+
+        builder.position_at_end(&entry);
+
+        for init in initializers {
+            builder.build_call(*init, &[], "init");
+        }
+
+        builder.build_return(None);
+
+        // { i32 priority, void ()* ctor, i8* data } の配列として登録する
+        let i32_type = context.i32_type();
+        let i8_ptr_type = context.i8_type().ptr_type(AddressSpace::Generic);
+        let ctor_ptr_type = ctor.get_type().ptr_type(AddressSpace::Generic);
+        let entry_type = context.struct_type(
+            &[i32_type.into(), ctor_ptr_type.into(), i8_ptr_type.into()],
+            false,
+        );
+        let entry = entry_type.const_named_struct(&[
+            i32_type.const_int(65535, false).into(),
+            ctor.as_global_value().as_pointer_value().into(),
+            i8_ptr_type.const_null().into(),
+        ]);
+
+        let ctors = module.add_global(entry_type.array_type(1), None, "llvm.global_ctors");
+
+        ctors.set_linkage(Linkage::Appending);
+        ctors.set_initializer(&entry_type.const_array(&[entry]));
+
+        ctor
+    }
+}
+
+/// 宣言済みの構造体に対応する、LLVMの名前付き構造体型を返す
+/// 名前付き構造体型はコンテキストに登録されるため、どのモジュールからも参照できる
+pub fn struct_type<'ctx>(module: &Module<'ctx>, name: &str) -> StructType<'ctx> {
+    module
+        .get_struct_type(name)
+        .expect("struct types are declared before use")
+}
+
+/// 関数値の関数へのポインタが指す関数の型を返す
+/// 最初の引数はキャプチャした変数の環境へのポインタとなる
+pub fn closure_code_type<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    ty: &Type,
+) -> FunctionType<'ctx> {
+    let (args, ret) = match *ty {
+        Type::Fn(ref args, ref ret) => (args, ret),
+        _ => unreachable!("only function values have code pointers"),
+    };
+
+    let mut param_types = vec![context.i8_type().ptr_type(AddressSpace::Generic).into()];
+
+    param_types.extend(args.iter().map(|ty| llvm_type(context, module, ty)));
+
+    llvm_type(context, module, ret).fn_type(&param_types, false)
+}
+
+/// 型に対応するLLVMの型を返す
+/// 構造体型はモジュールから参照する
+pub fn llvm_type<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    ty: &Type,
+) -> BasicTypeEnum<'ctx> {
+    match *ty {
+        Type::Bool => context.bool_type().into(),
+        Type::F64 => context.f64_type().into(),
+        Type::I32 => context.i32_type().into(),
+        Type::I64 => context.i64_type().into(),
+        Type::Str => context.i8_type().ptr_type(AddressSpace::Generic).into(),
+        Type::Array => context
+            .struct_type(
+                &[
+                    context.i64_type().into(),
+                    context.f64_type().array_type(0).into(),
+                ],
+                false,
+            )
+            .ptr_type(AddressSpace::Generic)
+            .into(),
+        Type::Struct(ref name) => struct_type(module, name)
+            .ptr_type(AddressSpace::Generic)
+            .into(),
+        Type::Fn(..) => context
+            .struct_type(
+                &[
+                    closure_code_type(context, module, ty)
+                        .ptr_type(AddressSpace::Generic)
+                        .into(),
+                    context.i8_type().ptr_type(AddressSpace::Generic).into(),
+                ],
+                false,
+            )
+            .ptr_type(AddressSpace::Generic)
+            .into(),
+    }
+}
+
+/// 数値と組み込みの算術演算子のみからなる式を、その値に畳み込む
+fn fold_constant(expr: &Expr) -> Option<f64> {
+    match expr.kind {
+        ExprKind::Number(nb) => Some(nb),
+
+        ExprKind::Binary {
+            op,
+            ref left,
+            ref right,
+        } => {
+            let lhs = fold_constant(left)?;
+            let rhs = fold_constant(right)?;
+
+            match op {
+                '+' => Some(lhs + rhs),
+                '-' => Some(lhs - rhs),
+                '*' => Some(lhs * rhs),
+                '/' => Some(lhs / rhs),
+                _ => None,
+            }
+        }
+
+        _ => None,
+    }
 }
@@ -0,0 +1,233 @@
+use crate::lexer::Span;
+use std::fmt;
+
+/// ソースコード上の位置に付与するラベル
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// 字句解析、構文解析、コード生成で発生したエラーを表す診断
+/// 警告は発生しないため、診断は全てコンパイルや実行を止めるエラーとなる
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// エラーの診断を作成
+    pub fn error<S: Into<String>>(message: S) -> Diagnostic {
+        Diagnostic {
+            code: None,
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    /// エラーコードを設定
+    pub fn with_code(mut self, code: &'static str) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+
+    /// 問題の発生箇所を示す主ラベルを設定
+    pub fn with_primary<S: Into<String>>(mut self, span: Span, message: S) -> Diagnostic {
+        self.primary = Some(Label {
+            span: span,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// 補足情報を示す副ラベルを追加
+    pub fn with_secondary<S: Into<String>>(mut self, span: Span, message: S) -> Diagnostic {
+        self.secondary.push(Label {
+            span: span,
+            message: message.into(),
+        });
+        self
+    }
+
+    /// 注記を追加
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// ソースコードの該当行とキャレットによる下線を含めて、診断を文字列に整形する
+    ///
+    /// ```text
+    /// error[E0200]: Expected ')' character at end of parenthesized expression.
+    ///  --> input.ks:2:9
+    ///   |
+    /// 2 |   (x + 1 y
+    ///   |          ^ unexpected token
+    /// ```
+    pub fn render(&self, source: &str, filename: &str) -> String {
+        let mut out = format!("{}\n", self);
+
+        // 主ラベルを先頭にして、行ごとにラベルをまとめる
+        let mut labels: Vec<(&Label, char)> = Vec::new();
+
+        if let Some(ref primary) = self.primary {
+            labels.push((primary, '^'));
+        }
+
+        for label in &self.secondary {
+            labels.push((label, '-'));
+        }
+
+        let gutter = labels
+            .iter()
+            .map(|&(label, _)| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(gutter);
+
+        if let Some(&(label, _)) = labels.first() {
+            out.push_str(&format!(
+                "{}--> {}:{}:{}\n",
+                pad, filename, label.span.line, label.span.column
+            ));
+            out.push_str(&format!("{} |\n", pad));
+        }
+
+        let mut lines: Vec<usize> = labels.iter().map(|&(label, _)| label.span.line).collect();
+
+        lines.sort();
+        lines.dedup();
+
+        for line in lines {
+            let (line_start, text) = match source_line(source, line) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            out.push_str(&format!("{:>width$} | {}\n", line, text, width = gutter));
+
+            for &(label, marker) in labels.iter().filter(|&&(l, _)| l.span.line == line) {
+                // 行の範囲内に収まるように、下線の開始位置と長さを計算
+                let start = label
+                    .span
+                    .start
+                    .max(line_start)
+                    .min(line_start + text.len());
+                let end = label.span.end.max(start).min(line_start + text.len());
+
+                let offset = source[line_start..start].chars().count();
+                let width = source[start..end].chars().count().max(1);
+
+                out.push_str(&format!(
+                    "{} | {}{} {}\n",
+                    pad,
+                    " ".repeat(offset),
+                    marker.to_string().repeat(width),
+                    label.message
+                ));
+            }
+        }
+
+        if !self.notes.is_empty() {
+            out.push_str(&format!("{} |\n", pad));
+        }
+
+        for note in &self.notes {
+            out.push_str(&format!("{} = note: {}\n", pad, note));
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "error[{}]: {}", code, self.message),
+            None => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// 指定された行(1始まり)の開始バイトオフセットと、改行を除いた内容を返す
+fn source_line(source: &str, line: usize) -> Option<(usize, &str)> {
+    let mut start = 0;
+
+    for (i, text) in source.split('\n').enumerate() {
+        if i + 1 == line {
+            return Some((start, text.trim_end_matches('\r')));
+        }
+
+        start += text.len() + 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ソースコード中で最初に現れる'text'を指すSpanを返す
+    fn span_of(source: &str, text: &str) -> Span {
+        let start = source.find(text).expect("text not found in the source");
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        Span {
+            start: start,
+            end: start + text.len(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    #[test]
+    fn render_labels_on_one_line() {
+        let source = "def f(x)\n  x + \"a\"";
+        let diagnostic = Diagnostic::error("Mismatched types: expected 'f64', found 'str'.")
+            .with_code("E0306")
+            .with_primary(span_of(source, "\"a\""), "expected 'f64'")
+            .with_secondary(span_of(source, "x +"), "this has type 'f64'")
+            .with_note("strings cannot be added to numbers");
+
+        assert_eq!(
+            diagnostic.render(source, "input.ks"),
+            "error[E0306]: Mismatched types: expected 'f64', found 'str'.
+ --> input.ks:2:7
+  |
+2 |   x + \"a\"
+  |       ^^^ expected 'f64'
+  |   --- this has type 'f64'
+  |
+  = note: strings cannot be added to numbers
+"
+        );
+    }
+
+    #[test]
+    fn render_labels_on_several_lines() {
+        let source = "struct P { x }\n\n\n\n\n\n\n\nP { x: 1,\n    x: 2 }";
+        let diagnostic = Diagnostic::error("Field 'x' is specified more than once.")
+            .with_primary(span_of(source, "2"), "field already specified")
+            .with_secondary(span_of(source, "1"), "first specified here");
+
+        assert_eq!(
+            diagnostic.render(source, "input.ks"),
+            "error: Field 'x' is specified more than once.
+  --> input.ks:10:8
+   |
+ 9 | P { x: 1,
+   |        - first specified here
+10 |     x: 2 }
+   |        ^ field already specified
+"
+        );
+    }
+}
@@ -0,0 +1,594 @@
+use crate::diagnostic::Diagnostic;
+use crate::driver::{self, Source};
+use crate::interp::{self, Interpreter};
+use crate::jit::{self, Session};
+use crate::operators::OperatorTable;
+use crate::parser::{Item, Parser};
+
+use inkwell::context::Context;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// JITとインタプリタで結果を比較する、組み込みのプログラムの名前とソース
+/// 組み込み関数の出力と、トップレベルの式の値が比較される
+/// 失敗することを確かめる要素は、その最後の行に'# expect error'のコメントを付ける
+pub const CORPUS: &[(&str, &str)] = &[
+    (
+        "arithmetic",
+        "1 + 2 * 3
+(1 + 2) * 3
+10 / 4 - 1
+1 / 0
+0 / 0
+2 < 3
+3 < 2
+0 / 0 < 1
+1 > (0 / 0)",
+    ),
+    (
+        "if",
+        "def abs(x) if x < 0 then 0 - x else x
+abs(0 - 3) + abs(4)
+if 0 then 1 else 2
+if 0 / 0 then 1 else 2
+if 1 < 2 then if 2 < 1 then 10 else 20 else 30
+def sign(x) if x < 0 then 0 - 1 else if x > 0 then 1 else 0
+sign(0 - 5) + sign(0) * 10 + sign(7) * 100",
+    ),
+    (
+        "recursion",
+        "def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2)
+fib(20)
+def fact(n) if n < 2 then 1 else n * fact(n - 1)
+fact(10)
+extern floor(x)
+def gcd(a, b) if b < 1 then a else gcd(b, a - b * floor(a / b))
+gcd(1071, 462)",
+    ),
+    (
+        "for",
+        "extern putchard(c)
+extern printd(x)
+def line(n) for i = 0, i < n in putchard(42)
+line(5)
+putchard(10)
+for i = 1, i < 4, 0.5 in printd(i)
+def sum(n) var s = 0 in (for i = 0, i < n in s = s + i) + s
+sum(100)
+for i = 0, 0 in printd(i)",
+    ),
+    (
+        "var",
+        "def swap(a, b) var t = a in var a = b, b = t in a * 10 + b
+swap(1, 2)
+def shadow(x) (var x = x + 1, y = x * 2 in x + y) + x
+shadow(5)
+var x in x
+def counter(n) var c = 0 in (for i = 0, i < n in c = c + 2) + c
+counter(7)",
+    ),
+    (
+        "custom operators",
+        "extern printd(x)
+def unary!(v) if v then 0 else 1
+def unary-(v) 0 - v
+def binary> 10 (lhs, rhs) rhs < lhs
+def binary| 5 (lhs, rhs) if lhs then 1 else if rhs then 1 else 0
+def binary& 6 (lhs, rhs) if !lhs then 0 else !!rhs
+def binary% 9 (lhs, rhs) !(lhs < rhs | lhs > rhs)
+def binary~ 1 (x, y) y
+1 + !0 + !1 * 10
+2 * -3 + 5
+0 | 1 & 0
+0 | 0 & 1 | 0
+2 % 2 + (2 % 3) * 10
+printd(1) ~ printd(2) ~ 3
+def binary^ 70 (x, n) if n < 1 then 1 else x * (x ^ (n - 1))
+2 ^ 10 + 1
+3 - -2 ^ 2",
+    ),
+    (
+        "mandelbrot",
+        "extern putchard(c)
+def binary : 1 (x, y) y
+def unary-(v) 0 - v
+def binary> 10 (lhs, rhs) rhs < lhs
+def binary| 5 (lhs, rhs) if lhs then 1 else if rhs then 1 else 0
+def printdensity(d) if d > 8 then putchard(32) else if d > 4 then putchard(46) else if d > 2 then putchard(43) else putchard(42)
+def mandelconverger(real, imag, iters, creal, cimag) if iters > 255 | (real * real + imag * imag > 4) then iters else mandelconverger(real * real - imag * imag + creal, 2 * real * imag + cimag, iters + 1, creal, cimag)
+def mandelconverge(real, imag) mandelconverger(real, imag, 0, real, imag)
+def mandelhelp(xmin, xmax, xstep, ymin, ymax, ystep) for y = ymin, y < ymax, ystep in ((for x = xmin, x < xmax, xstep in printdensity(mandelconverge(x, y))) : putchard(10))
+def mandel(realstart, imagstart, realmag, imagmag) mandelhelp(realstart, realstart + realmag * 78, realmag, imagstart, imagstart + imagmag * 40, imagmag)
+mandel(-2.3, -1.3, 0.05, 0.07)",
+    ),
+    (
+        "loops and blocks",
+        "extern printd(x)
+extern floor(x)
+def collatz(n) { var steps = 0; while n > 1 do { n = if n - 2 * floor(n / 2) < 1 then n / 2 else 3 * n + 1; steps = steps + 1 }; steps }
+collatz(27)
+def first(n) for i = 0, i < n in if 50 < i * i then break i else 0
+first(100)
+first(3)
+def odd(n) { var s = 0; for i = 0, i < n in { if i - 2 * floor(i / 2) < 1 then continue else 0; s = s + i }; s }
+odd(10)
+def find(limit) { var i = 0; while 1 do { i = i + 1; if i > limit then return i * 2 else 0 } }
+find(5)",
+    ),
+    (
+        "types",
+        "var x: i32 = 7 in x / 2
+var x: i64 = 3 / 2 * 2 in x
+var x: i64 = 1 in (for i = 0, i < 40 in x = x * 2) + x
+def half(n: i32) -> i32 n / 2
+half(9)
+var b: bool in b
+def avg(xs: array) { var s = 0, i = 0; while i < len(xs) do { s = s + xs[i]; i = i + 1 }; s / len(xs) }
+avg([1, 2, 3, 4])
+def scaled(n: i64) n * 0.5
+scaled(7)
+def count(xs: array) -> f64 len(xs)
+count([5, 6, 7])
+var m: i32 = 3 in (for i = 0, i < m in m = m - 1) + m",
+    ),
+    (
+        "scopes",
+        "global x = 5
+def inner() { { var x = 1; x }; x }
+inner()
+def shadowed(x) { { var x = x * 2; x }; x }
+shadowed(3)
+def nested() var y = 1 in (var y = 2 in y) + y
+nested()
+def ident(x) x
+def callsglobal() { { var ident = 2; ident }; ident(1) }
+callsglobal()
+def callslocal() var ident = \\x -> x + 1 in ident(5)
+callslocal()",
+    ),
+    (
+        "conversions",
+        "extern printd(x)
+i64(7.9) + 1
+i32(0 - 2.5)
+f64(i32(9) / 2)
+printd(f64(i64(3) * 2))
+f64(1 < 2) + 1
+i64(2 < 3)
+var n: i32 = 5 in f64(n) / 2
+def mean(xs: array) { var s = 0, i: i64 = 0; while i < len(xs) do { s = s + xs[i]; i = i + 1 }; s / f64(len(xs)) }
+mean([1, 2, 3, 4])",
+    ),
+    (
+        "globals",
+        "global n = i64(4) * 2
+n + 1
+global flag = 1 < 2
+flag + n
+global s = \"hi\" # expect error
+def bump() n = n + 1
+bump()
+n",
+    ),
+    (
+        "structs and closures",
+        "struct Point { x, y }
+def norm(p: Point) p.x * p.x + p.y * p.y
+norm(Point { y: 4, x: 3 })
+def moved(p: Point) -> Point { p.x = p.x + 1; p }
+moved(Point { x: 1, y: 2 }).x
+def adder(k) -> fn(f64) \\x -> x + k
+def apply(f: fn(f64), x) f(x)
+apply(adder(10), 5)
+apply(\\x -> x * x, 7)",
+    ),
+];
+
+/// 差分テストを行う最適化レベル
+const OPT_LEVELS: [u32; 4] = [0, 1, 2, 3];
+
+/// 失敗することが期待される要素の、最後の行に付けるコメント
+const EXPECT_ERROR: &str = "# expect error";
+
+/// 要素を評価した結果
+#[derive(Debug)]
+enum Outcome {
+    /// 定義など、値を持たない要素
+    Unit,
+    /// トップレベルの式の値の型の名前と値、数値の場合はf64に変換した値
+    Value(&'static str, String, Option<f64>),
+    /// 評価に失敗した場合の全ての診断のメッセージ
+    Error(Vec<String>),
+}
+
+impl Outcome {
+    /// 2つの結果が一致するかを返す
+    /// 値は型が同じ場合のみ一致し、NaN同士は一致するものとする
+    /// 両方が失敗した場合は、診断の内容に関係なく一致とする
+    fn agrees(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (&Outcome::Unit, &Outcome::Unit) => true,
+            (
+                &Outcome::Value(a_type, ref a, a_number),
+                &Outcome::Value(b_type, ref b, b_number),
+            ) if a_type == b_type => match (a_number, b_number) {
+                (Some(a), Some(b)) => a == b || (a.is_nan() && b.is_nan()),
+                _ => a == b,
+            },
+            (&Outcome::Error(_), &Outcome::Error(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        match *self {
+            Outcome::Error(_) => true,
+            _ => false,
+        }
+    }
+
+    /// 報告に表示する文字列
+    fn describe(&self) -> String {
+        match *self {
+            Outcome::Unit => "(no value)".to_string(),
+            Outcome::Value(ty, ref value, _) => format!("=> {}: {}", value, ty),
+            Outcome::Error(ref msgs) => format!("error: {}", msgs.join(" / ")),
+        }
+    }
+}
+
+/// JITで評価された値を結果に変換する
+fn jit_outcome(value: &jit::Value) -> Outcome {
+    let (ty, number) = match *value {
+        jit::Value::Bool(value) => ("bool", Some(value as u8 as f64)),
+        jit::Value::F64(value) => ("f64", Some(value)),
+        jit::Value::I32(value) => ("i32", Some(f64::from(value))),
+        jit::Value::I64(value) => ("i64", Some(value as f64)),
+        jit::Value::Str(_) => ("str", None),
+        jit::Value::Array(_) => ("array", None),
+        jit::Value::Struct(..) => ("struct", None),
+        jit::Value::Function(_) => ("fn", None),
+    };
+
+    Outcome::Value(ty, value.to_string(), number)
+}
+
+/// インタプリタで評価された値を結果に変換する
+fn interp_outcome(value: &interp::Value) -> Outcome {
+    let ty = match *value {
+        interp::Value::Bool(_) => "bool",
+        interp::Value::F64(_) => "f64",
+        interp::Value::I32(_) => "i32",
+        interp::Value::I64(_) => "i64",
+        interp::Value::Str(_) => "str",
+        interp::Value::Array(_) => "array",
+        interp::Value::Struct(..) => "struct",
+        interp::Value::Function(_) => "fn",
+    };
+
+    Outcome::Value(ty, value.to_string(), value.as_f64())
+}
+
+/// 評価の失敗を結果に変換する
+fn error_outcome(errs: &[Diagnostic]) -> Outcome {
+    Outcome::Error(errs.iter().map(|err| err.message.clone()).collect())
+}
+
+/// インタプリタの出力を捕捉する、共有されたバッファ
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// 書き込まれた内容を取り出す
+    fn take(&self) -> String {
+        let bytes = self.0.borrow_mut().split_off(0);
+
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// プログラムの各要素の結果と、組み込み関数の出力
+struct Run {
+    outcomes: Vec<ItemOutcome>,
+    output: String,
+}
+
+/// 要素のソースと、評価した結果
+struct ItemOutcome {
+    source: String,
+    /// 要素に'# expect error'のコメントが付いているか
+    expects_error: bool,
+    outcome: Outcome,
+}
+
+impl ItemOutcome {
+    fn new(text: &str, item: &Item, outcome: Outcome) -> ItemOutcome {
+        let span = match *item {
+            Item::Function(ref fun) => fun.span,
+            Item::Global(ref global) => global.span,
+            Item::Struct(ref def) => def.span,
+        };
+        let rest = text.get(span.end..).unwrap_or("");
+        let line = rest.split('\n').next().unwrap_or("");
+
+        ItemOutcome {
+            source: text
+                .get(span.start..span.end)
+                .unwrap_or("<unknown>")
+                .to_string(),
+            expects_error: line.trim_start().starts_with(EXPECT_ERROR),
+            outcome: outcome,
+        }
+    }
+}
+
+/// プログラムをREPLと同じくJITのセッションで評価する
+/// プログラムの構文解析に失敗した場合はErrとなる
+fn run_jit(text: &str, opt_level: u32) -> Result<Run, String> {
+    let context = Context::create();
+    let mut session = Session::new(&context, opt_level)?;
+    let items = parse(text, &mut session.operators)?;
+
+    let (outcomes, output) = crate::capture_output(|| {
+        items
+            .iter()
+            .map(|item| {
+                let outcome = match session.eval(item) {
+                    Ok(Some(value)) => jit_outcome(&value),
+                    Ok(None) => Outcome::Unit,
+                    Err(errs) => error_outcome(&errs),
+                };
+
+                ItemOutcome::new(text, item, outcome)
+            })
+            .collect()
+    });
+
+    Ok(Run {
+        outcomes: outcomes,
+        output: output,
+    })
+}
+
+/// プログラムをインタプリタで評価する
+fn run_interpreter(text: &str) -> Result<Run, String> {
+    let buffer = SharedBuffer::default();
+    let mut interpreter = Interpreter::with_output(Box::new(buffer.clone()));
+    let items = parse(text, &mut interpreter.operators)?;
+
+    let outcomes = items
+        .iter()
+        .map(|item| {
+            let outcome = match interpreter.eval(item) {
+                Ok(Some(value)) => interp_outcome(&value),
+                Ok(None) => Outcome::Unit,
+                Err(errs) => error_outcome(&errs),
+            };
+
+            ItemOutcome::new(text, item, outcome)
+        })
+        .collect();
+
+    Ok(Run {
+        outcomes: outcomes,
+        output: buffer.take(),
+    })
+}
+
+/// プログラム全体を構文解析する
+/// 演算子の定義は、評価を行うバックエンドの演算子表に登録される
+fn parse(text: &str, operators: &mut OperatorTable) -> Result<Vec<Item>, String> {
+    let (items, diagnostics) = Parser::new(text.to_string(), operators).parse_program();
+
+    match diagnostics.first() {
+        Some(err) => Err(format!("could not parse the program: {}", err.message)),
+        None => Ok(items),
+    }
+}
+
+/// '# expect error'のコメントと、失敗したかどうかが一致しない要素の報告を返す
+fn unexpected_outcomes(run: &Run) -> Vec<String> {
+    run.outcomes
+        .iter()
+        .enumerate()
+        .filter(|&(_, item)| item.outcome.is_error() != item.expects_error)
+        .map(|(i, item)| {
+            let problem = if item.expects_error {
+                "was expected to fail"
+            } else {
+                "failed"
+            };
+
+            format!(
+                "item {} {}\n    {}\n    interpreter: {}",
+                i + 1,
+                problem,
+                item.source,
+                item.outcome.describe()
+            )
+        })
+        .collect()
+}
+
+/// 1つのプログラムをJITとインタプリタで評価し、見つかった不一致の報告を返す
+fn check_program(text: &str) -> Vec<String> {
+    let expected = match run_interpreter(text) {
+        Ok(run) => run,
+        Err(msg) => return vec![msg],
+    };
+
+    // 両方のバックエンドが一致して失敗した場合も、期待されていなければ報告する
+    let mut mismatches = unexpected_outcomes(&expected);
+
+    for &opt_level in &OPT_LEVELS {
+        let actual = match run_jit(text, opt_level) {
+            Ok(run) => run,
+            Err(msg) => {
+                mismatches.push(format!("-O{}: {}", opt_level, msg));
+                continue;
+            }
+        };
+
+        // 両方のバックエンドは同じソースを解析するため、要素は1対1に対応する
+        let pairs = actual.outcomes.iter().zip(&expected.outcomes);
+
+        for (i, (jit, interp)) in pairs.enumerate() {
+            if !jit.outcome.agrees(&interp.outcome) {
+                mismatches.push(format!(
+                    "-O{}: item {} differs\n    {}\n    jit:         {}\n    interpreter: {}",
+                    opt_level,
+                    i + 1,
+                    jit.source,
+                    jit.outcome.describe(),
+                    interp.outcome.describe()
+                ));
+            }
+        }
+
+        if actual.output != expected.output {
+            mismatches.push(format!(
+                "-O{}: output differs\n    jit:         {:?}\n    interpreter: {:?}",
+                opt_level, actual.output, expected.output
+            ));
+        }
+    }
+
+    mismatches
+}
+
+/// 組み込みのコーパスと指定されたファイルを差分テストし、終了コードを返す
+/// 不一致があったプログラムは、その内容とともに報告される
+pub fn run(inputs: &[String]) -> i32 {
+    let mut programs: Vec<(String, String)> = CORPUS
+        .iter()
+        .map(|&(name, text)| (name.to_string(), text.to_string()))
+        .collect();
+
+    for path in inputs {
+        match Source::load(path) {
+            Ok(source) => programs.push((source.name, source.text)),
+            Err(err) => {
+                eprintln!("error: could not read '{}': {}", path, err);
+
+                return driver::EXIT_FAILURE;
+            }
+        }
+    }
+
+    let mut failed = 0;
+
+    for &(ref name, ref text) in &programs {
+        let mismatches = check_program(text);
+
+        if mismatches.is_empty() {
+            println!("ok       {}", name);
+        } else {
+            failed += 1;
+
+            println!("MISMATCH {}", name);
+
+            for mismatch in mismatches {
+                println!("  {}", mismatch);
+            }
+        }
+    }
+
+    println!(
+        "\n{} program(s) checked, {} mismatch(es)",
+        programs.len(),
+        failed
+    );
+
+    if failed == 0 {
+        0
+    } else {
+        driver::EXIT_FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// 組み込みのコーパスの全てのプログラムで、JITとインタプリタの結果が一致する
+    #[test]
+    fn corpus_agrees() {
+        // '--difftest'と同じく、深い再帰のために大きなスタックで実行する
+        let mismatches = thread::Builder::new()
+            .stack_size(crate::INTERPRETER_STACK_SIZE)
+            .spawn(|| {
+                CORPUS
+                    .iter()
+                    .flat_map(|&(name, text)| {
+                        check_program(text)
+                            .into_iter()
+                            .map(move |mismatch| format!("{}: {}", name, mismatch))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .expect("Could not start the difftest thread.")
+            .join()
+            .unwrap();
+
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+
+    /// コーパスで失敗するのは、'# expect error'が付いた要素のみ
+    #[test]
+    fn corpus_fails_only_where_expected() {
+        let problems = thread::Builder::new()
+            .stack_size(crate::INTERPRETER_STACK_SIZE)
+            .spawn(|| {
+                CORPUS
+                    .iter()
+                    .flat_map(|&(name, text)| {
+                        let run = run_interpreter(text).unwrap();
+
+                        unexpected_outcomes(&run)
+                            .into_iter()
+                            .map(move |problem| format!("{}: {}", name, problem))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .expect("Could not start the difftest thread.")
+            .join()
+            .unwrap();
+
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
+    }
+
+    /// 型の異なる値と、期待されていない失敗は不一致となる
+    #[test]
+    fn outcomes_compare_types() {
+        let run = run_interpreter("1 + 2\ni64(3)\n1 + \"a\"\n2 * 2 # expect error").unwrap();
+        let outcomes: Vec<&Outcome> = run.outcomes.iter().map(|item| &item.outcome).collect();
+
+        assert!(!outcomes[0].agrees(outcomes[1]));
+        assert!(outcomes[0].agrees(&Outcome::Value("f64", "3".to_string(), Some(3.0))));
+
+        let problems = unexpected_outcomes(&run);
+
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("item 3 failed"), "{}", problems[0]);
+        assert!(
+            problems[1].starts_with("item 4 was expected to fail"),
+            "{}",
+            problems[1]
+        );
+    }
+}
@@ -0,0 +1,655 @@
+use crate::backend;
+use crate::bytecode::{Program, ProgramBuilder};
+use crate::cli::{Emit, Options};
+use crate::compiler::Compiler;
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{Lexer, Span, SpannedToken, Token};
+use crate::link;
+use crate::operators::OperatorTable;
+use crate::parser::{Item, Parser, Type};
+use crate::resolve::{Resolver, Symbols};
+use crate::typeck::{self, DeclTable, StructTable};
+use crate::vm::Vm;
+
+use inkwell::context::Context;
+use inkwell::module::Module;
+use inkwell::passes::PassManager;
+use inkwell::targets::FileType;
+use inkwell::values::FunctionValue;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// 引数の誤りやファイルの入出力に失敗した場合の終了コード
+pub const EXIT_FAILURE: i32 = 1;
+
+/// 字句解析、または構文解析に失敗した場合の終了コード
+pub const EXIT_SYNTAX_ERROR: i32 = 2;
+
+/// コード生成に失敗した場合の終了コード
+pub const EXIT_COMPILE_ERROR: i32 = 3;
+
+/// 読み込まれたソースファイル
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    /// 指定されたパスからソースを読み込む
+    /// パスが'-'の場合は標準入力から読み込む
+    pub fn load(path: &str) -> io::Result<Source> {
+        let mut text = String::new();
+
+        if path == "-" {
+            io::stdin().read_to_string(&mut text)?;
+
+            return Ok(Source {
+                name: "<stdin>".to_string(),
+                text: text,
+            });
+        }
+
+        File::open(path)?.read_to_string(&mut text)?;
+
+        Ok(Source {
+            name: path.to_string(),
+            text: text,
+        })
+    }
+
+    /// このソースで発生した診断を標準エラー出力に表示
+    pub fn report(&self, err: &Diagnostic) {
+        eprint!("{}", err.render(&self.text, &self.name));
+    }
+}
+
+/// 最適化レベルに応じた関数パスマネージャを作成
+pub fn create_fpm<'ctx>(module: &Module<'ctx>, opt_level: u32) -> PassManager<FunctionValue<'ctx>> {
+    let fpm = PassManager::create(module);
+
+    match opt_level {
+        0 => (),
+
+        1 => {
+            fpm.add_promote_memory_to_register_pass();
+            fpm.add_instruction_combining_pass();
+            fpm.add_reassociate_pass();
+        }
+
+        _ => {
+            fpm.add_instruction_combining_pass();
+            fpm.add_reassociate_pass();
+            fpm.add_gvn_pass();
+            fpm.add_cfg_simplification_pass();
+            fpm.add_basic_alias_analysis_pass();
+            fpm.add_promote_memory_to_register_pass();
+            fpm.add_instruction_combining_pass();
+            fpm.add_reassociate_pass();
+
+            if opt_level >= 3 {
+                fpm.add_tail_call_elimination_pass();
+                fpm.add_licm_pass();
+                fpm.add_cfg_simplification_pass();
+            }
+        }
+    }
+
+    fpm.initialize();
+
+    fpm
+}
+
+/// オプションに従って入力ファイルをコンパイルし、終了コードを返す
+pub fn run(options: &Options) -> i32 {
+    // コンパイル済みのバイトコードは、構文解析をせずにそのまま実行する
+    if options.vm && options.inputs.iter().any(|path| path.ends_with(".ksc")) {
+        return run_compiled(options);
+    }
+
+    let mut sources = Vec::with_capacity(options.inputs.len());
+
+    for path in &options.inputs {
+        match Source::load(path) {
+            Ok(source) => sources.push(source),
+            Err(err) => {
+                eprintln!("error: could not read '{}': {}", path, err);
+
+                return EXIT_FAILURE;
+            }
+        }
+    }
+
+    if options.emit == Emit::Tokens {
+        return emit_tokens(options, &sources);
+    }
+
+    // 全てのファイルを解析する
+    // 演算子の定義は後続のファイルにも引き継がれる
+    let mut operators = OperatorTable::new();
+    let mut items: Vec<(usize, Item)> = Vec::new();
+    let mut failed = false;
+
+    for (i, source) in sources.iter().enumerate() {
+        let (parsed, diagnostics) =
+            Parser::new(source.text.clone(), &mut operators).parse_program();
+
+        for err in &diagnostics {
+            source.report(err);
+        }
+
+        failed |= !diagnostics.is_empty();
+        items.extend(parsed.into_iter().map(|item| (i, item)));
+    }
+
+    if failed {
+        return EXIT_SYNTAX_ERROR;
+    }
+
+    if options.emit == Emit::Ast {
+        let mut out = String::new();
+
+        for &(_, ref item) in &items {
+            out.push_str(&format!("{:#?}\n", item));
+        }
+
+        return write_text(options, &sources, &out);
+    }
+
+    // REPLと異なり、ファイルでは関数を再定義できない
+    if !check_redefinitions(&sources, &items) {
+        return EXIT_COMPILE_ERROR;
+    }
+
+    if options.vm || options.emit == Emit::Ksc {
+        return run_bytecode(options, &sources, &items);
+    }
+
+    // 実行ファイルではランタイムが'main'を定義するため、ユーザーの'main'の名前を変更する
+    if options.emit == Emit::Exe
+        && !link::rename_entry_point(items.iter_mut().map(|item| &mut item.1))
+    {
+        eprintln!("error: no 'main' function is defined");
+
+        return EXIT_COMPILE_ERROR;
+    }
+
+    // ランタイムは'main'の戻り値をf64として受け取る
+    let main_ret_type = items.iter().find_map(|item| match item.1 {
+        Item::Function(ref fun) if fun.prototype.name == link::ENTRY_SYMBOL => {
+            Some(fun.prototype.ret_type.clone())
+        }
+        _ => None,
+    });
+
+    if options.emit == Emit::Exe && main_ret_type != Some(Type::F64) {
+        eprintln!("error: 'main' function must return 'f64'");
+
+        return EXIT_COMPILE_ERROR;
+    }
+
+    failed |= !resolve(&sources, &items);
+
+    // 構造体は宣言の位置に関係なく、全ての関数から参照できる
+    let mut structs = StructTable::new();
+    let mut struct_defs = Vec::new();
+
+    for &(i, ref item) in &items {
+        if let Item::Struct(ref def) = *item {
+            match structs.add(def) {
+                Ok(()) => struct_defs.push(def),
+                Err(err) => {
+                    sources[i].report(&err);
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed {
+        return EXIT_COMPILE_ERROR;
+    }
+
+    // 全ての関数とグローバル変数を同じモジュールにコンパイル
+    let context = Context::create();
+    let module = context.create_module(&output_stem(&sources));
+    let builder = context.create_builder();
+    let fpm = create_fpm(&module, options.opt_level);
+
+    Compiler::declare_structs(&context, &module, &struct_defs);
+
+    // 後に定義される関数やグローバル変数を参照できるように、先に全て宣言しておく
+    for &(_, ref item) in &items {
+        match *item {
+            Item::Function(ref fun) if !fun.is_anon => {
+                Compiler::declare(&context, &module, &fun.prototype);
+            }
+            Item::Function(_) | Item::Struct(_) => (),
+            Item::Global(ref global) => {
+                Compiler::declare_global(&context, &module, &global.name);
+            }
+        }
+    }
+
+    let mut initializers = Vec::new();
+
+    for &(i, ref item) in &items {
+        let result = match *item {
+            Item::Function(ref fun) => Compiler::compile(
+                &context,
+                &builder,
+                &fpm,
+                &module,
+                &structs,
+                fun,
+                options.checks,
+            )
+            .map(|_| ()),
+            Item::Global(ref global) => Compiler::compile_global(
+                &context,
+                &builder,
+                &fpm,
+                &module,
+                &structs,
+                global,
+                options.checks,
+            )
+            .map(|init| initializers.extend(init)),
+            Item::Struct(_) => Ok(()),
+        };
+
+        if let Err(err) = result {
+            sources[i].report(&err);
+            failed = true;
+        }
+    }
+
+    if failed {
+        return EXIT_COMPILE_ERROR;
+    }
+
+    // 定数に畳み込めなかった初期化式は、モジュールのコンストラクタで宣言順に実行する
+    if !initializers.is_empty() {
+        Compiler::compile_global_ctor(&context, &builder, &module, &initializers);
+    }
+
+    match options.emit {
+        Emit::LlvmIr => write_text(options, &sources, &module.print_to_string().to_string()),
+
+        Emit::Bitcode => {
+            let path = output_path(options, &sources);
+
+            if module.write_bitcode_to_path(Path::new(&path)) {
+                0
+            } else {
+                eprintln!("error: could not write bitcode to '{}'", path);
+
+                EXIT_FAILURE
+            }
+        }
+
+        Emit::Asm => emit_native(options, &sources, &module, FileType::Assembly),
+
+        Emit::Obj => emit_native(options, &sources, &module, FileType::Object),
+
+        Emit::Exe => {
+            let path = output_path(options, &sources);
+
+            match link::link_executable(&module, &options.target, options.opt_level, &path) {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("error: could not link '{}': {}", path, err);
+
+                    EXIT_FAILURE
+                }
+            }
+        }
+
+        Emit::Tokens | Emit::Ast | Emit::Ksc => unreachable!(),
+    }
+}
+
+/// 本体を持つ同じ名前の関数が、2回以上定義されていないかを検査する
+/// 'extern'による宣言は、定義の前後に何度あってもよい
+fn check_redefinitions(sources: &[Source], items: &[(usize, Item)]) -> bool {
+    let mut definitions: HashMap<&str, (usize, Span)> = HashMap::new();
+    let mut ok = true;
+
+    for &(i, ref item) in items {
+        let proto = match *item {
+            Item::Function(ref fun) if fun.body.is_some() && !fun.is_anon => &fun.prototype,
+            _ => continue,
+        };
+
+        let (first_source, first_span) = match definitions.get(proto.name.as_str()) {
+            Some(&first) => first,
+            None => {
+                definitions.insert(&proto.name, (i, proto.span));
+                continue;
+            }
+        };
+
+        let err = Diagnostic::error(format!(
+            "Function '{}' is defined more than once.",
+            proto.name
+        ))
+        .with_code("E0325")
+        .with_primary(proto.span, "redefined here");
+
+        // 別のファイルの位置はラベルとして表示できないため、注記で示す
+        let err = if first_source == i {
+            err.with_secondary(first_span, "first defined here")
+        } else {
+            err.with_note(format!(
+                "first defined in '{}' at {}:{}",
+                sources[first_source].name, first_span.line, first_span.column
+            ))
+        };
+
+        sources[i].report(&err);
+        ok = false;
+    }
+
+    ok
+}
+
+/// コード生成の前に全ての名前を解決し、成功したかを返す
+/// 関数とグローバル変数は、定義より前の位置からも参照できる
+fn resolve(sources: &[Source], items: &[(usize, Item)]) -> bool {
+    let mut symbols = Symbols::new();
+    let mut resolved = true;
+
+    for &(_, ref item) in items {
+        symbols.add_item(item);
+    }
+
+    for &(i, ref item) in items {
+        for err in Resolver::resolve_item(&symbols, item) {
+            sources[i].report(&err);
+            resolved = false;
+        }
+    }
+
+    resolved
+}
+
+/// LLVMのモジュールを使わずに、JITと同じ規則で全ての要素を型検査する
+/// 型が設定された要素を返し、失敗した場合はNoneとなる
+fn type_check(sources: &[Source], items: &[(usize, Item)]) -> Option<Vec<(usize, Item)>> {
+    let mut decls = DeclTable::new();
+    let mut structs = StructTable::new();
+    let mut failed = false;
+
+    for &(i, ref item) in items {
+        decls.add_item(item);
+
+        if let Item::Struct(ref def) = *item {
+            if let Err(err) = structs.add(def) {
+                sources[i].report(&err);
+                failed = true;
+            }
+        }
+    }
+
+    let mut checked = Vec::with_capacity(items.len());
+
+    for &(i, ref item) in items {
+        match typeck::check_item(&decls, &structs, item) {
+            Ok(item) => checked.push((i, item)),
+            Err(err) => {
+                sources[i].report(&err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        None
+    } else {
+        Some(checked)
+    }
+}
+
+/// バイトコードにコンパイルし、'.ksc'ファイルに書き出すか、'--vm'の場合はそのまま実行する
+fn run_bytecode(options: &Options, sources: &[Source], items: &[(usize, Item)]) -> i32 {
+    if !resolve(sources, items) {
+        return EXIT_COMPILE_ERROR;
+    }
+
+    // JITが拒否するプログラムを受け付けないように、コンパイルの前に型検査する
+    let items = match type_check(sources, items) {
+        Some(items) => items,
+        None => return EXIT_COMPILE_ERROR,
+    };
+
+    let mut builder = ProgramBuilder::new();
+    let mut failed = false;
+
+    for &(i, ref item) in &items {
+        if let Err(err) = builder.declare(item) {
+            sources[i].report(&err);
+            failed = true;
+        }
+    }
+
+    // 宣言に失敗した要素は、コンパイルでも同じエラーとなる
+    if !failed {
+        for &(i, ref item) in &items {
+            if let Err(err) = builder.compile(item) {
+                sources[i].report(&err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        return EXIT_COMPILE_ERROR;
+    }
+
+    let program = builder.finish();
+
+    if options.vm {
+        return run_vm(program, &output_stem(sources));
+    }
+
+    let path = output_path(options, sources);
+
+    match File::create(&path).and_then(|mut file| program.write_to(&mut file)) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: could not write bytecode to '{}': {}", path, err);
+
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// '.ksc'ファイルを読み込んでVMで実行する
+fn run_compiled(options: &Options) -> i32 {
+    if options.inputs.len() != 1 {
+        eprintln!("error: a compiled '.ksc' program must be the only input");
+
+        return EXIT_FAILURE;
+    }
+
+    let path = &options.inputs[0];
+
+    match File::open(path).and_then(|mut file| Program::read_from(&mut file)) {
+        Ok(program) => run_vm(program, path),
+        Err(err) => {
+            eprintln!("error: could not load '{}': {}", path, err);
+
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// プログラムをVMで実行し、トップレベルの式の値をREPLと同じ形式で表示する
+fn run_vm(program: Program, name: &str) -> i32 {
+    let mut vm = match Vm::new(program) {
+        Ok(vm) => vm,
+        Err(msg) => {
+            eprintln!("error: invalid bytecode in '{}': {}", name, msg);
+
+            return EXIT_FAILURE;
+        }
+    };
+
+    match vm.run(|value| println!("=> {}", value)) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprint!("{}", err.render("", name));
+
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// TargetMachineを利用して、モジュールをアセンブリかオブジェクトファイルとして出力
+fn emit_native(options: &Options, sources: &[Source], module: &Module, file_type: FileType) -> i32 {
+    let path = output_path(options, sources);
+    let result = backend::create_target_machine(&options.target, options.opt_level)
+        .and_then(|machine| backend::write_module(&machine, module, file_type, &path));
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: could not emit '{}': {}", path, err);
+
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// 各ソースのトークン列を位置情報とともに出力
+fn emit_tokens(options: &Options, sources: &[Source]) -> i32 {
+    let mut out = String::new();
+    let mut failed = false;
+
+    for source in sources {
+        let mut lexer = Lexer::new(&source.text);
+
+        loop {
+            match lexer.lex() {
+                Ok(SpannedToken {
+                    token: Token::EOF, ..
+                }) => break,
+                Ok(tok) => out.push_str(&format!(
+                    "{}:{}:{}\t{:?}\n",
+                    source.name, tok.span.line, tok.span.column, tok.token
+                )),
+                Err(err) => {
+                    source.report(&err.into());
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed {
+        return EXIT_SYNTAX_ERROR;
+    }
+
+    write_text(options, sources, &out)
+}
+
+/// テキスト形式の出力を書き込む
+/// トークン列とASTは'-o'が指定されない限り標準出力に書き込む
+fn write_text(options: &Options, sources: &[Source], text: &str) -> i32 {
+    let to_stdout = match options.output {
+        Some(ref path) => path == "-",
+        None => options.emit == Emit::Tokens || options.emit == Emit::Ast,
+    };
+
+    let result = if to_stdout {
+        io::stdout().write_all(text.as_bytes())
+    } else {
+        File::create(output_path(options, sources)).and_then(|mut f| f.write_all(text.as_bytes()))
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: could not write output: {}", err);
+
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// 出力ファイルのパスを返す
+/// '-o'が指定されない場合は、最初の入力ファイル名の拡張子を出力形式に合わせて置き換える
+pub fn output_path(options: &Options, sources: &[Source]) -> String {
+    if let Some(ref path) = options.output {
+        return path.clone();
+    }
+
+    let stem = output_stem(sources);
+
+    match options.emit.extension() {
+        "" => stem,
+        ext => format!("{}.{}", stem, ext),
+    }
+}
+
+/// 最初の入力ファイル名から拡張子を除いた名前を返す
+/// 標準入力の場合は"main"となる
+fn output_stem(sources: &[Source]) -> String {
+    sources
+        .first()
+        .filter(|source| source.name != "<stdin>")
+        .and_then(|source| Path::new(&source.name).file_stem())
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("main")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 各ソースを解析し、ソースの番号を付けた要素を返す
+    fn parse(texts: &[&str]) -> (Vec<Source>, Vec<(usize, Item)>) {
+        let mut operators = OperatorTable::new();
+        let mut sources = Vec::new();
+        let mut items = Vec::new();
+
+        for (i, text) in texts.iter().enumerate() {
+            let (parsed, diagnostics) =
+                Parser::new(text.to_string(), &mut operators).parse_program();
+
+            assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+            sources.push(Source {
+                name: format!("test{}.ks", i),
+                text: text.to_string(),
+            });
+            items.extend(parsed.into_iter().map(|item| (i, item)));
+        }
+
+        (sources, items)
+    }
+
+    /// 同じ名前の関数の再定義は、同じファイルでも別のファイルでも拒否される
+    #[test]
+    fn redefinition_is_rejected() {
+        let (sources, items) = parse(&["def foo(x) x\ndef foo(x) x * 2"]);
+
+        assert!(!check_redefinitions(&sources, &items));
+
+        let (sources, items) = parse(&["def foo(x) x", "def foo(x) x * 2"]);
+
+        assert!(!check_redefinitions(&sources, &items));
+    }
+
+    /// 'extern'による宣言と、その後の定義は再定義とならない
+    #[test]
+    fn declarations_are_not_redefinitions() {
+        let (sources, items) =
+            parse(&["extern foo(x)\ndef foo(x) x\nextern foo(x)\nfoo(1)\nfoo(2)"]);
+
+        assert!(check_redefinitions(&sources, &items));
+    }
+}
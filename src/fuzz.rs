@@ -0,0 +1,506 @@
+use crate::compiler::Compiler;
+use crate::driver::{self, create_fpm};
+use crate::operators::OperatorTable;
+use crate::parser::{Item, Parser};
+use crate::resolve::{Resolver, Symbols};
+use crate::typeck::StructTable;
+
+use inkwell::context::Context;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ユーザー定義演算子に使われる文字
+/// 型注釈や代入、フィールドアクセスに使われる文字は含まない
+const OPERATOR_CHARS: [char; 9] = ['!', '%', '&', '|', '^', '~', '@', '$', '?'];
+
+/// 算術を行う組み込みの二項演算子
+const BUILTIN_OPERATORS: [char; 4] = ['+', '-', '*', '/'];
+
+/// 1つのプログラムに含まれる要素の最大数
+const MAX_ITEMS: usize = 12;
+
+/// 式の入れ子の最大の深さ
+const MAX_DEPTH: usize = 4;
+
+/// シードから再現可能な擬似乱数生成器(xorshift64*)
+struct Rng(u64);
+
+impl Rng {
+    /// シードから生成器を作成する
+    /// 状態が0にならないよう、シードはsplitmix64で撹拌する
+    fn new(seed: u64) -> Rng {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// 0以上'n'未満の値を返す
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// 'percent'%の確率でtrueを返す
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// 構文的に正しく、型検査を通るKaleidoscopeのプログラムを無作為に生成する
+/// 全ての値はf64であり、比較は条件式にのみ現れる
+pub struct Generator {
+    rng: Rng,
+    /// 呼び出せる関数の名前と引数の数
+    functions: Vec<(String, usize)>,
+    binary_ops: Vec<char>,
+    unary_ops: Vec<char>,
+    /// 参照できる変数の名前
+    scope: Vec<String>,
+    /// 名前を重複させないための連番
+    names: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            rng: Rng::new(seed),
+            functions: Vec::new(),
+            binary_ops: Vec::new(),
+            unary_ops: Vec::new(),
+            scope: Vec::new(),
+            names: 0,
+        }
+    }
+
+    /// 改行で区切られた要素からなるプログラムを生成する
+    pub fn program(&mut self) -> String {
+        let mut lines = Vec::new();
+
+        for _ in 0..1 + self.rng.below(MAX_ITEMS) {
+            lines.push(self.item());
+        }
+
+        lines.join("\n")
+    }
+
+    fn item(&mut self) -> String {
+        match self.rng.below(10) {
+            0 | 1 => self.extern_decl(),
+            2 => self.binary_def(),
+            3 => self.unary_def(),
+            4 | 5 if !self.functions.is_empty() => self.top_level_call(),
+            _ => self.function_def(),
+        }
+    }
+
+    fn extern_decl(&mut self) -> String {
+        let name = self.fresh("ext");
+        let arity = self.rng.below(4);
+        let params = self.params(arity);
+        let text = format!("extern {}({})", name, params.join(", "));
+
+        self.functions.push((name, params.len()));
+
+        text
+    }
+
+    /// 再帰呼び出しができるよう、本体の生成前に関数を登録する
+    fn function_def(&mut self) -> String {
+        let name = self.fresh("f");
+        let arity = self.rng.below(4);
+        let params = self.params(arity);
+
+        self.functions.push((name.clone(), params.len()));
+
+        let body = self.body(&params);
+
+        format!("def {}({}) {}", name, params.join(", "), body)
+    }
+
+    /// 優先順位は組み込み演算子より低いものと高いものの両方を含む
+    fn binary_def(&mut self) -> String {
+        let op = match self.unused_op(true) {
+            Some(op) => op,
+            None => return self.function_def(),
+        };
+        let prec = 1 + self.rng.below(100);
+        let params = self.params(2);
+
+        self.binary_ops.push(op);
+
+        let body = self.body(&params);
+
+        format!("def binary{} {} ({}) {}", op, prec, params.join(", "), body)
+    }
+
+    fn unary_def(&mut self) -> String {
+        let op = match self.unused_op(false) {
+            Some(op) => op,
+            None => return self.function_def(),
+        };
+        let params = self.params(1);
+
+        self.unary_ops.push(op);
+
+        let body = self.body(&params);
+
+        format!("def unary{}({}) {}", op, params[0], body)
+    }
+
+    /// トップレベルの式は、前の要素の続きとして解析されないよう関数呼び出しから始める
+    fn top_level_call(&mut self) -> String {
+        self.scope.clear();
+        self.call(MAX_DEPTH)
+    }
+
+    /// まだ定義されていない演算子の文字を選ぶ
+    fn unused_op(&mut self, binary: bool) -> Option<char> {
+        let defined = if binary {
+            &self.binary_ops
+        } else {
+            &self.unary_ops
+        };
+        let unused: Vec<char> = OPERATOR_CHARS
+            .iter()
+            .cloned()
+            .filter(|op| !defined.contains(op))
+            .collect();
+
+        if unused.is_empty() {
+            None
+        } else {
+            Some(unused[self.rng.below(unused.len())])
+        }
+    }
+
+    fn params(&mut self, count: usize) -> Vec<String> {
+        (0..count).map(|_| self.fresh("a")).collect()
+    }
+
+    /// 新しい名前を作成する
+    /// 'f64'などの組み込み関数の名前と重ならないように、番号の前に'_'を置く
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.names += 1;
+
+        format!("{}_{}", prefix, self.names)
+    }
+
+    /// 引数のみを参照できる関数本体を生成する
+    fn body(&mut self, params: &[String]) -> String {
+        self.scope = params.to_vec();
+
+        let depth = 1 + self.rng.below(MAX_DEPTH);
+
+        self.expr(depth)
+    }
+
+    fn expr(&mut self, depth: usize) -> String {
+        if depth == 0 {
+            return self.term(0);
+        }
+
+        match self.rng.below(10) {
+            0 => self.if_expr(depth),
+            1 => self.for_expr(depth),
+            2 => self.var_expr(depth),
+            _ => self.chain(depth),
+        }
+    }
+
+    /// 括弧のない二項演算の列を生成し、優先順位の解析を試す
+    fn chain(&mut self, depth: usize) -> String {
+        let mut text = self.term(depth - 1);
+
+        for _ in 0..self.rng.below(4) {
+            let op = self.binary_op();
+            let rhs = self.term(depth - 1);
+
+            text = format!("{} {} {}", text, op, rhs);
+        }
+
+        text
+    }
+
+    fn binary_op(&mut self) -> char {
+        let i = self
+            .rng
+            .below(BUILTIN_OPERATORS.len() + self.binary_ops.len());
+
+        match BUILTIN_OPERATORS.get(i) {
+            Some(&op) => op,
+            None => self.binary_ops[i - BUILTIN_OPERATORS.len()],
+        }
+    }
+
+    /// 二項演算の被演算子となる式
+    /// 'if'などの後ろに続く式を取り込む構文は括弧で囲む
+    fn term(&mut self, depth: usize) -> String {
+        let choice = if depth == 0 {
+            self.rng.below(3)
+        } else {
+            self.rng.below(8)
+        };
+
+        match choice {
+            0 => self.number(),
+            1 | 2 => match self.variable() {
+                Some(name) => name,
+                None => self.number(),
+            },
+            3 => self.call(depth),
+            4 if !self.unary_ops.is_empty() => {
+                let op = self.unary_ops[self.rng.below(self.unary_ops.len())];
+
+                format!("{}{}", op, self.term(depth - 1))
+            }
+            5 => match self.variable() {
+                Some(name) => format!("({} = {})", name, self.expr(depth - 1)),
+                None => self.number(),
+            },
+            _ => format!("({})", self.expr(depth - 1)),
+        }
+    }
+
+    fn variable(&mut self) -> Option<String> {
+        if self.scope.is_empty() {
+            None
+        } else {
+            Some(self.scope[self.rng.below(self.scope.len())].clone())
+        }
+    }
+
+    /// 整数、小数、'.'から始まる小数の形式で数値リテラルを生成する
+    fn number(&mut self) -> String {
+        let int = self.rng.below(1000);
+        let frac = self.rng.below(1000);
+
+        match self.rng.below(8) {
+            0 => int.to_string(),
+            1 => format!("{}.{}", int, frac),
+            2 => format!(".{}", frac),
+            3 => format!("{}.{}e-{}", int, frac, self.rng.below(4)),
+            4 => format!("0x{:X}", int),
+            5 => format!("0b{:b}", int),
+            6 => format!("0o{:o}", int),
+            // 3桁ごとに区切る
+            _ => format!("{}_{:03}", int, frac),
+        }
+    }
+
+    fn call(&mut self, depth: usize) -> String {
+        if self.functions.is_empty() {
+            return self.number();
+        }
+
+        let (name, arity) = self.functions[self.rng.below(self.functions.len())].clone();
+        let args: Vec<String> = (0..arity)
+            .map(|_| self.expr(depth.saturating_sub(1)))
+            .collect();
+
+        format!("{}({})", name, args.join(", "))
+    }
+
+    /// 条件式は数値か、2つの数値の比較とする
+    fn condition(&mut self, depth: usize) -> String {
+        let lhs = self.chain(depth);
+
+        if self.rng.chance(50) {
+            format!("{} < {}", lhs, self.chain(depth))
+        } else {
+            lhs
+        }
+    }
+
+    fn if_expr(&mut self, depth: usize) -> String {
+        format!(
+            "if {} then {} else {}",
+            self.condition(depth),
+            self.expr(depth - 1),
+            self.expr(depth - 1)
+        )
+    }
+
+    /// ループ変数は終了条件、増分、本体から参照できる
+    fn for_expr(&mut self, depth: usize) -> String {
+        let start = self.expr(depth - 1);
+        let name = self.fresh("i");
+
+        self.scope.push(name.clone());
+
+        let cond = self.condition(depth);
+        let step = if self.rng.chance(50) {
+            format!(", {}", self.expr(depth - 1))
+        } else {
+            String::new()
+        };
+        let body = self.expr(depth - 1);
+
+        self.scope.pop();
+
+        format!("for {} = {}, {}{} in {}", name, start, cond, step, body)
+    }
+
+    /// 初期化式は外側のスコープのみを参照し、省略された変数は0となる
+    fn var_expr(&mut self, depth: usize) -> String {
+        let mut bindings = Vec::new();
+        let mut names = Vec::new();
+
+        for _ in 0..1 + self.rng.below(3) {
+            let name = self.fresh("v");
+
+            if self.rng.chance(75) {
+                bindings.push(format!("{} = {}", name, self.expr(depth - 1)));
+            } else {
+                bindings.push(name.clone());
+            }
+
+            names.push(name);
+        }
+
+        let outer = self.scope.len();
+
+        self.scope.extend(names);
+
+        let body = self.expr(depth - 1);
+
+        self.scope.truncate(outer);
+
+        format!("var {} in {}", bindings.join(", "), body)
+    }
+}
+
+/// 生成されたプログラムを解析、コンパイルし、最初に見つかった問題を返す
+/// 関数は全て検証され、最後にモジュール全体も検証される
+fn check_program(text: &str, opt_level: u32, checks: bool) -> Result<(), String> {
+    let mut operators = OperatorTable::new();
+    let (items, diagnostics) = Parser::new(text.to_string(), &mut operators).parse_program();
+
+    if let Some(err) = diagnostics.first() {
+        return Err(format!("parse error: {}", err.message));
+    }
+
+    let mut symbols = Symbols::new();
+
+    for item in &items {
+        symbols.add_item(item);
+    }
+
+    for item in &items {
+        if let Some(err) = Resolver::resolve_item(&symbols, item).first() {
+            return Err(format!("resolve error: {}", err.message));
+        }
+    }
+
+    let context = Context::create();
+    let module = context.create_module("fuzz");
+    let builder = context.create_builder();
+    let fpm = create_fpm(&module, opt_level);
+    let structs = StructTable::new();
+
+    for item in &items {
+        if let Item::Function(ref fun) = *item {
+            if !fun.is_anon {
+                Compiler::declare(&context, &module, &fun.prototype);
+            }
+        }
+    }
+
+    for item in &items {
+        let fun = match *item {
+            Item::Function(ref fun) => fun,
+            Item::Global(_) | Item::Struct(_) => continue,
+        };
+        let fn_value = Compiler::compile(&context, &builder, &fpm, &module, &structs, fun, checks)
+            .map_err(|err| format!("compile error: {}", err.message))?;
+
+        if !fn_value.verify(true) {
+            return Err(format!("function '{}' does not verify", fun.prototype.name));
+        }
+    }
+
+    module
+        .verify()
+        .map_err(|err| format!("module does not verify: {}", err.to_string()))
+}
+
+/// シードごとにプログラムを生成して検査し、終了コードを返す
+/// 失敗したプログラムは、再現に使うシードとともに報告される
+pub fn run(count: u64, seed: Option<u64>) -> i32 {
+    let base = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+    });
+
+    println!("fuzzing {} program(s) from seed {}", count, base);
+
+    let mut failed = 0;
+
+    for i in 0..count {
+        let seed = base.wrapping_add(i);
+        let mut generator = Generator::new(seed);
+        let text = generator.program();
+        let opt_level = generator.rng.below(4) as u32;
+        let checks = generator.rng.chance(50);
+
+        let result =
+            panic::catch_unwind(AssertUnwindSafe(|| check_program(&text, opt_level, checks)))
+                .unwrap_or_else(|_| Err("panicked".to_string()));
+
+        if let Err(msg) = result {
+            failed += 1;
+
+            println!(
+                "\nFAIL seed {} (-O{}{}): {}\n{}",
+                seed,
+                opt_level,
+                if checks { ", --checks" } else { "" },
+                msg,
+                text
+            );
+        }
+    }
+
+    println!("\n{} program(s) checked, {} failure(s)", count, failed);
+
+    if failed == 0 {
+        0
+    } else {
+        driver::EXIT_FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 固定されたシードの範囲で生成したプログラムが、全てコンパイルと検証に成功する
+    #[test]
+    fn fixed_seeds_verify() {
+        let failures: Vec<String> = (0..64)
+            .filter_map(|seed| {
+                let mut generator = Generator::new(seed);
+                let text = generator.program();
+                let opt_level = generator.rng.below(4) as u32;
+                let checks = generator.rng.chance(50);
+
+                check_program(&text, opt_level, checks)
+                    .err()
+                    .map(|msg| format!("seed {} (-O{}): {}\n{}", seed, opt_level, msg, text))
+            })
+            .collect();
+
+        assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+    }
+}
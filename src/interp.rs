@@ -0,0 +1,1062 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::operators::OperatorTable;
+use crate::parser::*;
+use crate::resolve::{Resolver, Symbols};
+use crate::typeck::{self, DeclTable, StructTable};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// インタプリタで許される、関数呼び出しの最大の深さ
+/// 式の評価はRustのスタックを再帰的に使うため、JITより小さな値とする
+pub const MAX_CALL_DEPTH: usize = 1_000;
+
+/// インタプリタが扱う値
+/// 配列と構造体はJITと同じく参照として共有される
+#[derive(Debug, Clone)]
+pub enum Value {
+    Bool(bool),
+    F64(f64),
+    I32(i32),
+    I64(i64),
+    Str(String),
+    Array(Rc<RefCell<Vec<f64>>>),
+    /// 構造体の名前と、宣言された順のフィールドの値
+    Struct(String, Rc<RefCell<Vec<(String, Value)>>>),
+    Function(Rc<Callable>),
+}
+
+/// 関数値として呼び出される関数
+#[derive(Debug)]
+pub enum Callable {
+    /// 名前で参照された、定義済みの関数
+    Named(Prototype),
+    /// 無名関数と、作成時にキャプチャしたローカル変数
+    Lambda {
+        params: Vec<(String, Option<Type>)>,
+        body: Expr,
+        env: Vec<(String, Value)>,
+    },
+}
+
+impl Value {
+    /// 数値として読み出す
+    /// boolは0か1となり、数値でない値はNoneとなる
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Bool(value) => Some(value as u8 as f64),
+            Value::F64(value) => Some(value),
+            Value::I32(value) => Some(f64::from(value)),
+            Value::I64(value) => Some(value as f64),
+            _ => None,
+        }
+    }
+
+    /// 条件としての真偽を返す
+    /// JITと同じく、NaNは偽となる
+    fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Bool(value) => value,
+            Value::F64(value) => !value.is_nan() && value != 0.0,
+            Value::I32(value) => value != 0,
+            Value::I64(value) => value != 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::F64(value) => write!(f, "{}", value),
+            Value::I32(value) => write!(f, "{}", value),
+            Value::I64(value) => write!(f, "{}", value),
+            Value::Str(ref value) => write!(f, "{:?}", value),
+            Value::Array(ref values) => write!(f, "{:?}", values.borrow()),
+            Value::Struct(ref name, ref fields) => {
+                write!(f, "{} {{", name)?;
+
+                let fields = fields.borrow();
+
+                if fields.is_empty() {
+                    return write!(f, " .. }}");
+                }
+
+                // 構造体のフィールドに含まれる構造体は、循環を避けるため中身を表示しない
+                for (i, &(ref field, ref value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+
+                    match *value {
+                        Value::Struct(ref name, _) => {
+                            write!(f, "{} {}: {} {{ .. }}", sep, field, name)?
+                        }
+                        _ => write!(f, "{} {}: {}", sep, field, value)?,
+                    }
+                }
+
+                write!(f, " }}")
+            }
+            Value::Function(ref callable) => match **callable {
+                Callable::Named(ref proto) => write!(
+                    f,
+                    "<{}>",
+                    Type::Fn(proto.arg_types.clone(), Box::new(proto.ret_type.clone()))
+                ),
+                Callable::Lambda { .. } => write!(f, "<fn>"),
+            },
+        }
+    }
+}
+
+/// 式の評価を中断して制御を移す理由
+enum Unwind {
+    Break(Value),
+    Continue,
+    Return(Value),
+    Error(Diagnostic),
+    /// 呼び出された関数の中で発生し、呼び出し元の位置に付け替えられたエラー
+    Nested(Diagnostic),
+}
+
+type Eval = Result<Value, Unwind>;
+
+/// 構文木を直接評価するインタプリタ
+///
+/// LLVMを使わずにREPLの入力を評価する。関数、グローバル変数、構造体と演算子は
+/// 入力をまたいで保持され、JITのSessionと同じ意味で評価される。
+/// 入力はJITと同じく型検査され、型検査で挿入された変換とともに評価される。
+pub struct Interpreter {
+    /// 定義、または外部宣言された全ての関数
+    functions: HashMap<String, Rc<Function>>,
+    globals: HashMap<String, Value>,
+    structs: StructTable,
+    /// 評価中の関数のローカル変数
+    /// 内側の束縛ほど後ろに積まれ、スコープを抜けると取り除かれる
+    locals: Vec<(String, Value)>,
+    depth: usize,
+    /// 組み込み関数の出力先
+    out: Box<dyn Write>,
+
+    /// 入力をまたいで保持される演算子表
+    pub operators: OperatorTable,
+}
+
+impl Interpreter {
+    /// 標準出力に出力する、新しいインタプリタを作成
+    pub fn new() -> Interpreter {
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+
+    /// 組み込み関数の出力先を指定して、新しいインタプリタを作成
+    pub fn with_output(out: Box<dyn Write>) -> Interpreter {
+        Interpreter {
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            structs: StructTable::new(),
+            locals: Vec::new(),
+            depth: 0,
+            out: out,
+            operators: OperatorTable::new(),
+        }
+    }
+
+    /// 関数の定義、外部宣言、グローバル変数、構造体、またはトップレベルの式を評価する
+    /// トップレベルの式の場合は、その計算結果を返す
+    pub fn eval(&mut self, item: &Item) -> Result<Option<Value>, Vec<Diagnostic>> {
+        let problems = Resolver::resolve_item(&self.symbols(item), item);
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let item = typeck::check_item(&self.declarations(item), &self.structs, item)
+            .map_err(|err| vec![err])?;
+
+        let result = match item {
+            Item::Function(ref fun) if fun.is_anon => {
+                let body = fun.body.as_ref().unwrap();
+
+                self.eval_body(body, Vec::new())
+                    .map(Some)
+                    .map_err(into_diagnostic)
+            }
+            Item::Function(ref fun) => {
+                self.functions
+                    .insert(fun.prototype.name.clone(), Rc::new(fun.clone()));
+
+                Ok(None)
+            }
+            Item::Global(ref global) => {
+                let value = self
+                    .eval_body(&global.init, Vec::new())
+                    .map_err(into_diagnostic);
+
+                value.map(|value| {
+                    // グローバル変数はJITと同じくf64として保持する
+                    let value = convert(value, &Type::F64);
+
+                    self.globals.insert(global.name.clone(), value);
+
+                    None
+                })
+            }
+            Item::Struct(ref def) => self.structs.add(def).map(|()| None),
+        };
+
+        result.map_err(|err| vec![err])
+    }
+
+    /// 定義済みの関数をインタプリタから取り除く
+    /// 取り除かれた関数があった場合はtrueを返す
+    pub fn remove_function(&mut self, name: &str) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    /// ユーザー定義演算子と、それを実装する関数をインタプリタから取り除く
+    /// 取り除かれた演算子があった場合はtrueを返す
+    pub fn remove_operator(&mut self, op: char) -> bool {
+        let removed = self.operators.remove(op);
+
+        self.remove_function(&format!("binary{}", op));
+        self.remove_function(&format!("unary{}", op));
+
+        removed
+    }
+
+    /// 定義済みの名前と、評価する要素が定義する名前の表を作成
+    fn symbols(&self, item: &Item) -> Symbols {
+        let mut symbols = Symbols::new();
+
+        for fun in self.functions.values() {
+            symbols.add_function(&fun.prototype);
+        }
+
+        for global in self.globals.keys() {
+            symbols.add_global(global);
+        }
+
+        for def in self.structs.iter() {
+            symbols.add_struct(&def.name);
+        }
+
+        symbols.add_item(item);
+
+        symbols
+    }
+
+    /// 型検査で参照される、定義済みの関数とグローバル変数、評価する要素が定義するものの表を作成
+    fn declarations(&self, item: &Item) -> DeclTable {
+        let mut decls = DeclTable::new();
+
+        for fun in self.functions.values() {
+            decls.add_function(&fun.prototype);
+        }
+
+        for global in self.globals.keys() {
+            decls.add_global(global);
+        }
+
+        decls.add_item(item);
+
+        decls
+    }
+
+    /// 指定されたローカル変数の環境で関数の本体を評価する
+    /// 'return'はここで受け止められる
+    fn eval_body(&mut self, body: &Expr, locals: Vec<(String, Value)>) -> Eval {
+        let outer = mem::replace(&mut self.locals, locals);
+        let result = self.eval_expr(body);
+
+        self.locals = outer;
+
+        match result {
+            Err(Unwind::Return(value)) => Ok(value),
+            // 名前解決で、ループの外の'break'と'continue'は拒否されている
+            Err(Unwind::Break(_)) | Err(Unwind::Continue) => Err(error(runtime_error(
+                body.span,
+                "Unexpected control flow out of a function.",
+            ))),
+            result => result,
+        }
+    }
+
+    /// 関数の本体を呼び出す
+    /// 関数の定義は以前の入力にあるため、発生したエラーは呼び出し元の位置に付け替える
+    fn call_body(
+        &mut self,
+        function: &str,
+        body: &Expr,
+        locals: Vec<(String, Value)>,
+        span: Span,
+    ) -> Eval {
+        self.enter(span)?;
+
+        let result = self.eval_body(body, locals);
+
+        self.depth -= 1;
+
+        result.map_err(|unwind| match unwind {
+            Unwind::Error(err) => {
+                let note = match err.primary {
+                    Some(ref label) => format!(
+                        "in function '{}' at {}:{}",
+                        function, label.span.line, label.span.column
+                    ),
+                    None => format!("in function '{}'", function),
+                };
+
+                Unwind::Nested(err.with_primary(span, "in this call").with_note(note))
+            }
+            Unwind::Nested(err) => Unwind::Nested(err.with_primary(span, "in this call")),
+            unwind => unwind,
+        })
+    }
+
+    /// 名前で関数を呼び出す
+    /// 引数と戻り値はプロトタイプの型に変換され、本体のない関数は組み込み関数として呼び出される
+    fn call_function(&mut self, name: &str, args: Vec<Value>, span: Span) -> Eval {
+        let fun = match self.functions.get(name) {
+            Some(fun) => fun.clone(),
+            None => {
+                return Err(error(runtime_error(
+                    span,
+                    format!("Unknown function '{}'.", name),
+                )))
+            }
+        };
+
+        let proto = &fun.prototype;
+        let args = args
+            .into_iter()
+            .zip(proto.arg_types.iter())
+            .map(|(arg, ty)| convert(arg, ty))
+            .collect::<Vec<Value>>();
+
+        let result = match fun.body {
+            Some(ref body) => {
+                let locals = proto.args.iter().cloned().zip(args).collect();
+
+                self.call_body(name, body, locals, span)?
+            }
+            None => self.call_builtin(name, &args, span)?,
+        };
+
+        Ok(convert(result, &proto.ret_type))
+    }
+
+    /// 関数値を呼び出す
+    fn call_value(&mut self, callable: &Callable, args: Vec<Value>, span: Span) -> Eval {
+        match *callable {
+            Callable::Named(ref proto) => self.call_function(&proto.name, args, span),
+            Callable::Lambda {
+                ref params,
+                ref body,
+                ref env,
+            } => {
+                let mut locals = env.clone();
+
+                for (&(ref name, ref ty), arg) in params.iter().zip(args) {
+                    let arg = match *ty {
+                        Some(ref ty) => convert(arg, ty),
+                        None => arg,
+                    };
+
+                    locals.push((name.clone(), arg));
+                }
+
+                self.call_body("lambda", body, locals, span)
+            }
+        }
+    }
+
+    /// 関数呼び出しの深さを数え、上限を超えた場合はエラーとする
+    fn enter(&mut self, span: Span) -> Result<(), Unwind> {
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(error(runtime_error(span, "Maximum call depth exceeded.")));
+        }
+
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    /// 外部宣言された関数を、対応する組み込み関数として呼び出す
+    /// REPLがJITに提供する関数と、Cライブラリの数学関数が利用できる
+    fn call_builtin(&mut self, name: &str, args: &[Value], span: Span) -> Eval {
+        let number = |i: usize| args.get(i).and_then(Value::as_f64).unwrap_or(0.0);
+        let string = |i: usize| match args.get(i) {
+            Some(&Value::Str(ref value)) => value.clone(),
+            _ => String::new(),
+        };
+
+        let value = match name {
+            "putchard" => {
+                let x = number(0);
+
+                self.write(span, format!("{}", x as u8 as char))?;
+
+                Value::F64(x)
+            }
+            "printd" => {
+                let x = number(0);
+
+                self.write(span, format!("{}\n", x))?;
+
+                Value::F64(x)
+            }
+            "prints" => {
+                self.write(span, string(0))?;
+
+                Value::F64(0.0)
+            }
+            "concat" => Value::Str(string(0) + &string(1)),
+            "tostr" => Value::Str(number(0).to_string()),
+            "strlen" => Value::I64(string(0).len() as i64),
+
+            "sin" => Value::F64(number(0).sin()),
+            "cos" => Value::F64(number(0).cos()),
+            "tan" => Value::F64(number(0).tan()),
+            "atan2" => Value::F64(number(0).atan2(number(1))),
+            "sqrt" => Value::F64(number(0).sqrt()),
+            "exp" => Value::F64(number(0).exp()),
+            "log" => Value::F64(number(0).ln()),
+            "pow" => Value::F64(number(0).powf(number(1))),
+            "fabs" => Value::F64(number(0).abs()),
+            "floor" => Value::F64(number(0).floor()),
+            "ceil" => Value::F64(number(0).ceil()),
+            "fmod" => Value::F64(number(0) % number(1)),
+
+            _ => {
+                return Err(error(
+                    runtime_error(
+                        span,
+                        format!(
+                            "External function '{}' is not available in the interpreter.",
+                            name
+                        ),
+                    )
+                    .with_note("only the REPL builtins and common math functions can be called"),
+                ))
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// 組み込み関数の出力を書き込む
+    fn write(&mut self, span: Span, text: String) -> Result<(), Unwind> {
+        self.out
+            .write_all(text.as_bytes())
+            .and_then(|()| self.out.flush())
+            .map_err(|err| {
+                error(runtime_error(
+                    span,
+                    format!("Could not write output: {}", err),
+                ))
+            })
+    }
+
+    /// 変数の値を取得
+    /// ローカル変数、グローバル変数、関数値の順に探す
+    fn lookup_variable(&self, name: &str, span: Span) -> Eval {
+        if let Some(&(_, ref value)) = self
+            .locals
+            .iter()
+            .rev()
+            .find(|&&(ref local, _)| local == name)
+        {
+            return Ok(value.clone());
+        }
+
+        if let Some(value) = self.globals.get(name) {
+            return Ok(value.clone());
+        }
+
+        match self.functions.get(name) {
+            Some(fun) => Ok(Value::Function(Rc::new(Callable::Named(
+                fun.prototype.clone(),
+            )))),
+            None => Err(error(
+                Diagnostic::error("Could not find a matching variable.")
+                    .with_code("E0300")
+                    .with_primary(span, "not found in this scope"),
+            )),
+        }
+    }
+
+    /// 変数に値を代入する
+    /// 数値は変数の現在の型に変換される
+    fn assign_variable(&mut self, name: &str, value: Value, span: Span) -> Eval {
+        let slot = match self
+            .locals
+            .iter_mut()
+            .rev()
+            .find(|&&mut (ref local, _)| local == name)
+        {
+            Some(&mut (_, ref mut slot)) => slot,
+            None => match self.globals.get_mut(name) {
+                Some(slot) => slot,
+                None => {
+                    return Err(error(
+                        Diagnostic::error("Undefined variable.")
+                            .with_code("E0300")
+                            .with_primary(span, "not found in this scope"),
+                    ))
+                }
+            },
+        };
+
+        *slot = conform(value, slot);
+
+        Ok(slot.clone())
+    }
+
+    /// 式を評価する
+    fn eval_expr(&mut self, expr: &Expr) -> Eval {
+        match expr.kind {
+            // JITと同じく、型検査で整数に合わせられたリテラルは整数の値となる
+            ExprKind::Number(nb) => Ok(convert(Value::F64(nb), &expr.ty)),
+
+            ExprKind::Str(ref value) => Ok(Value::Str(value.clone())),
+
+            ExprKind::Array(ref elements) => {
+                let mut values = Vec::with_capacity(elements.len());
+
+                for element in elements {
+                    values.push(self.eval_number(element)?);
+                }
+
+                Ok(Value::Array(Rc::new(RefCell::new(values))))
+            }
+
+            ExprKind::Index {
+                ref array,
+                ref index,
+            } => {
+                let (array, index) = self.eval_element(array, index)?;
+                let value = array.borrow()[index];
+
+                Ok(Value::F64(value))
+            }
+
+            ExprKind::Construct {
+                ref name,
+                ref fields,
+            } => self.eval_construct(name, fields, expr.span),
+
+            ExprKind::Field {
+                ref value,
+                ref field,
+                ..
+            } => {
+                let (fields, index) = self.eval_field(value, field)?;
+                let value = fields.borrow()[index].1.clone();
+
+                Ok(value)
+            }
+
+            ExprKind::Variable(ref name) => self.lookup_variable(name, expr.span),
+
+            ExprKind::Lambda {
+                ref params,
+                ref body,
+                ..
+            } => Ok(Value::Function(Rc::new(Callable::Lambda {
+                params: params.clone(),
+                body: (**body).clone(),
+                // JITと同じく、キャプチャした変数は作成時の値がコピーされる
+                env: self.locals.clone(),
+            }))),
+
+            ExprKind::VarIn {
+                ref variables,
+                ref body,
+            } => {
+                let depth = self.locals.len();
+
+                for &(ref name, ref ty, ref init) in variables {
+                    let value = match (init, ty) {
+                        (&Some(ref init), &Some(ref ty)) => convert(self.eval_expr(init)?, ty),
+                        (&Some(ref init), &None) => self.eval_expr(init)?,
+                        (&None, ty) => default_value(ty.as_ref().unwrap_or(&Type::F64)),
+                    };
+
+                    self.locals.push((name.clone(), value));
+                }
+
+                let result = self.eval_expr(body);
+
+                self.locals.truncate(depth);
+
+                result
+            }
+
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => match op {
+                '=' => self.eval_assign(left, right),
+                '+' | '-' | '*' | '/' | '<' | '>' => {
+                    let lhs = self.eval_expr(left)?;
+                    let rhs = self.eval_expr(right)?;
+
+                    binary(op, lhs, rhs, expr.span).map_err(error)
+                }
+                _ => {
+                    let lhs = self.eval_expr(left)?;
+                    let rhs = self.eval_expr(right)?;
+
+                    self.call_function(&format!("binary{}", op), vec![lhs, rhs], expr.span)
+                }
+            },
+
+            ExprKind::Call {
+                ref fn_name,
+                ref args,
+                ..
+            } => {
+                let mut values = Vec::with_capacity(args.len());
+
+                for arg in args {
+                    values.push(self.eval_expr(arg)?);
+                }
+
+                match fn_name.as_str() {
+                    "array" => {
+                        let len = values[0].as_f64().unwrap_or(0.0).max(0.0) as usize;
+
+                        return Ok(Value::Array(Rc::new(RefCell::new(vec![0.0; len]))));
+                    }
+                    "len" => {
+                        if let Value::Array(ref array) = values[0] {
+                            return Ok(Value::I64(array.borrow().len() as i64));
+                        }
+                    }
+                    _ => (),
+                }
+
+                // ローカル変数に格納された関数値の呼び出し
+                let callee = self
+                    .locals
+                    .iter()
+                    .rev()
+                    .find(|&&(ref local, _)| local == fn_name)
+                    .map(|&(_, ref value)| value.clone());
+
+                match callee {
+                    Some(Value::Function(callable)) => {
+                        self.call_value(&callable, values, expr.span)
+                    }
+                    Some(_) => Err(error(
+                        Diagnostic::error(format!("'{}' is not a function.", fn_name))
+                            .with_code("E0303")
+                            .with_primary(expr.span, "called here"),
+                    )),
+                    None => self.call_function(fn_name, values, expr.span),
+                }
+            }
+
+            // 型検査で挿入される変換と、型変換の組み込み関数
+            ExprKind::Cast(ref value) => {
+                let value = self.eval_expr(value)?;
+
+                Ok(convert(value, &expr.ty))
+            }
+
+            ExprKind::Conditional {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                if self.eval_expr(cond)?.is_truthy() {
+                    self.eval_expr(consequence)
+                } else {
+                    self.eval_expr(alternative)
+                }
+            }
+
+            ExprKind::For {
+                ref var_name,
+                ref var_type,
+                ref start,
+                ref end,
+                ref step,
+                ref body,
+            } => {
+                let start = self.eval_expr(start)?;
+                let start = match *var_type {
+                    Some(ref ty) => convert(start, ty),
+                    None => start,
+                };
+
+                let depth = self.locals.len();
+
+                self.locals.push((var_name.clone(), start));
+
+                let result = self.eval_for(end, step.as_ref().map(|step| &**step), body);
+
+                self.locals.truncate(depth);
+
+                result
+            }
+
+            ExprKind::While { ref cond, ref body } => loop {
+                if !self.eval_expr(cond)?.is_truthy() {
+                    return Ok(Value::F64(0.0));
+                }
+
+                match self.eval_expr(body) {
+                    Ok(_) | Err(Unwind::Continue) => (),
+                    Err(Unwind::Break(value)) => return Ok(value),
+                    Err(unwind) => return Err(unwind),
+                }
+            },
+
+            ExprKind::Block(ref exprs) => {
+                let mut value = Value::F64(0.0);
+
+                for expr in exprs {
+                    value = self.eval_expr(expr)?;
+                }
+
+                Ok(value)
+            }
+
+            ExprKind::Break(ref value) => {
+                let value = match *value {
+                    Some(ref value) => self.eval_number(value)?,
+                    None => 0.0,
+                };
+
+                Err(Unwind::Break(Value::F64(value)))
+            }
+
+            ExprKind::Continue => Err(Unwind::Continue),
+
+            ExprKind::Return(ref value) => {
+                let value = self.eval_expr(value)?;
+
+                Err(Unwind::Return(value))
+            }
+        }
+    }
+
+    /// 数値として使われる式を評価する
+    fn eval_number(&mut self, expr: &Expr) -> Result<f64, Unwind> {
+        let value = self.eval_expr(expr)?;
+
+        value.as_f64().ok_or_else(|| {
+            error(runtime_error(
+                expr.span,
+                format!("Expected a number, found {}.", value),
+            ))
+        })
+    }
+
+    /// forループの終了条件、ステップ、本体を評価する
+    /// JITと同じく、本体の後に終了条件を評価してからループ変数を更新する
+    fn eval_for(&mut self, end: &Expr, step: Option<&Expr>, body: &Expr) -> Eval {
+        let index = self.locals.len() - 1;
+
+        loop {
+            match self.eval_expr(body) {
+                Ok(_) | Err(Unwind::Continue) => (),
+                Err(Unwind::Break(value)) => return Ok(value),
+                Err(unwind) => return Err(unwind),
+            }
+
+            let current = self.locals[index].1.clone();
+            let step = match step {
+                Some(step) => self.eval_expr(step)?,
+                None => conform(Value::F64(1.0), &current),
+            };
+
+            let end_value = self.eval_expr(end)?;
+            let current = self.locals[index].1.clone();
+
+            self.locals[index].1 = binary('+', current, step, end.span).map_err(error)?;
+
+            if !end_value.is_truthy() {
+                return Ok(Value::F64(0.0));
+            }
+        }
+    }
+
+    /// 代入を評価する
+    fn eval_assign(&mut self, target: &Expr, value: &Expr) -> Eval {
+        match target.kind {
+            ExprKind::Variable(ref name) => {
+                let value = self.eval_expr(value)?;
+
+                self.assign_variable(name, value, target.span)
+            }
+
+            ExprKind::Index {
+                ref array,
+                ref index,
+            } => {
+                let (array, index) = self.eval_element(array, index)?;
+                let value = self.eval_number(value)?;
+
+                array.borrow_mut()[index] = value;
+
+                Ok(Value::F64(value))
+            }
+
+            ExprKind::Field {
+                value: ref target,
+                ref field,
+                ..
+            } => {
+                let (fields, index) = self.eval_field(target, field)?;
+                let value = self.eval_expr(value)?;
+                let value = conform(value, &fields.borrow()[index].1);
+
+                fields.borrow_mut()[index].1 = value.clone();
+
+                Ok(value)
+            }
+
+            _ => Err(error(
+                Diagnostic::error(
+                    "Expected variable, array element or field as left-hand operator of assignement.",
+                )
+                .with_code("E0301")
+                .with_primary(target.span, "cannot assign to this expression"),
+            )),
+        }
+    }
+
+    /// 配列と添字を評価し、範囲内の添字を返す
+    fn eval_element(
+        &mut self,
+        array: &Expr,
+        index: &Expr,
+    ) -> Result<(Rc<RefCell<Vec<f64>>>, usize), Unwind> {
+        let array = match self.eval_expr(array)? {
+            Value::Array(array) => array,
+            value => {
+                return Err(error(runtime_error(
+                    array.span,
+                    format!("Expected an array, found {}.", value),
+                )))
+            }
+        };
+
+        let position = self.eval_number(index)?;
+        let len = array.borrow().len();
+
+        if position < 0.0 || position as usize >= len {
+            return Err(error(runtime_error(
+                index.span,
+                format!(
+                    "Index {} is out of bounds for an array of length {}.",
+                    position, len
+                ),
+            )));
+        }
+
+        Ok((array, position as usize))
+    }
+
+    /// 構造体を評価し、フィールドの位置を返す
+    fn eval_field(
+        &mut self,
+        value: &Expr,
+        field: &str,
+    ) -> Result<(Rc<RefCell<Vec<(String, Value)>>>, usize), Unwind> {
+        let (name, fields) = match self.eval_expr(value)? {
+            Value::Struct(name, fields) => (name, fields),
+            other => {
+                return Err(error(runtime_error(
+                    value.span,
+                    format!("Expected a struct, found {}.", other),
+                )))
+            }
+        };
+
+        let index = fields
+            .borrow()
+            .iter()
+            .position(|&(ref name, _)| name == field);
+
+        match index {
+            Some(index) => Ok((fields, index)),
+            None => Err(error(
+                Diagnostic::error(format!("No field '{}' on struct '{}'.", field, name))
+                    .with_code("E0314")
+                    .with_primary(value.span, "unknown field"),
+            )),
+        }
+    }
+
+    /// 構造体の作成式を評価する
+    /// JITと同じく、フィールドは宣言された順に評価される
+    fn eval_construct(&mut self, name: &str, fields: &[(String, Expr)], span: Span) -> Eval {
+        let def = match self.structs.get(name) {
+            Some(def) => def.clone(),
+            None => {
+                return Err(error(
+                    Diagnostic::error(format!("Unknown type '{}'.", name))
+                        .with_code("E0308")
+                        .with_primary(span, "no struct with this name"),
+                ))
+            }
+        };
+
+        if let Some(&(ref field, ref value)) = fields
+            .iter()
+            .find(|&&(ref field, _)| def.field_index(field).is_none())
+        {
+            return Err(error(
+                Diagnostic::error(format!("No field '{}' on struct '{}'.", field, name))
+                    .with_code("E0314")
+                    .with_primary(value.span, "unknown field"),
+            ));
+        }
+
+        let mut values = Vec::with_capacity(def.fields.len());
+
+        for &(ref field, ref ty) in &def.fields {
+            let value = match fields.iter().find(|&&(ref name, _)| name == field) {
+                Some(&(_, ref value)) => convert(self.eval_expr(value)?, ty),
+                None => {
+                    return Err(error(
+                        Diagnostic::error(format!(
+                            "Missing field '{}' in struct '{}'.",
+                            field, name
+                        ))
+                        .with_code("E0313")
+                        .with_primary(span, "in this struct expression"),
+                    ))
+                }
+            };
+
+            values.push((field.clone(), value));
+        }
+
+        Ok(Value::Struct(
+            name.to_string(),
+            Rc::new(RefCell::new(values)),
+        ))
+    }
+}
+
+/// 組み込みの二項演算子を評価する
+/// 両辺は型検査で同じ数値型に揃えられているため、型の異なる値の組み合わせはエラーとなる
+fn binary(op: char, lhs: Value, rhs: Value, span: Span) -> Result<Value, Diagnostic> {
+    let division_by_zero = || runtime_error(span, "Division by zero.");
+
+    let value = match (lhs, rhs) {
+        // 比較はJITと同じく、NaNを含む場合に真となる
+        (Value::F64(lhs), Value::F64(rhs)) => match op {
+            '+' => Value::F64(lhs + rhs),
+            '-' => Value::F64(lhs - rhs),
+            '*' => Value::F64(lhs * rhs),
+            '/' => Value::F64(lhs / rhs),
+            '<' => Value::Bool(!(lhs >= rhs)),
+            _ => Value::Bool(!(rhs >= lhs)),
+        },
+        (Value::I32(lhs), Value::I32(rhs)) => match op {
+            '+' => Value::I32(lhs.wrapping_add(rhs)),
+            '-' => Value::I32(lhs.wrapping_sub(rhs)),
+            '*' => Value::I32(lhs.wrapping_mul(rhs)),
+            '/' if rhs == 0 => return Err(division_by_zero()),
+            '/' => Value::I32(lhs.wrapping_div(rhs)),
+            '<' => Value::Bool(lhs < rhs),
+            _ => Value::Bool(lhs > rhs),
+        },
+        (Value::I64(lhs), Value::I64(rhs)) => match op {
+            '+' => Value::I64(lhs.wrapping_add(rhs)),
+            '-' => Value::I64(lhs.wrapping_sub(rhs)),
+            '*' => Value::I64(lhs.wrapping_mul(rhs)),
+            '/' if rhs == 0 => return Err(division_by_zero()),
+            '/' => Value::I64(lhs.wrapping_div(rhs)),
+            '<' => Value::Bool(lhs < rhs),
+            _ => Value::Bool(lhs > rhs),
+        },
+        (lhs, rhs) => {
+            return Err(runtime_error(
+                span,
+                format!("Cannot apply '{}' to {} and {}.", op, lhs, rhs),
+            ))
+        }
+    };
+
+    Ok(value)
+}
+
+/// 数値を指定された型に変換する
+/// 数値でない値と、数値でない型への変換はそのまま返す
+fn convert(value: Value, ty: &Type) -> Value {
+    let number = match value.as_f64() {
+        Some(number) => number,
+        None => return value,
+    };
+
+    match (value, ty) {
+        (Value::I64(value), &Type::I32) => Value::I32(value as i32),
+        (Value::I32(value), &Type::I64) => Value::I64(i64::from(value)),
+        (Value::I64(value), &Type::I64) => Value::I64(value),
+        (Value::Bool(value), &Type::Bool) => Value::Bool(value),
+        (_, &Type::F64) => Value::F64(number),
+        (_, &Type::I32) => Value::I32(number as i32),
+        (_, &Type::I64) => Value::I64(number as i64),
+        (_, &Type::Bool) => Value::Bool(number != 0.0),
+        (value, _) => value,
+    }
+}
+
+/// 値を、既存の値と同じ型の数値に変換する
+fn conform(value: Value, like: &Value) -> Value {
+    match *like {
+        Value::Bool(_) => convert(value, &Type::Bool),
+        Value::F64(_) => convert(value, &Type::F64),
+        Value::I32(_) => convert(value, &Type::I32),
+        Value::I64(_) => convert(value, &Type::I64),
+        _ => value,
+    }
+}
+
+/// 初期化式のない変数の値
+/// 構造体と関数値には既定値がないため、型検査と同じく使われることはない
+fn default_value(ty: &Type) -> Value {
+    match *ty {
+        Type::Bool => Value::Bool(false),
+        Type::I32 => Value::I32(0),
+        Type::I64 => Value::I64(0),
+        Type::Str => Value::Str(String::new()),
+        Type::Array => Value::Array(Rc::new(RefCell::new(Vec::new()))),
+        Type::F64 | Type::Struct(_) | Type::Fn(..) => Value::F64(0.0),
+    }
+}
+
+/// 評価中に発生したエラーの診断を作成
+fn runtime_error<S: Into<String>>(span: Span, msg: S) -> Diagnostic {
+    Diagnostic::error(msg)
+        .with_code("E0400")
+        .with_primary(span, "while evaluating this expression")
+}
+
+/// 関数の外まで中断された評価のエラーを返す
+/// 'return'、'break'と'continue'はeval_bodyで受け止められている
+fn into_diagnostic(unwind: Unwind) -> Diagnostic {
+    match unwind {
+        Unwind::Error(err) | Unwind::Nested(err) => err,
+        Unwind::Break(_) | Unwind::Continue | Unwind::Return(_) => {
+            unreachable!("control flow does not leave a function body")
+        }
+    }
+}
+
+/// 診断を評価の中断として返す
+fn error(err: Diagnostic) -> Unwind {
+    Unwind::Error(err)
+}
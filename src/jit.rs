@@ -0,0 +1,430 @@
+use crate::compiler::{Compiler, GLOBAL_INIT_PREFIX};
+use crate::diagnostic::Diagnostic;
+use crate::driver;
+use crate::lexer::Span;
+use crate::operators::OperatorTable;
+use crate::parser::{Function, GlobalVar, Item, Prototype, StructDef, Type};
+use crate::resolve::{Resolver, Symbols};
+use crate::typeck::{self, StructTable};
+
+use inkwell::builder::Builder;
+use inkwell::context::Context;
+use inkwell::execution_engine::{ExecutionEngine, FunctionLookupError};
+use inkwell::module::Module;
+use inkwell::OptimizationLevel;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::slice;
+
+/// トップレベルの式の評価結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    F64(f64),
+    I32(i32),
+    I64(i64),
+    Str(String),
+    Array(Vec<f64>),
+    /// 構造体の名前とフィールドの値
+    /// 構造体のフィールドに含まれる構造体は、フィールドを持たない値として表される
+    Struct(String, Vec<(String, Value)>),
+    /// 関数値の型
+    Function(Type),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::F64(value) => write!(f, "{}", value),
+            Value::I32(value) => write!(f, "{}", value),
+            Value::I64(value) => write!(f, "{}", value),
+            Value::Str(ref value) => write!(f, "{:?}", value),
+            Value::Array(ref values) => write!(f, "{:?}", values),
+            Value::Struct(ref name, ref fields) => {
+                write!(f, "{} {{", name)?;
+
+                if fields.is_empty() {
+                    return write!(f, " .. }}");
+                }
+
+                for (i, &(ref field, ref value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+
+                    write!(f, "{} {}: {}", sep, field, value)?;
+                }
+
+                write!(f, " }}")
+            }
+            Value::Function(ref ty) => write!(f, "<{}>", ty),
+        }
+    }
+}
+
+/// REPLの入力をまたいでコンパイル済みの関数を保持するJITセッション
+///
+/// 入力ごとに新しいモジュールを作成し、単一の実行エンジンに追加していく。
+/// 以前に定義された関数は新しいモジュールに宣言のみが追加され、
+/// 実行エンジンのシンボル解決によって既存のコードが呼び出されるため、再コンパイルは行われない。
+pub struct Session<'ctx> {
+    context: &'ctx Context,
+    builder: Builder<'ctx>,
+    engine: ExecutionEngine<'ctx>,
+    opt_level: u32,
+
+    /// 実行エンジンの作成に使用した空のモジュール
+    /// 実行エンジンが所有しているため、セッションと同じ期間だけ保持する
+    /// 構造体の型はコンテキストに登録されるため、このモジュールを通して宣言する
+    root: Module<'ctx>,
+
+    /// 定義、または宣言済みの全ての関数のプロトタイプ
+    prototypes: HashMap<String, Prototype>,
+    /// 定義済みの全てのグローバル変数の名前
+    globals: HashSet<String>,
+    /// 宣言済みの全ての構造体
+    structs: StructTable,
+    /// 関数名またはグローバル変数名と、それを定義しているモジュール
+    modules: HashMap<String, Module<'ctx>>,
+
+    /// 入力をまたいで保持される演算子表
+    pub operators: OperatorTable,
+
+    /// コンパイルされたIRを標準エラー出力に表示するかどうか
+    pub display_compiler_output: bool,
+    /// 実行時検査を挿入してコンパイルするかどうか
+    pub checks: bool,
+}
+
+impl<'ctx> Session<'ctx> {
+    /// 新しいセッションと、その実行エンジンを作成
+    pub fn new(context: &'ctx Context, opt_level: u32) -> Result<Session<'ctx>, String> {
+        // 実行エンジンの作成には所有するモジュールが必要なため、空のモジュールを用意する
+        let root = context.create_module("session");
+        let engine = root
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|err| err.to_string())?;
+
+        Ok(Session {
+            context: context,
+            builder: context.create_builder(),
+            engine: engine,
+            opt_level: opt_level,
+            root: root,
+            prototypes: HashMap::new(),
+            globals: HashSet::new(),
+            structs: StructTable::new(),
+            modules: HashMap::new(),
+            operators: OperatorTable::new(),
+            display_compiler_output: false,
+            checks: false,
+        })
+    }
+
+    /// 関数の定義、外部宣言、グローバル変数、またはトップレベルの式を評価する
+    /// トップレベルの式の場合は、その計算結果を返す
+    /// 名前解決で問題が見つかった場合は、コンパイルせずに全ての診断を返す
+    pub fn eval(&mut self, item: &Item) -> Result<Option<Value>, Vec<Diagnostic>> {
+        let problems = Resolver::resolve_item(&self.symbols(item), item);
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let result = match *item {
+            Item::Function(ref fun) => self.eval_function(fun),
+            Item::Global(ref global) => self.eval_global(global).map(|()| None),
+            Item::Struct(ref def) => self.eval_struct(def).map(|()| None),
+        };
+
+        result.map_err(|err| vec![err])
+    }
+
+    /// セッションで定義済みの名前と、評価する要素が定義する名前の表を作成
+    fn symbols(&self, item: &Item) -> Symbols {
+        let mut symbols = Symbols::new();
+
+        for proto in self.prototypes.values() {
+            symbols.add_function(proto);
+        }
+
+        for global in &self.globals {
+            symbols.add_global(global);
+        }
+
+        for def in self.structs.iter() {
+            symbols.add_struct(&def.name);
+        }
+
+        symbols.add_item(item);
+
+        symbols
+    }
+
+    /// 新しいモジュールを作成し、以前に定義された関数とグローバル変数を宣言する
+    /// 'name'はこのモジュールで定義されるため宣言しない
+    fn create_module(&self, name: &str) -> Module<'ctx> {
+        let module = self.context.create_module(name);
+
+        for proto in self.prototypes.values() {
+            if proto.name != name {
+                Compiler::declare(self.context, &module, proto);
+            }
+        }
+
+        for global in &self.globals {
+            if global != name {
+                Compiler::declare_global(self.context, &module, global);
+            }
+        }
+
+        module
+    }
+
+    /// 関数の定義、外部宣言、またはトップレベルの式を評価する
+    fn eval_function(&mut self, fun: &Function) -> Result<Option<Value>, Diagnostic> {
+        let name = fun.prototype.name.clone();
+
+        // 外部宣言はプロトタイプを記録するのみで、以降のモジュールで宣言される
+        if fun.body.is_none() {
+            self.prototypes.insert(name, fun.prototype.clone());
+
+            return Ok(None);
+        }
+
+        let module = self.create_module(&name);
+        let fpm = driver::create_fpm(&module, self.opt_level);
+        let function = Compiler::compile(
+            self.context,
+            &self.builder,
+            &fpm,
+            &module,
+            &self.structs,
+            fun,
+            self.checks,
+        )?;
+
+        if self.display_compiler_output {
+            // Not printing a new line since LLVM automatically
+            // prefixes the generated string with one
+            eprint!("-> Expression compiled to IR:");
+            function.print_to_stderr();
+        }
+
+        if fun.is_anon {
+            // 匿名関数の戻り値の型は型検査で推論されているため、コンパイル結果から取得する
+            let ret_type = typeck::signature(function).map_or(Type::F64, |sig| sig.ret);
+
+            return self.run_anonymous(module, fun, ret_type).map(Some);
+        }
+
+        // 再定義の場合は古いモジュールを取り除き、以降の入力が新しい定義を参照するようにする
+        // 古い定義を既に呼び出しているコードは、そのまま古い定義を使い続ける
+        if let Some(old) = self.modules.remove(&name) {
+            let _ = self.engine.remove_module(&old);
+        }
+
+        self.engine.add_module(&module).map_err(|()| {
+            engine_error(
+                fun.span,
+                "Could not add the module to the execution engine.",
+            )
+        })?;
+
+        self.prototypes.insert(name.clone(), fun.prototype.clone());
+        self.modules.insert(name, module);
+
+        Ok(None)
+    }
+
+    /// グローバル変数を定義し、初期化式を実行する
+    /// 再定義の場合は新しい変数で置き換え、以降の入力は新しい変数を参照する
+    fn eval_global(&mut self, global: &GlobalVar) -> Result<(), Diagnostic> {
+        let name = global.name.clone();
+        let module = self.create_module(&name);
+        let fpm = driver::create_fpm(&module, self.opt_level);
+        let init = Compiler::compile_global(
+            self.context,
+            &self.builder,
+            &fpm,
+            &module,
+            &self.structs,
+            global,
+            self.checks,
+        )?;
+
+        if self.display_compiler_output {
+            eprint!("-> Global compiled to IR:");
+            module.print_to_stderr();
+        }
+
+        if let Some(old) = self.modules.remove(&name) {
+            let _ = self.engine.remove_module(&old);
+        }
+
+        self.engine.add_module(&module).map_err(|()| {
+            engine_error(
+                global.span,
+                "Could not add the module to the execution engine.",
+            )
+        })?;
+
+        self.globals.insert(name.clone());
+        self.modules.insert(name.clone(), module);
+
+        // 定数に畳み込めなかった初期化式は、ここで一度だけ実行する
+        if init.is_some() {
+            unsafe {
+                self.engine
+                    .get_function::<unsafe extern "C" fn() -> f64>(&format!(
+                        "{}{}",
+                        GLOBAL_INIT_PREFIX, name
+                    ))
+                    .map(|init_fn| {
+                        init_fn.call();
+                    })
+                    .map_err(|err| {
+                        engine_error(global.span, &format!("Error during execution: {:?}", err))
+                    })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 構造体を宣言する
+    /// 構造体の再宣言はできない
+    fn eval_struct(&mut self, def: &StructDef) -> Result<(), Diagnostic> {
+        self.structs.add(def)?;
+
+        Compiler::declare_structs(self.context, &self.root, &[def]);
+
+        Ok(())
+    }
+
+    /// 定義済みの関数をセッションから取り除く
+    /// 取り除かれた関数があった場合はtrueを返す
+    pub fn remove_function(&mut self, name: &str) -> bool {
+        if let Some(module) = self.modules.remove(name) {
+            let _ = self.engine.remove_module(&module);
+        }
+
+        self.prototypes.remove(name).is_some()
+    }
+
+    /// ユーザー定義演算子と、それを実装する関数をセッションから取り除く
+    /// 取り除かれた演算子があった場合はtrueを返す
+    pub fn remove_operator(&mut self, op: char) -> bool {
+        let removed = self.operators.remove(op);
+
+        self.remove_function(&format!("binary{}", op));
+        self.remove_function(&format!("unary{}", op));
+
+        removed
+    }
+
+    /// 匿名関数のモジュールを一時的に実行エンジンに追加して実行する
+    fn run_anonymous(
+        &mut self,
+        module: Module<'ctx>,
+        fun: &Function,
+        ret_type: Type,
+    ) -> Result<Value, Diagnostic> {
+        self.engine.add_module(&module).map_err(|()| {
+            engine_error(
+                fun.span,
+                "Could not add the module to the execution engine.",
+            )
+        })?;
+
+        let name = fun.prototype.name.as_str();
+        let result = unsafe {
+            match ret_type {
+                Type::Bool => self.call::<bool>(name).map(Value::Bool),
+                Type::F64 => self.call::<f64>(name).map(Value::F64),
+                Type::I32 => self.call::<i32>(name).map(Value::I32),
+                Type::I64 => self.call::<i64>(name).map(Value::I64),
+                Type::Str => self.call::<*const c_char>(name).map(|ptr| read_str(ptr)),
+                Type::Array => self.call::<*const i64>(name).map(|ptr| read_array(ptr)),
+                Type::Struct(ref struct_name) => self
+                    .call::<*const u8>(name)
+                    .map(|ptr| self.read_struct(ptr, struct_name)),
+                Type::Fn(..) => self
+                    .call::<*const u8>(name)
+                    .map(|_| Value::Function(ret_type.clone())),
+            }
+        };
+
+        let _ = self.engine.remove_module(&module);
+
+        result.map_err(|err| engine_error(fun.span, &format!("Error during execution: {:?}", err)))
+    }
+
+    /// 構造体へのポインタから、フィールドの値を読み出す
+    /// フィールドはLLVMのデフォルトのデータレイアウトに従い、それぞれのサイズに揃えて配置される
+    unsafe fn read_struct(&self, ptr: *const u8, name: &str) -> Value {
+        let def = match self.structs.get(name) {
+            Some(def) => def,
+            None => return Value::Struct(name.to_string(), vec![]),
+        };
+
+        let mut fields = Vec::with_capacity(def.fields.len());
+        let mut offset = 0;
+
+        for &(ref field, ref ty) in &def.fields {
+            let size = match *ty {
+                Type::Bool => 1,
+                Type::I32 => 4,
+                _ => 8,
+            };
+
+            offset = (offset + size - 1) / size * size;
+
+            let field_ptr = ptr.add(offset);
+            let value = match *ty {
+                Type::Bool => Value::Bool(*field_ptr != 0),
+                Type::F64 => Value::F64(*(field_ptr as *const f64)),
+                Type::I32 => Value::I32(*(field_ptr as *const i32)),
+                Type::I64 => Value::I64(*(field_ptr as *const i64)),
+                Type::Str => read_str(*(field_ptr as *const *const c_char)),
+                Type::Array => read_array(*(field_ptr as *const *const i64)),
+                Type::Struct(ref name) => Value::Struct(name.clone(), vec![]),
+                Type::Fn(..) => Value::Function(ty.clone()),
+            };
+
+            fields.push((field.clone(), value));
+            offset += size;
+        }
+
+        Value::Struct(name.to_string(), fields)
+    }
+
+    /// 引数のないコンパイル済みの関数を呼び出す
+    unsafe fn call<T>(&self, name: &str) -> Result<T, FunctionLookupError> {
+        self.engine
+            .get_function::<unsafe extern "C" fn() -> T>(name)
+            .map(|compiled_fn| compiled_fn.call())
+    }
+}
+
+/// 文字列へのポインタから値を読み出す
+unsafe fn read_str(ptr: *const c_char) -> Value {
+    Value::Str(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+}
+
+/// 配列へのポインタから値を読み出す
+/// 配列は長さの後に要素が続く
+unsafe fn read_array(ptr: *const i64) -> Value {
+    let data = ptr.offset(1) as *const f64;
+
+    Value::Array(slice::from_raw_parts(data, *ptr as usize).to_vec())
+}
+
+/// 実行エンジンで発生したエラーの診断を作成
+fn engine_error(span: Span, msg: &str) -> Diagnostic {
+    Diagnostic::error(msg)
+        .with_code("E0305")
+        .with_primary(span, "while evaluating this input")
+}
@@ -1,3 +1,4 @@
+use crate::diagnostic::Diagnostic;
 use std::iter::Peekable;
 use std::ops::DerefMut;
 use std::str::Chars;
@@ -7,54 +8,99 @@ use Token::*;
 #[derive(Debug, Clone)]
 pub enum Token {
     Binary,
+    Break,
     Comma,
     Comment,
+    Continue,
     Def,
+    Do,
+    Dot,
     Else,
     EOF,
     Extern,
     For,
+    Global,
     Ident(String),
     If,
     In,
+    LBrace,
+    LBracket,
     LParen,
+    /// 無名関数の開始を示す'\'
+    Lambda,
     Number(f64),
     Op(char),
+    RBrace,
+    RBracket,
+    Return,
     RParen,
+    Semicolon,
+    Str(String),
+    Struct,
     Then,
     Unary,
     Var,
+    While,
 }
 
 /// Lexerで発生したエラーを定義
 #[derive(Debug)]
 pub struct LexError {
     pub error: &'static str,
-    pub index: usize,
+    pub span: Span,
 }
 
 /// Lexerで発生したエラーを生成
 impl LexError {
-    #[allow(dead_code)]
-    pub fn new(msg: &'static str) -> LexError {
+    pub fn new(msg: &'static str, span: Span) -> LexError {
         LexError {
             error: msg,
-            index: 0,
+            span: span,
         }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn with_index(msg: &'static str, index: usize) -> LexError {
-        LexError {
-            error: msg,
-            index: index,
+/// LexErrorを他のエラーと同じ形式の診断に変換
+impl From<LexError> for Diagnostic {
+    fn from(err: LexError) -> Diagnostic {
+        Diagnostic::error(err.error)
+            .with_code("E0100")
+            .with_primary(err.span, "invalid token")
+    }
+}
+
+/// ソースコード上の位置を定義
+/// startとendはバイトオフセット、lineとcolumnは開始位置の行と列(1始まり)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// 自身の開始位置から'other'の終了位置までを覆うSpanを返す
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            column: self.column,
         }
     }
 }
 
+/// 位置情報付きのトークン
+#[derive(Debug, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
 /// 字句解析結果を定義
 /// 成功した場合はトークン、失敗した場合はLexErrorとなります
-pub type LexResult = Result<Token, LexError>;
+pub type LexResult = Result<SpannedToken, LexError>;
 
 /// Stringの入力を変換するレクサーの定義
 /// Peekableはpeek()メソッドを利用することにより、中身を確認することができる
@@ -63,6 +109,8 @@ pub struct Lexer<'a> {
     input: &'a str,
     chars: Box<Peekable<Chars<'a>>>,
     pos: usize,
+    line: usize,
+    column: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -72,146 +120,490 @@ impl<'a> Lexer<'a> {
             input: input,
             chars: Box::new(input.chars().peekable()),
             pos: 0,
+            line: 1,
+            column: 1,
         }
     }
 
-    /// ソースコードから次のトークンを実行して返す
-    pub fn lex(&mut self) -> LexResult {
-        let chars = self.chars.deref_mut();
-        let src = self.input;
+    /// 次の文字を消費し、位置情報を進める
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+
+        self.pos += ch.len_utf8();
+
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(ch)
+    }
+
+    /// 次の文字を消費せずに返す
+    fn peek(&mut self) -> Option<char> {
+        self.chars.deref_mut().peek().cloned()
+    }
+
+    /// 開始位置からの範囲を表すSpanを作成
+    fn span_from(&self, start: usize, line: usize, column: usize) -> Span {
+        Span {
+            start: start,
+            end: self.pos,
+            line: line,
+            column: column,
+        }
+    }
 
-        let mut pos = self.pos;
+    /// 開始の'"'に続く文字列リテラルを解析し、エスケープシーケンスを展開する
+    fn lex_string(&mut self, start: usize, line: usize, column: usize) -> Result<Token, LexError> {
+        let mut value = String::new();
+        // 不正なエスケープシーケンスがあっても、リテラルの終わりまでは読み進める
+        let mut invalid_escape = None;
 
-        // 空白スキップとEOF判断
         loop {
-            // Note:
-            // 次の行は独自のスコープ内となっている。
-            // charsの借用期間を制限して許可するために
-            // ループ内でchar.next()によって再度借用される。
-            {
-                let ch = chars.peek();
-
-                // EOFチェック
-                if ch.is_none() {
-                    self.pos = pos;
-
-                    return Ok(EOF);
-                }
+            let (escape_start, escape_line, escape_column) = (self.pos, self.line, self.column);
+
+            match self.bump() {
+                Some('"') => break,
+
+                Some('\\') => match self.bump() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('\\') => value.push('\\'),
+                    Some('"') => value.push('"'),
+                    Some('\'') => value.push('\''),
+                    Some(_) => {
+                        invalid_escape = invalid_escape.or_else(|| {
+                            Some(self.span_from(escape_start, escape_line, escape_column))
+                        })
+                    }
+                    None => return Err(self.unterminated_string(start, line, column)),
+                },
+
+                // 文字列リテラルは改行をまたがない
+                Some('\n') | None => return Err(self.unterminated_string(start, line, column)),
+
+                Some(ch) => value.push(ch),
+            }
+        }
+
+        match invalid_escape {
+            Some(span) => Err(LexError::new(
+                "Unknown escape sequence in string literal.",
+                span,
+            )),
+            None => Ok(Str(value)),
+        }
+    }
+
+    /// 2文字先を先読みする
+    fn peek_second(&self) -> Option<char> {
+        self.input[self.pos..].chars().nth(1)
+    }
+
+    /// 'first'から始まる数値リテラルを解析する
+    /// 小数と指数を持つ10進数と、'0x'、'0b'、'0o'で始まる整数を受け付け、数字の間には'_'を置ける
+    fn lex_number(
+        &mut self,
+        first: char,
+        start: usize,
+        line: usize,
+        column: usize,
+    ) -> Result<Token, LexError> {
+        let radix = match (first, self.peek()) {
+            ('0', Some('x')) | ('0', Some('X')) => 16,
+            ('0', Some('b')) | ('0', Some('B')) => 2,
+            ('0', Some('o')) | ('0', Some('O')) => 8,
+            _ => 10,
+        };
+
+        let result = if radix == 10 {
+            self.lex_decimal(first)
+        } else {
+            self.bump();
+            self.lex_integer(radix)
+        };
+
+        // 数値の直後に識別子の文字や'.'が続く場合は、不正なリテラルとみなす
+        let result = match self.peek() {
+            Some(ch) if ch == '_' || ch == '.' || ch.is_alphanumeric() => {
+                result.and(Err("Invalid character in number literal."))
+            }
+            _ => result,
+        };
 
-                // 真上でnoneチェックを行っているため、unwrapを使っても安全性が保たれている
-                if !ch.unwrap().is_whitespace() {
+        result.map(Number).map_err(|msg| {
+            // 次のトークンが壊れないように、リテラルの残りを読み飛ばす
+            while let Some(ch) = self.peek() {
+                if ch != '_' && ch != '.' && !ch.is_alphanumeric() {
                     break;
                 }
+
+                self.bump();
+            }
+
+            LexError::new(msg, self.span_from(start, line, column))
+        })
+    }
+
+    /// 10進数の小数部と指数部を解析する
+    fn lex_decimal(&mut self, first: char) -> Result<f64, &'static str> {
+        let mut text = String::new();
+
+        if first == '.' {
+            text.push_str("0.");
+            text.push_str(&self.digits(10, false)?);
+        } else {
+            text.push(first);
+            text.push_str(&self.digits(10, true)?);
+
+            // '1.'のように小数部を省略することもできる
+            if self.peek() == Some('.') {
+                self.bump();
+                text.push('.');
+                text.push_str(&self.digits(10, false)?);
+            }
+        }
+
+        if let Some(e) = self.peek().filter(|&ch| ch == 'e' || ch == 'E') {
+            self.bump();
+            text.push(e);
+
+            if let Some(sign) = self.peek().filter(|&ch| ch == '+' || ch == '-') {
+                self.bump();
+                text.push(sign);
+            }
+
+            let exponent = self.digits(10, false)?;
+
+            if exponent.is_empty() {
+                return Err("Missing digits in number exponent.");
             }
 
-            chars.next();
-            pos += 1;
+            text.push_str(&exponent);
         }
 
-        let start = pos;
-        let next = chars.next();
+        text.parse().map_err(|_| "Invalid number literal.")
+    }
+
+    /// 基数の接頭辞に続く整数を解析する
+    fn lex_integer(&mut self, radix: u32) -> Result<f64, &'static str> {
+        let digits = self.digits(radix, false)?;
 
-        if next.is_none() {
-            return Ok(EOF);
+        if digits.is_empty() {
+            return Err("Missing digits after number base prefix.");
         }
 
-        pos += 1;
+        match u64::from_str_radix(&digits, radix) {
+            Ok(value) => Ok(value as f64),
+            Err(_) => Err("Integer literal is too large."),
+        }
+    }
+
+    /// 'radix'進数の数字を読み進め、区切りの'_'を除いて返す
+    /// 'after_digit'は、直前の文字が既に数字であるかを示す
+    fn digits(&mut self, radix: u32, after_digit: bool) -> Result<String, &'static str> {
+        let mut digits = String::new();
+        let mut after_digit = after_digit;
+
+        while let Some(ch) = self.peek() {
+            if ch == '_' {
+                // '_'は数字の間にのみ置ける
+                let before_digit = self.peek_second().map_or(false, |ch| ch.is_digit(radix));
+
+                if !after_digit || !before_digit {
+                    return Err("Digit separator must be placed between digits.");
+                }
+
+                after_digit = false;
+            } else if ch.is_digit(radix) {
+                digits.push(ch);
+                after_digit = true;
+            } else {
+                break;
+            }
+
+            self.bump();
+        }
+
+        Ok(digits)
+    }
+
+    /// 閉じられていない文字列リテラルのエラーを作成
+    fn unterminated_string(&self, start: usize, line: usize, column: usize) -> LexError {
+        LexError::new(
+            "Unterminated string literal.",
+            self.span_from(start, line, column),
+        )
+    }
+
+    /// ソースコードから次のトークンを実行して返す
+    pub fn lex(&mut self) -> LexResult {
+        let src = self.input;
+
+        // 空白スキップ
+        while let Some(ch) = self.peek() {
+            if !ch.is_whitespace() {
+                break;
+            }
+
+            self.bump();
+        }
+
+        let start = self.pos;
+        let line = self.line;
+        let column = self.column;
+
+        // EOFチェック
+        let next = match self.bump() {
+            Some(ch) => ch,
+            None => {
+                return Ok(SpannedToken {
+                    token: EOF,
+                    span: Span {
+                        start: start,
+                        end: start,
+                        line: line,
+                        column: column,
+                    },
+                })
+            }
+        };
 
         // 実際にNextTokenの取得をする
-        let result = match next.unwrap() {
-            '(' => Ok(LParen),
-            ')' => Ok(RParen),
-            ',' => Ok(Comma),
+        let token = match next {
+            '(' => LParen,
+            ')' => RParen,
+            '[' => LBracket,
+            ']' => RBracket,
+            '{' => LBrace,
+            '}' => RBrace,
+            '\\' => Lambda,
+            ',' => Comma,
+            ';' => Semicolon,
 
             '#' => {
                 // 改行まで取得せずにloopする
                 loop {
-                    let ch = chars.next();
-                    pos += 1;
-
-                    if ch == Some('\n') {
-                        break;
+                    match self.bump() {
+                        Some('\n') | None => break,
+                        Some(_) => (),
                     }
                 }
 
-                Ok(Comment)
+                Comment
             }
 
-            '.' | '0'..='9' => {
-                // Numberリテラルのパース
-                loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(EOF),
-                    };
-
-                    // Parse float.
-                    if ch != '.' && !ch.is_digit(16) {
-                        break;
-                    }
+            '"' => self.lex_string(start, line, column)?,
 
-                    chars.next();
-                    pos += 1;
-                }
+            // 数字が続かない'.'はフィールドアクセス
+            '.' if !self.peek().map_or(false, |ch| ch.is_digit(10)) => Dot,
 
-                Ok(Number(src[start..pos].parse().unwrap()))
-            }
+            '.' | '0'..='9' => self.lex_number(next, start, line, column)?,
 
             'a'..='z' | 'A'..='Z' | '_' => {
                 // 識別子のパース
-                loop {
-                    let ch = match chars.peek() {
-                        Some(ch) => *ch,
-                        None => return Ok(EOF),
-                    };
-
+                while let Some(ch) = self.peek() {
                     // 識別子の2文字目以降はアンダースコアか数字のみである
                     if ch != '_' && !ch.is_alphanumeric() {
                         break;
                     }
 
-                    chars.next();
-                    pos += 1;
+                    self.bump();
                 }
 
-                match &src[start..pos] {
+                match &src[start..self.pos] {
                     // 予約後として認識
-                    "def" => Ok(Def),
-                    "extern" => Ok(Extern),
-                    "if" => Ok(If),
-                    "then" => Ok(Then),
-                    "else" => Ok(Else),
-                    "for" => Ok(For),
-                    "in" => Ok(In),
-                    "unary" => Ok(Unary),
-                    "binary" => Ok(Binary),
-                    "var" => Ok(Var),
+                    "def" => Def,
+                    "extern" => Extern,
+                    "if" => If,
+                    "then" => Then,
+                    "else" => Else,
+                    "for" => For,
+                    "in" => In,
+                    "unary" => Unary,
+                    "binary" => Binary,
+                    "var" => Var,
+                    "global" => Global,
+                    "struct" => Struct,
+                    "while" => While,
+                    "do" => Do,
+                    "break" => Break,
+                    "continue" => Continue,
+                    "return" => Return,
                     // 予約後ではない場合はユーザー定義識別子として認識
-                    ident => Ok(Ident(ident.to_string())),
+                    ident => Ident(ident.to_string()),
                 }
             }
 
             // その他は全てオペレータとして認識
             op => {
                 // Parse operator
-                Ok(Op(op))
+                Op(op)
             }
         };
 
-        // positionを現在地に進めて保存し、終了
-        self.pos = pos;
-
-        result
+        Ok(SpannedToken {
+            token: token,
+            span: Span {
+                start: start,
+                end: self.pos,
+                line: line,
+                column: column,
+            },
+        })
     }
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Token;
+    type Item = SpannedToken;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.lex() {
-            Ok(EOF) | Err(_) => None,
+            Ok(SpannedToken { token: EOF, .. }) | Err(_) => None,
             Ok(token) => Some(token),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 入力の終わりまで字句解析し、EOFを除いた結果を返す
+    fn lex_all(input: &str) -> Vec<LexResult> {
+        let mut lexer = Lexer::new(input);
+        let mut results = Vec::new();
+
+        loop {
+            match lexer.lex() {
+                Ok(SpannedToken { token: EOF, .. }) => return results,
+                result => results.push(result),
+            }
+        }
+    }
+
+    /// 入力がひとつの数値リテラルであることを確かめ、その値を返す
+    fn number(input: &str) -> f64 {
+        match lex_all(input).as_slice() {
+            [Ok(SpannedToken {
+                token: Number(value),
+                span,
+            })] => {
+                assert_eq!((span.start, span.end), (0, input.len()), "{}", input);
+
+                *value
+            }
+            results => panic!("{}: expected a single number, found {:?}", input, results),
+        }
+    }
+
+    /// 入力がひとつの字句解析エラーとなることを確かめ、そのエラーを返す
+    fn error(input: &str) -> LexError {
+        let mut results = lex_all(input);
+
+        assert_eq!(results.len(), 1, "{}: {:?}", input, results);
+
+        results.pop().unwrap().unwrap_err()
+    }
+
+    #[test]
+    fn decimal_literals() {
+        assert_eq!(number("42"), 42.0);
+        assert_eq!(number("1.5e-3"), 1.5e-3);
+        assert_eq!(number("2E+2"), 200.0);
+        assert_eq!(number("1."), 1.0);
+        assert_eq!(number(".5"), 0.5);
+        assert_eq!(number("1_000.000_1"), 1000.0001);
+    }
+
+    #[test]
+    fn prefixed_literals() {
+        assert_eq!(number("0x1F"), 31.0);
+        assert_eq!(number("0XfF"), 255.0);
+        assert_eq!(number("0b1010"), 10.0);
+        assert_eq!(number("0o17"), 15.0);
+        assert_eq!(number("0xdead_beef"), 3_735_928_559.0);
+    }
+
+    #[test]
+    fn invalid_literals() {
+        let cases = [
+            ("0x", "Missing digits after number base prefix."),
+            ("0b", "Missing digits after number base prefix."),
+            ("0o", "Missing digits after number base prefix."),
+            ("0b2", "Missing digits after number base prefix."),
+            ("1e", "Missing digits in number exponent."),
+            ("1e+", "Missing digits in number exponent."),
+            ("1.2.3", "Invalid character in number literal."),
+            ("2abc", "Invalid character in number literal."),
+            ("1__0", "Digit separator must be placed between digits."),
+            ("1_", "Digit separator must be placed between digits."),
+            ("0x_1", "Digit separator must be placed between digits."),
+            ("0x1_0000_0000_0000_0000", "Integer literal is too large."),
+        ];
+
+        for &(input, msg) in &cases {
+            let err = error(input);
+
+            assert_eq!(err.error, msg, "{}", input);
+            // エラーはリテラル全体を指す
+            assert_eq!(
+                (err.span.start, err.span.end),
+                (0, input.len()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn error_span_position() {
+        let results = lex_all("var x =\n  y + 0x");
+
+        let err = match results.last() {
+            Some(Err(err)) => err,
+            results => panic!("expected an error, found {:?}", results),
+        };
+
+        assert_eq!(
+            err.span,
+            Span {
+                start: 14,
+                end: 16,
+                line: 2,
+                column: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn lexing_resumes_after_error() {
+        let results = lex_all("1__0 + 2abc * 0x10");
+
+        assert_eq!(results.len(), 5, "{:?}", results);
+        assert!(results[0].is_err());
+        assert!(results[2].is_err());
+
+        match (&results[1], &results[3], &results[4]) {
+            (
+                Ok(SpannedToken { token: Op('+'), .. }),
+                Ok(SpannedToken { token: Op('*'), .. }),
+                Ok(SpannedToken {
+                    token: Number(value),
+                    span,
+                }),
+            ) => {
+                assert_eq!(*value, 16.0);
+                assert_eq!((span.start, span.end), (14, 18));
+            }
+            results => panic!("unexpected tokens: {:?}", results),
+        }
+
+        assert_eq!(results[2].as_ref().unwrap_err().span.start, 7);
+        assert_eq!(results[2].as_ref().unwrap_err().span.end, 11);
+    }
+}
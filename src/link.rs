@@ -0,0 +1,229 @@
+use crate::backend::{self, TargetOptions};
+use crate::lexer::Span;
+use crate::parser::{Expr, ExprKind, Item};
+use crate::resolve::{Resolver, Symbols};
+
+use inkwell::module::Module;
+use inkwell::targets::{FileType, RelocMode};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// 実行ファイルにリンクされるランタイムのソースコード
+const RUNTIME_SOURCE: &str = include_str!("runtime/runtime.c");
+
+/// ユーザー定義の'main'関数がコンパイルされる際のシンボル名
+/// Cの'main'はランタイムが定義し、この関数を呼び出す
+pub const ENTRY_SYMBOL: &str = "ks_main";
+
+/// ユーザー定義の'main'関数と、その関数に解決される呼び出しと関数値をENTRY_SYMBOLに名前変更する
+/// 同じ名前のローカル変数は名前解決と同じ規則で区別され、変更されない
+/// 'main'が定義されていない場合はfalseを返す
+pub fn rename_entry_point<'a, I: Iterator<Item = &'a mut Item>>(items: I) -> bool {
+    let mut items: Vec<&mut Item> = items.collect();
+    let mut symbols = Symbols::new();
+
+    let is_entry_point = |item: &Item| match *item {
+        Item::Function(ref fun) => fun.prototype.name == "main" && fun.body.is_some(),
+        Item::Global(_) | Item::Struct(_) => false,
+    };
+
+    if !items.iter().any(|item| is_entry_point(item)) {
+        return false;
+    }
+
+    for item in &items {
+        symbols.add_item(item);
+    }
+
+    for item in &mut items {
+        let spans = Resolver::function_references(&symbols, item, "main");
+        let is_main = is_entry_point(item);
+
+        match **item {
+            Item::Function(ref mut fun) => {
+                if is_main {
+                    fun.prototype.name = ENTRY_SYMBOL.to_string();
+                }
+
+                if let Some(ref mut body) = fun.body {
+                    rename_references(body, &spans, ENTRY_SYMBOL);
+                }
+            }
+            Item::Global(ref mut global) => {
+                rename_references(&mut global.init, &spans, ENTRY_SYMBOL)
+            }
+            Item::Struct(_) => (),
+        }
+    }
+
+    true
+}
+
+/// 式に含まれる、指定された位置の変数と呼び出しの関数名を'to'に置き換える
+fn rename_references(expr: &mut Expr, spans: &[Span], to: &str) {
+    if spans.contains(&expr.span) {
+        match expr.kind {
+            ExprKind::Variable(ref mut name) => *name = to.to_string(),
+            ExprKind::Call {
+                ref mut fn_name, ..
+            } => *fn_name = to.to_string(),
+            _ => (),
+        }
+    }
+
+    for child in expr.children_mut() {
+        rename_references(child, spans, to);
+    }
+}
+
+/// モジュールをオブジェクトファイルにコンパイルし、ランタイムとともにシステムのリンカでリンクする
+/// リンカは環境変数'CC'で変更でき、デフォルトは'cc'となる
+pub fn link_executable(
+    module: &Module,
+    target: &TargetOptions,
+    opt_level: u32,
+    output: &str,
+) -> Result<(), String> {
+    // 近年のccはデフォルトでPIEを生成するため、位置独立コードとして出力する
+    let mut target = target.clone();
+
+    if target.reloc_mode == RelocMode::Default {
+        target.reloc_mode = RelocMode::PIC;
+    }
+
+    let object = temp_path("module.o");
+    let runtime = temp_path("runtime.c");
+
+    let result = backend::create_target_machine(&target, opt_level)
+        .and_then(|machine| {
+            backend::write_module(&machine, module, FileType::Object, path_str(&object)?)
+        })
+        .and_then(|()| {
+            File::create(&runtime)
+                .and_then(|mut f| f.write_all(RUNTIME_SOURCE.as_bytes()))
+                .map_err(|err| format!("could not write the runtime: {}", err))
+        })
+        .and_then(|()| run_linker(&object, &runtime, output));
+
+    // 一時ファイルの削除に失敗しても結果には影響しない
+    let _ = fs::remove_file(&object);
+    let _ = fs::remove_file(&runtime);
+
+    result
+}
+
+/// リンカを起動して実行ファイルを生成
+fn run_linker(object: &Path, runtime: &Path, output: &str) -> Result<(), String> {
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = Command::new(&cc)
+        .arg("-o")
+        .arg(output)
+        .arg(object)
+        .arg(runtime)
+        .arg("-lm")
+        .status()
+        .map_err(|err| format!("could not run linker '{}': {}", cc, err))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("linker '{}' failed with {}", cc, status))
+    }
+}
+
+/// プロセスごとに一意な一時ファイルのパスを返す
+fn temp_path(name: &str) -> PathBuf {
+    let mut path = env::temp_dir();
+
+    path.push(format!("kaleidscope-{}-{}", std::process::id(), name));
+
+    path
+}
+
+/// パスを文字列に変換
+fn path_str(path: &Path) -> Result<&str, String> {
+    path.to_str()
+        .ok_or_else(|| format!("invalid temporary path '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operators::OperatorTable;
+    use crate::parser::Parser;
+
+    /// 要素に含まれる変数と呼び出しの名前を集める
+    fn referenced_names(item: &mut Item) -> Vec<String> {
+        fn collect(expr: &mut Expr, names: &mut Vec<String>) {
+            match expr.kind {
+                ExprKind::Variable(ref name) => names.push(name.clone()),
+                ExprKind::Call { ref fn_name, .. } => names.push(fn_name.clone()),
+                _ => (),
+            }
+
+            for child in expr.children_mut() {
+                collect(child, names);
+            }
+        }
+
+        let mut names = Vec::new();
+
+        match *item {
+            Item::Function(ref mut fun) => {
+                if let Some(ref mut body) = fun.body {
+                    collect(body, &mut names);
+                }
+            }
+            Item::Global(ref mut global) => collect(&mut global.init, &mut names),
+            Item::Struct(_) => (),
+        }
+
+        names
+    }
+
+    /// 'main'関数への呼び出しと関数値は変更され、同じ名前のローカル変数は変更されない
+    #[test]
+    fn renames_resolved_references() {
+        let text = "def main() 0
+def direct() main()
+def value() var f = main in f()
+global entry = main
+def param(main: fn(f64), x) main(x)
+def local() var main = \\x -> x in main(1) + main";
+        let mut operators = OperatorTable::new();
+        let (mut items, diagnostics) =
+            Parser::new(text.to_string(), &mut operators).parse_program();
+
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert!(rename_entry_point(items.iter_mut()));
+
+        match items[0] {
+            Item::Function(ref fun) => assert_eq!(fun.prototype.name, ENTRY_SYMBOL),
+            _ => panic!("expected a function"),
+        }
+
+        let names: Vec<Vec<String>> = items.iter_mut().map(referenced_names).collect();
+
+        assert_eq!(names[1], vec![ENTRY_SYMBOL]);
+        assert_eq!(names[2], vec![ENTRY_SYMBOL, "f"]);
+        assert_eq!(names[3], vec![ENTRY_SYMBOL]);
+        assert_eq!(names[4], vec!["main", "x"]);
+        assert_eq!(names[5], vec!["x", "main", "main"]);
+    }
+
+    /// 'main'が定義されていない場合は、何も変更されない
+    #[test]
+    fn missing_entry_point() {
+        let mut operators = OperatorTable::new();
+        let (mut items, _) =
+            Parser::new("extern main()\nmain()".to_string(), &mut operators).parse_program();
+
+        assert!(!rename_entry_point(items.iter_mut()));
+        assert_eq!(referenced_names(&mut items[1]), vec!["main"]);
+    }
+}
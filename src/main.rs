@@ -1,20 +1,41 @@
+mod backend;
+mod bytecode;
+mod cli;
 mod compiler;
+mod diagnostic;
+mod difftest;
+mod driver;
+mod fuzz;
+mod interp;
+mod jit;
 mod lexer;
+mod link;
+mod operators;
 mod parser;
-
-use compiler::*;
+mod resolve;
+mod typeck;
+mod vm;
+
+use cli::Options;
+use diagnostic::Diagnostic;
+use interp::Interpreter;
+use jit::Session;
 use lexer::*;
+use operators::OperatorTable;
 use parser::*;
 
 use inkwell::context::Context;
-use inkwell::passes::PassManager;
-use inkwell::OptimizationLevel;
 
-use std::collections::HashMap;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
 use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::thread;
 
-use std::fs::File;
-use std::io::prelude::*;
+/// インタプリタのREPLを実行するスレッドのスタックサイズ
+/// インタプリタは式の評価にRustのスタックを再帰的に使うため、深い再帰に備えて大きくとる
+const INTERPRETER_STACK_SIZE: usize = 256 << 20;
 
 // 新しい行を出力せずにprintとflushに使用されるマクロ
 macro_rules! print_flush {
@@ -25,124 +46,229 @@ macro_rules! print_flush {
     };
 }
 
+thread_local! {
+    /// 組み込み関数の出力を捕捉するバッファ
+    /// Noneの場合は標準出力に書き込む
+    static CAPTURED_OUTPUT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// 組み込み関数の出力を、捕捉中であればバッファに、それ以外は標準出力に書き込む
+fn write_output(text: &str) {
+    CAPTURED_OUTPUT.with(|captured| match *captured.borrow_mut() {
+        Some(ref mut buffer) => buffer.push_str(text),
+        None => {
+            print_flush!("{}", text);
+        }
+    });
+}
+
+/// 'f'を実行し、その間に組み込み関数が出力した文字列を結果とともに返す
+pub fn capture_output<T, F: FnOnce() -> T>(f: F) -> (T, String) {
+    CAPTURED_OUTPUT.with(|captured| *captured.borrow_mut() = Some(String::new()));
+
+    let result = f();
+    let output = CAPTURED_OUTPUT.with(|captured| captured.borrow_mut().take());
+
+    (result, output.unwrap_or_default())
+}
+
 #[no_mangle]
 pub extern "C" fn putchard(x: f64) -> f64 {
-    print_flush!("{}", x as u8 as char);
+    write_output(&(x as u8 as char).to_string());
     x
 }
 
 #[no_mangle]
 pub extern "C" fn printd(x: f64) -> f64 {
-    println!("{}", x);
+    write_output(&format!("{}\n", x));
     x
 }
 
+/// 文字列を改行せずに出力する
+#[no_mangle]
+pub unsafe extern "C" fn prints(s: *const c_char) -> f64 {
+    write_output(&CStr::from_ptr(s).to_string_lossy());
+    0.
+}
+
+/// 2つの文字列を連結した新しい文字列を返す
+/// 返された文字列は解放されない
+#[no_mangle]
+pub unsafe extern "C" fn concat(a: *const c_char, b: *const c_char) -> *mut c_char {
+    let mut bytes = CStr::from_ptr(a).to_bytes().to_vec();
+
+    bytes.extend_from_slice(CStr::from_ptr(b).to_bytes());
+
+    CString::new(bytes).unwrap().into_raw()
+}
+
+/// 数値をprintdと同じ形式で文字列に変換する
+/// 返された文字列は解放されない
+#[no_mangle]
+pub extern "C" fn tostr(x: f64) -> *mut c_char {
+    CString::new(x.to_string()).unwrap().into_raw()
+}
+
+/// 長さ'len'の配列を確保し、要素を0で初期化する
+/// 配列は長さの後に要素が続くメモリとして表現され、解放されない
+#[no_mangle]
+pub extern "C" fn ks_array_new(len: i64) -> *mut i64 {
+    let len = len.max(0) as usize;
+    let layout = Layout::from_size_align(8 * (len + 1), 8).unwrap();
+
+    unsafe {
+        let array = alloc::alloc_zeroed(layout) as *mut i64;
+
+        if array.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+
+        *array = len as i64;
+
+        array
+    }
+}
+
+/// 実行時検査に失敗した場所を表示し、プロセスを異常終了する
+#[no_mangle]
+pub unsafe extern "C" fn ks_trap(
+    message: *const c_char,
+    function: *const c_char,
+    line: i32,
+    column: i32,
+) {
+    io::stdout().flush().ok();
+
+    eprintln!(
+        "runtime error: {}\n  --> in function '{}' at {}:{}",
+        CStr::from_ptr(message).to_string_lossy(),
+        CStr::from_ptr(function).to_string_lossy(),
+        line,
+        column
+    );
+
+    std::process::abort();
+}
+
+/// 実行時検査が有効な場合に、関数呼び出しの深さを数える
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut ks_call_depth: i64 = 0;
+
 /// Rustコンパイラに削除されないよう、上記の関数をグローバル配列に追加する。
+/// 'strlen'はCライブラリの関数がそのまま使われる
 #[used]
 static EXTERNAL_FNS: [extern "C" fn(f64) -> f64; 2] = [putchard, printd];
 
-/// Replのエントリーポイント
+#[used]
+static STRING_FNS: (
+    unsafe extern "C" fn(*const c_char) -> f64,
+    unsafe extern "C" fn(*const c_char, *const c_char) -> *mut c_char,
+    extern "C" fn(f64) -> *mut c_char,
+) = (prints, concat, tostr);
+
+#[used]
+static ARRAY_FNS: [extern "C" fn(i64) -> *mut i64; 1] = [ks_array_new];
+
+#[used]
+static TRAP_FNS: [unsafe extern "C" fn(*const c_char, *const c_char, i32, i32); 1] = [ks_trap];
+
+/// エントリーポイント
 fn main() {
-    let mut repl = false;
-    for arg in std::env::args() {
-        match arg.as_str() {
-            "-a" => repl = true,
-            _ => (),
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(driver::EXIT_FAILURE);
         }
-    }
+    };
 
-    if repl {
-        run_repl();
+    if options.help {
+        println!("{}", cli::USAGE);
+    } else if options.difftest {
+        // インタプリタと同じく、深い再帰のために大きなスタックを用意する
+        let inputs = options.inputs.clone();
+        let difftest = thread::Builder::new()
+            .stack_size(INTERPRETER_STACK_SIZE)
+            .spawn(move || difftest::run(&inputs))
+            .expect("Could not start the difftest thread.");
+
+        std::process::exit(difftest.join().unwrap_or(driver::EXIT_FAILURE));
+    } else if let Some(count) = options.fuzz {
+        std::process::exit(fuzz::run(count, options.seed));
+    } else if options.repl && options.interpret {
+        let repl = thread::Builder::new()
+            .stack_size(INTERPRETER_STACK_SIZE)
+            .spawn(move || run_repl(&options))
+            .expect("Could not start the interpreter thread.");
+
+        if repl.join().is_err() {
+            std::process::exit(driver::EXIT_FAILURE);
+        }
+    } else if options.repl {
+        run_repl(&options);
     } else {
-        compile();
+        std::process::exit(driver::run(&options));
     }
 }
 
-fn compile() {
-    let context = Context::create();
-    let module = context.create_module("repl");
-    let builder = context.create_builder();
-
-    // Create FPM
-    let fpm = PassManager::create(&module);
-
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.add_gvn_pass();
-    fpm.add_cfg_simplification_pass();
-    fpm.add_basic_alias_analysis_pass();
-    fpm.add_promote_memory_to_register_pass();
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-
-    fpm.initialize();
-
-    // ファイルが見つかりませんでした
-    let mut f = File::open("input.ks").expect("file not found");
-
-    let mut input = String::new();
-    f.read_to_string(&mut input)
-        // ファイルの読み込み中に問題がありました
-        .expect("something went wrong reading the file");
-
-    // 優先順位mapの生成
-    let mut prec = HashMap::with_capacity(6);
-
-    prec.insert('=', 2);
-    prec.insert('<', 10);
-    prec.insert('+', 20);
-    prec.insert('-', 20);
-    prec.insert('*', 40);
-    prec.insert('/', 40);
-
-    // make module
-    let module = context.create_module("main");
-
-    match Parser::new(input, &mut prec).parse() {
-        Ok(fun) => {
-            Compiler::compile(&context, &builder, &fpm, &module, &fun).unwrap();
-        }
-        Err(err) => {
-            println!("!> Error parsing expression: {}", err);
-        }
-    };
-    module.print_to_file("main.ll").unwrap();
+/// REPLの入力を評価するバックエンド
+enum Backend<'ctx> {
+    Jit(Session<'ctx>),
+    Interpreter(Interpreter),
 }
 
-fn run_repl() {
-    // use self::inkwell::support::add_symbol;
-    let mut display_lexer_output = false;
-    let mut display_parser_output = false;
-    let mut display_compiler_output = false;
+impl<'ctx> Backend<'ctx> {
+    /// 入力をまたいで保持される演算子表を返す
+    fn operators(&mut self) -> &mut OperatorTable {
+        match *self {
+            Backend::Jit(ref mut session) => &mut session.operators,
+            Backend::Interpreter(ref mut interpreter) => &mut interpreter.operators,
+        }
+    }
 
-    for arg in std::env::args() {
-        match arg.as_str() {
-            "--dl" => display_lexer_output = true,
-            "--dp" => display_parser_output = true,
-            "--dc" => display_compiler_output = true,
-            _ => (),
+    /// 入力を評価し、トップレベルの式の場合は表示する値を返す
+    fn eval(&mut self, item: &Item) -> Result<Option<String>, Vec<Diagnostic>> {
+        match *self {
+            Backend::Jit(ref mut session) => session
+                .eval(item)
+                .map(|value| value.map(|value| value.to_string())),
+            Backend::Interpreter(ref mut interpreter) => interpreter
+                .eval(item)
+                .map(|value| value.map(|value| value.to_string())),
         }
     }
 
-    let context = Context::create();
-    let module = context.create_module("repl");
-    let builder = context.create_builder();
+    /// ユーザー定義演算子と、それを実装する関数を取り除く
+    fn remove_operator(&mut self, op: char) -> bool {
+        match *self {
+            Backend::Jit(ref mut session) => session.remove_operator(op),
+            Backend::Interpreter(ref mut interpreter) => interpreter.remove_operator(op),
+        }
+    }
+}
 
-    // Create FPM
-    let fpm = PassManager::create(&module);
+fn run_repl(options: &Options) {
+    let display_lexer_output = options.display_lexer_output;
+    let display_parser_output = options.display_parser_output;
 
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
-    fpm.add_gvn_pass();
-    fpm.add_cfg_simplification_pass();
-    fpm.add_basic_alias_analysis_pass();
-    fpm.add_promote_memory_to_register_pass();
-    fpm.add_instruction_combining_pass();
-    fpm.add_reassociate_pass();
+    let context = Context::create();
+    let mut backend = if options.interpret {
+        Backend::Interpreter(Interpreter::new())
+    } else {
+        let mut session = match Session::new(&context, options.opt_level) {
+            Ok(session) => session,
+            Err(err) => {
+                eprintln!("error: could not create the JIT session: {}", err);
+                std::process::exit(driver::EXIT_FAILURE);
+            }
+        };
 
-    fpm.initialize();
+        session.display_compiler_output = options.display_compiler_output;
+        session.checks = options.checks;
 
-    let mut previous_exprs = Vec::new();
+        Backend::Jit(session)
+    };
 
     loop {
         println!();
@@ -158,93 +284,81 @@ fn run_repl() {
             break;
         } else if input.chars().all(char::is_whitespace) {
             continue;
+        } else if input.starts_with(':') {
+            run_command(&mut backend, input.trim());
+            continue;
         }
 
-        // 優先順位mapの生成
-        let mut prec = HashMap::with_capacity(6);
-
-        prec.insert('=', 2);
-        prec.insert('<', 10);
-        prec.insert('+', 20);
-        prec.insert('-', 20);
-        prec.insert('*', 40);
-        prec.insert('/', 40);
-
         // 入力の解析および表示(optionall)
         if display_lexer_output {
             println!(
                 "-> Attempting to parse lexed input: \n{:?}\n",
-                Lexer::new(input.as_str()).collect::<Vec<Token>>()
+                Lexer::new(input.as_str())
+                    .map(|tok| tok.token)
+                    .collect::<Vec<Token>>()
             );
         }
 
-        // make module
-        let module = context.create_module("tmp");
+        let item = match Parser::new(input.clone(), backend.operators()).parse() {
+            Ok(item) => item,
+            Err(err) => {
+                print!("{}", err.render(&input, "<repl>"));
+                continue;
+            }
+        };
 
-        // 以前に解析された全ての関数を新しいモジュールに再コンパイル
-        for prev in &previous_exprs {
-            Compiler::compile(&context, &builder, &fpm, &module, prev)
-                .expect("Cannot re-add previously compiled function.");
+        if display_parser_output {
+            match item {
+                Item::Function(ref fun) if fun.is_anon => {
+                    println!("-> Expression parsed: \n{:?}\n", fun.body)
+                }
+                Item::Function(ref fun) => println!("-> Function parsed: \n{:?}\n", fun),
+                Item::Global(ref global) => println!("-> Global parsed: \n{:?}\n", global),
+                Item::Struct(ref def) => println!("-> Struct parsed: \n{:?}\n", def),
+            }
         }
 
-        let (name, is_anonymous) = match Parser::new(input, &mut prec).parse() {
-            Ok(fun) => {
-                let is_anon = fun.is_anon;
-
-                if display_parser_output {
-                    if is_anon {
-                        println!("-> Expression parsed: \n{:?}\n", fun.body);
-                    } else {
-                        println!("-> Function parsed: \n{:?}\n", fun);
-                    }
+        // 関数とグローバル変数はセッションに保持され、以降の入力から参照できる
+        match backend.eval(&item) {
+            Ok(Some(value)) => println!("=> {}", value),
+            Ok(None) => (),
+            Err(errs) => {
+                for err in errs {
+                    print!("{}", err.render(&input, "<repl>"));
                 }
+            }
+        }
+    }
+}
 
-                match Compiler::compile(&context, &builder, &fpm, &module, &fun) {
-                    Ok(function) => {
-                        if display_compiler_output {
-                            // Not printing a new line since LLVM automatically
-                            // prefixes the generated string with one
-                            print_flush!("-> Expression compiled to IR:");
-                            function.print_to_stderr();
-                        }
-
-                        if !is_anon {
-                            // only add it now to ensure it is correct
-                            previous_exprs.push(fun);
-                        }
-
-                        (function.get_name().to_str().unwrap().to_string(), is_anon)
-                    }
-                    Err(err) => {
-                        println!("!> Error compiling function: {}", err);
-                        continue;
-                    }
-                }
+/// ':'で始まるREPLのコマンドを実行
+fn run_command(backend: &mut Backend, command: &str) {
+    let mut words = command.split_whitespace();
+
+    match (words.next(), words.next()) {
+        // ユーザー定義演算子の一覧
+        (Some(":ops"), None) => {
+            for (op, prec) in backend.operators().custom_binary() {
+                println!("binary{} (precedence {})", op, prec);
             }
-            Err(err) => {
-                println!("!> Error parsing expression: {}", err);
-                continue;
+
+            for op in backend.operators().custom_unary() {
+                println!("unary{}", op);
             }
-        };
+        }
 
-        if is_anonymous {
-            let ee = module
-                .create_jit_execution_engine(OptimizationLevel::None)
-                .unwrap();
-
-            let maybe_fn =
-                unsafe { ee.get_function::<unsafe extern "C" fn() -> f64>(name.as_str()) };
-            let compiled_fn = match maybe_fn {
-                Ok(f) => f,
-                Err(err) => {
-                    println!("!> Error during execution: {:?}", err);
-                    continue;
-                }
-            };
+        // ユーザー定義演算子の削除
+        (Some(":undef"), Some(op)) if op.chars().count() == 1 => {
+            let op = op.chars().next().unwrap();
 
-            unsafe {
-                println!("=> {}", compiled_fn.call());
+            if !backend.remove_operator(op) {
+                println!("!> '{}' is not a user-defined operator", op);
             }
         }
+
+        _ => {
+            println!("!> Unknown command '{}'", command);
+            println!("!> Available commands: ':ops', ':undef <operator>', 'exit'");
+        }
     }
 }
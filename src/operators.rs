@@ -0,0 +1,77 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// 組み込みのバイナリ演算子と、その優先順位
+const BUILTIN_OPERATORS: [(char, i32); 6] = [
+    ('=', 2),
+    ('<', 10),
+    ('+', 20),
+    ('-', 20),
+    ('*', 40),
+    ('/', 40),
+];
+
+/// 組み込み演算子とユーザー定義演算子の表
+/// パーサーはこの表から二項演算子の優先順位を取得し、演算子の定義を解析すると表を更新する
+#[derive(Debug, Clone)]
+pub struct OperatorTable {
+    precedence: BTreeMap<char, i32>,
+    custom_binary: BTreeSet<char>,
+    custom_unary: BTreeSet<char>,
+}
+
+impl OperatorTable {
+    /// 組み込み演算子のみを含む表を作成
+    pub fn new() -> OperatorTable {
+        OperatorTable {
+            precedence: BUILTIN_OPERATORS.iter().cloned().collect(),
+            custom_binary: BTreeSet::new(),
+            custom_unary: BTreeSet::new(),
+        }
+    }
+
+    /// 二項演算子の優先順位を返す
+    pub fn precedence(&self, op: char) -> Option<i32> {
+        self.precedence.get(&op).cloned()
+    }
+
+    /// ユーザー定義の二項演算子を追加、または優先順位を再定義する
+    pub fn define_binary(&mut self, op: char, prec: i32) {
+        self.precedence.insert(op, prec);
+        self.custom_binary.insert(op);
+    }
+
+    /// ユーザー定義の単項演算子を追加する
+    pub fn define_unary(&mut self, op: char) {
+        self.custom_unary.insert(op);
+    }
+
+    /// ユーザー定義の二項、単項演算子を取り除く
+    /// 取り除かれた演算子があった場合はtrueを返す
+    pub fn remove(&mut self, op: char) -> bool {
+        let binary = self.custom_binary.remove(&op);
+        let unary = self.custom_unary.remove(&op);
+
+        if binary {
+            // 組み込み演算子を上書きしていた場合は、元の優先順位に戻す
+            match BUILTIN_OPERATORS.iter().find(|&&(builtin, _)| builtin == op) {
+                Some(&(_, prec)) => self.precedence.insert(op, prec),
+                None => self.precedence.remove(&op),
+            };
+        }
+
+        binary || unary
+    }
+
+    /// ユーザー定義の二項演算子と、その優先順位を返す
+    pub fn custom_binary(&self) -> Vec<(char, i32)> {
+        self.custom_binary
+            .iter()
+            .map(|op| (*op, self.precedence[op]))
+            .collect()
+    }
+
+    /// ユーザー定義の単項演算子を返す
+    pub fn custom_unary(&self) -> Vec<char> {
+        self.custom_unary.iter().cloned().collect()
+    }
+}
@@ -1,21 +1,142 @@
+use crate::diagnostic::Diagnostic;
 use crate::lexer::*;
-use std::collections::HashMap;
+use crate::operators::OperatorTable;
 use Token::*;
 
+use std::fmt;
+
 const ANONYMOUS_FUNCTION_NAME: &str = "anonymous";
 
+/// コンパイラが直接実装する組み込み関数と、その引数の数
+/// 'array(n)'は長さnの配列を確保し、'len(a)'は配列の長さを返す
+/// 'f64(x)'、'i32(x)'、'i64(x)'は数値かboolを指定された型に変換する
+pub const INTRINSICS: [(&str, usize); 5] =
+    [("array", 1), ("len", 1), ("f64", 1), ("i32", 1), ("i64", 1)];
+
+/// 組み込み関数であれば、その引数の数を返す
+pub fn intrinsic_arity(name: &str) -> Option<usize> {
+    INTRINSICS
+        .iter()
+        .find(|&&(intrinsic, _)| intrinsic == name)
+        .map(|&(_, arity)| arity)
+}
+
+/// 値の静的な型
+/// 型注釈が省略された場合はf64となる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Bool,
+    F64,
+    I32,
+    I64,
+    /// NULで終端された文字列へのポインタ
+    Str,
+    /// 長さを持つf64の配列へのポインタ
+    Array,
+    /// 名前で宣言された構造体へのポインタ
+    Struct(String),
+    /// 引数と戻り値の型を持つ関数値
+    /// 関数へのポインタと、キャプチャした変数の環境の組へのポインタとして表される
+    Fn(Vec<Type>, Box<Type>),
+}
+
+impl Type {
+    /// 型注釈に書かれた名前から組み込みの型を取得
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "bool" => Some(Type::Bool),
+            "f64" => Some(Type::F64),
+            "i32" => Some(Type::I32),
+            "i64" => Some(Type::I64),
+            "str" => Some(Type::Str),
+            "array" => Some(Type::Array),
+            _ => None,
+        }
+    }
+
+    /// 整数型かどうかを返す
+    pub fn is_integer(&self) -> bool {
+        *self == Type::I32 || *self == Type::I64
+    }
+
+    /// 算術演算が可能な数値型かどうかを返す
+    pub fn is_numeric(&self) -> bool {
+        *self == Type::F64 || self.is_integer()
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Bool => write!(f, "bool"),
+            Type::F64 => write!(f, "f64"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::Str => write!(f, "str"),
+            Type::Array => write!(f, "array"),
+            Type::Struct(ref name) => write!(f, "{}", name),
+            Type::Fn(ref args, ref ret) => {
+                write!(f, "fn(")?;
+
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{}", arg)?;
+                }
+
+                write!(f, ") -> {}", ret)
+            }
+        }
+    }
+}
+
+/// 位置情報付きの式
+/// 'ty'は型検査の後に設定され、それまではf64となる
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    pub ty: Type,
+}
+
 /// プリミティブ式の定義
-#[derive(Debug)]
-pub enum Expr {
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    /// 配列リテラル'[e1, e2, ...]'
+    Array(Vec<Expr>),
+
     Binary {
         op: char,
         left: Box<Expr>,
         right: Box<Expr>,
     },
 
+    /// '{ e1; e2; ... }'で順に評価される式
+    /// 値は最後の式の値で、空の場合は0となる
+    Block(Vec<Expr>),
+
+    /// ループを抜ける'break'
+    /// 値が指定された場合は、それがループの値となる
+    Break(Option<Box<Expr>>),
+
+    /// 関数呼び出し'fn_name(args)'
+    /// 'local'は型検査の後に設定される、ローカル変数に格納された関数値の呼び出しかどうか
     Call {
         fn_name: String,
         args: Vec<Expr>,
+        local: bool,
+    },
+
+    /// 型検査によって挿入される、式の型の変換
+    Cast(Box<Expr>),
+
+    /// 構造体の作成'Name { field: value, ... }'
+    /// 型検査の後はフィールドが宣言された順に並べ替えられる
+    Construct {
+        name: String,
+        fields: Vec<(String, Expr)>,
     },
 
     Conditional {
@@ -24,78 +145,272 @@ pub enum Expr {
         alternative: Box<Expr>,
     },
 
+    /// ループの次の繰り返しに進む'continue'
+    Continue,
+
     For {
         var_name: String,
+        var_type: Option<Type>,
         start: Box<Expr>,
         end: Box<Expr>,
         step: Option<Box<Expr>>,
         body: Box<Expr>,
     },
 
+    /// 構造体のフィールド'value.field'
+    /// 'index'は型検査の後に設定される、宣言されたフィールドの位置
+    Field {
+        value: Box<Expr>,
+        field: String,
+        index: usize,
+    },
+
+    /// 配列の要素'array[index]'
+    Index {
+        array: Box<Expr>,
+        index: Box<Expr>,
+    },
+
+    /// 無名関数'\x, y -> body'
+    /// 'captures'は型検査の後に設定される、本体から参照される外側のローカル変数とその型
+    Lambda {
+        params: Vec<(String, Option<Type>)>,
+        body: Box<Expr>,
+        captures: Vec<(String, Type)>,
+    },
+
     Number(f64),
 
+    Str(String),
+
     Variable(String),
 
     VarIn {
-        variables: Vec<(String, Option<Expr>)>,
+        variables: Vec<(String, Option<Type>, Option<Expr>)>,
+        body: Box<Expr>,
+    },
+
+    /// 囲む関数から値を返す'return'
+    Return(Box<Expr>),
+
+    /// 'while cond do body'ループ
+    /// 'break'で値が渡されなければ0となる
+    While {
+        cond: Box<Expr>,
         body: Box<Expr>,
     },
 }
 
-/// 関数のプロトタイプ(名前とパラメータ)を定義
-#[derive(Debug)]
+impl Expr {
+    /// 式が値を返さずに制御を移すかを返す
+    /// ブロックは最後の式が、条件式は両方の分岐が制御を移す場合に該当する
+    pub fn diverges(&self) -> bool {
+        match self.kind {
+            ExprKind::Break(_) | ExprKind::Continue | ExprKind::Return(_) => true,
+            ExprKind::Block(ref exprs) => exprs.last().map_or(false, Expr::diverges),
+            ExprKind::VarIn { ref body, .. } => body.diverges(),
+            ExprKind::Conditional {
+                ref consequence,
+                ref alternative,
+                ..
+            } => consequence.diverges() && alternative.diverges(),
+            _ => false,
+        }
+    }
+
+    /// 直下の子の式を全て返す
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self.kind {
+            ExprKind::Array(ref mut elements) => elements.iter_mut().collect(),
+
+            ExprKind::Binary {
+                ref mut left,
+                ref mut right,
+                ..
+            } => vec![&mut **left, &mut **right],
+
+            ExprKind::Block(ref mut exprs) => exprs.iter_mut().collect(),
+
+            ExprKind::Break(Some(ref mut value)) | ExprKind::Return(ref mut value) => {
+                vec![&mut **value]
+            }
+
+            ExprKind::Call { ref mut args, .. } => args.iter_mut().collect(),
+
+            ExprKind::Cast(ref mut value) => vec![&mut **value],
+
+            ExprKind::Construct { ref mut fields, .. } => fields
+                .iter_mut()
+                .map(|&mut (_, ref mut value)| value)
+                .collect(),
+
+            ExprKind::Conditional {
+                ref mut cond,
+                ref mut consequence,
+                ref mut alternative,
+            } => vec![&mut **cond, &mut **consequence, &mut **alternative],
+
+            ExprKind::For {
+                ref mut start,
+                ref mut end,
+                ref mut step,
+                ref mut body,
+                ..
+            } => {
+                let mut children = vec![&mut **start, &mut **end];
+
+                if let Some(ref mut step) = *step {
+                    children.push(&mut **step);
+                }
+
+                children.push(&mut **body);
+                children
+            }
+
+            ExprKind::Field { ref mut value, .. } => vec![&mut **value],
+
+            ExprKind::Index {
+                ref mut array,
+                ref mut index,
+            } => vec![&mut **array, &mut **index],
+
+            ExprKind::Lambda { ref mut body, .. } => vec![&mut **body],
+
+            ExprKind::Break(None)
+            | ExprKind::Continue
+            | ExprKind::Number(_)
+            | ExprKind::Str(_)
+            | ExprKind::Variable(_) => vec![],
+
+            ExprKind::VarIn {
+                ref mut variables,
+                ref mut body,
+            } => {
+                let mut children: Vec<&mut Expr> = variables
+                    .iter_mut()
+                    .filter_map(|&mut (_, _, ref mut init)| init.as_mut())
+                    .collect();
+
+                children.push(&mut **body);
+                children
+            }
+
+            ExprKind::While {
+                ref mut cond,
+                ref mut body,
+            } => vec![&mut **cond, &mut **body],
+        }
+    }
+}
+
+/// 関数のプロトタイプ(名前、パラメータと型)を定義
+#[derive(Debug, Clone)]
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    pub arg_types: Vec<Type>,
+    pub ret_type: Type,
     pub is_op: bool,
     pub prec: usize,
+    pub span: Span,
 }
 
 /// ユーザー定義、または外部関数の定義
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Function {
     pub prototype: Prototype,
     pub body: Option<Expr>,
     pub is_anon: bool,
+    pub span: Span,
+}
+
+/// トップレベルのグローバル変数の宣言
+#[derive(Debug)]
+pub struct GlobalVar {
+    pub name: String,
+    pub init: Expr,
+    pub span: Span,
+}
+
+/// 構造体の宣言'struct Name { field: type, ... }'
+/// 型注釈が省略されたフィールドはf64となる
+#[derive(Debug, Clone)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<(String, Type)>,
+    pub span: Span,
+}
+
+impl StructDef {
+    /// 指定された名前のフィールドの位置を返す
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|&(ref field, _)| field == name)
+    }
+}
+
+/// ソースファイルのトップレベルの要素
+#[derive(Debug)]
+pub enum Item {
+    Function(Function),
+    Global(GlobalVar),
+    Struct(StructDef),
 }
 
 /// 式パーサーを表す
 #[derive(Debug)]
 pub struct Parser<'a> {
-    tokens: Vec<Token>,
+    tokens: Vec<SpannedToken>,
+    lex_errors: Vec<Diagnostic>,
     pos: usize,
-    prec: &'a mut HashMap<char, i32>,
+    ops: &'a mut OperatorTable,
 }
 
 // チェックせずにself.advanceを呼び出すためにlintを無視
 // EOFが許容される場合の結果
 #[allow(unused_must_use)]
 impl<'a> Parser<'a> {
-    /// 入力とHashMapを指定して新しいパーサーを作成する
-    /// HashMapはバイナリ式の演算子と優先度
-    pub fn new(input: String, op_precedence: &'a mut HashMap<char, i32>) -> Self {
+    /// 入力と演算子表を指定して新しいパーサーを作成する
+    /// 演算子の定義を解析すると、演算子表に追加される
+    pub fn new(input: String, operators: &'a mut OperatorTable) -> Self {
         let mut lexer = Lexer::new(input.as_str());
-        let tokens = lexer.by_ref().collect();
+        let mut tokens = Vec::new();
+        let mut lex_errors = Vec::new();
+
+        loop {
+            match lexer.lex() {
+                Ok(SpannedToken { token: EOF, .. }) => break,
+                // コメントは構文に影響しないため、トークン列から取り除く
+                Ok(SpannedToken { token: Comment, .. }) => (),
+                Ok(tok) => tokens.push(tok),
+                // 不正なトークンは読み飛ばされるため、エラーを記録して字句解析を続ける
+                Err(err) => lex_errors.push(err.into()),
+            }
+        }
 
         Parser {
             tokens: tokens,
-            prec: op_precedence,
+            lex_errors: lex_errors,
+            ops: operators,
             pos: 0,
         }
     }
 
-    /// パーサーの中身を解析
-    pub fn parse(&mut self) -> Result<Function, &'static str> {
-        let result = match self.current()? {
-            Def => self.parse_def(),
-            Extern => self.parse_extern(),
-            _ => self.parse_toplevel_expr(),
-        };
+    /// パーサーの中身を単一のトップレベル要素として解析
+    pub fn parse(&mut self) -> Result<Item, Diagnostic> {
+        if !self.lex_errors.is_empty() {
+            return Err(self.lex_errors.remove(0));
+        }
+
+        let result = self.parse_item();
 
         match result {
             Ok(result) => {
                 if !self.at_end() {
-                    Err("Unexpected token after parsed expression.")
+                    Err(
+                        Diagnostic::error("Unexpected token after parsed expression.")
+                            .with_code("E0202")
+                            .with_primary(self.span(), "expected end of input"),
+                    )
                 } else {
                     Ok(result)
                 }
@@ -105,25 +420,124 @@ impl<'a> Parser<'a> {
         }
     }
 
-    /// セーフチェックをせずに現在のトークンを返す
+    /// ソースファイル全体を解析し、解析できた全ての要素と発生した全ての診断を返す
+    /// エラーが発生した場合は次の'def'、'extern'、'global'か'struct'まで読み飛ばして解析を続ける
+    pub fn parse_program(&mut self) -> (Vec<Item>, Vec<Diagnostic>) {
+        let mut items = Vec::new();
+        let mut diagnostics = self.lex_errors.split_off(0);
+
+        while !self.at_end() {
+            let start = self.pos;
+
+            match self.parse_item() {
+                Ok(item) => items.push(item),
+                Err(err) => {
+                    diagnostics.push(err);
+                    self.synchronize(start);
+                }
+            }
+        }
+
+        (items, diagnostics)
+    }
+
+    /// エラーからの回復のため、次のトップレベル要素の開始位置まで読み飛ばす
+    /// 'start'は失敗した要素の開始位置で、少なくとも1トークンは進むことを保証する
+    fn synchronize(&mut self, start: usize) {
+        if self.pos <= start {
+            self.pos = start + 1;
+        }
+
+        while !self.at_end() {
+            match self.curr() {
+                Def | Extern | Global | Struct => return,
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    /// トップレベルの要素(関数定義、外部宣言、グローバル変数、構造体、または式)を1つ解析
+    fn parse_item(&mut self) -> Result<Item, Diagnostic> {
+        match self.current()? {
+            Def => self.parse_def().map(Item::Function),
+            Extern => self.parse_extern().map(Item::Function),
+            Global => self.parse_global().map(Item::Global),
+            Struct => self.parse_struct().map(Item::Struct),
+            _ => self.parse_toplevel_expr().map(Item::Function),
+        }
+    }
+
+    /// 現在のトークンを返す
+    /// 入力の終わりに達している場合はEOFを返すため、エラー回復中でもパニックしない
     fn curr(&self) -> Token {
-        self.tokens[self.pos].clone()
+        match self.tokens.get(self.pos) {
+            Some(tok) => tok.token.clone(),
+            None => EOF,
+        }
     }
 
     /// セーフチェックをして現在のトークン、またはエラーを返す
     /// エラーの場合はファイルの終わりに予期せずに到達したことを示す
-    fn current(&self) -> Result<Token, &'static str> {
+    fn current(&self) -> Result<Token, Diagnostic> {
         if self.pos >= self.tokens.len() {
-            Err("Unexpected end of file.")
+            Err(self.eof_error())
         } else {
-            Ok(self.tokens[self.pos].clone())
+            Ok(self.tokens[self.pos].token.clone())
+        }
+    }
+
+    /// 現在のトークンの位置を返す
+    /// 入力の終わりに達している場合は、最後のトークンの直後を指す
+    fn span(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(tok) => tok.span,
+            None => self.end_span(),
+        }
+    }
+
+    /// 直前に消費したトークンの位置を返す
+    fn prev_span(&self) -> Span {
+        if self.pos == 0 {
+            return self.span();
+        }
+
+        match self.tokens.get(self.pos - 1) {
+            Some(tok) => tok.span,
+            None => self.end_span(),
+        }
+    }
+
+    /// 入力の終わりを指す空のSpanを返す
+    fn end_span(&self) -> Span {
+        match self.tokens.last() {
+            Some(tok) => Span {
+                start: tok.span.end,
+                end: tok.span.end,
+                line: tok.span.line,
+                column: tok.span.column + (tok.span.end - tok.span.start),
+            },
+            None => Span {
+                start: 0,
+                end: 0,
+                line: 1,
+                column: 1,
+            },
+        }
+    }
+
+    /// 'start'から直前のトークンまでを覆う式を作成
+    fn spanned(&self, start: Span, kind: ExprKind) -> Expr {
+        Expr {
+            kind: kind,
+            span: start.to(self.prev_span()),
+            ty: Type::F64,
         }
     }
 
     /// ポジションを進めて、エラーか空の成功をもつ結果を返す
     /// これにより、'?'構文を使用できる
     /// エラーの場合はファイルの終わりに予期せずに到達したことを示す
-    fn advance(&mut self) -> Result<(), &'static str> {
+    fn advance(&mut self) -> Result<(), Diagnostic> {
         let npos = self.pos + 1;
 
         self.pos = npos;
@@ -131,10 +545,30 @@ impl<'a> Parser<'a> {
         if npos < self.tokens.len() {
             Ok(())
         } else {
-            Err("Unexpected end of file.")
+            Err(self.eof_error())
         }
     }
 
+    /// 現在のトークンを指す構文エラーの診断を作成
+    fn error(&self, msg: &str) -> Diagnostic {
+        let label = if self.at_end() {
+            "unexpected end of file"
+        } else {
+            "unexpected token"
+        };
+
+        Diagnostic::error(msg)
+            .with_code("E0200")
+            .with_primary(self.span(), label)
+    }
+
+    /// 予期せずに入力の終わりに到達したことを示す診断を作成
+    fn eof_error(&self) -> Diagnostic {
+        Diagnostic::error("Unexpected end of file.")
+            .with_code("E0201")
+            .with_primary(self.end_span(), "input ends here")
+    }
+
     /// 入力の終わりに達したかどうかを返す
     fn at_end(&self) -> bool {
         self.pos >= self.tokens.len()
@@ -144,14 +578,15 @@ impl<'a> Parser<'a> {
     /// バイナリ演算子でない場合は-1
     fn get_tok_precedence(&self) -> i32 {
         if let Ok(Op(op)) = self.current() {
-            *self.prec.get(&op).unwrap_or(&100)
+            self.ops.precedence(op).unwrap_or(100)
         } else {
             -1
         }
     }
 
     /// 外部、ユーザー定義に関係なく、関数のプロトタイプを解析
-    fn parse_prototype(&mut self) -> Result<Prototype, &'static str> {
+    fn parse_prototype(&mut self) -> Result<Prototype, Diagnostic> {
+        let start = self.span();
         let (id, is_operator, precedence) = match self.curr() {
             Ident(id) => {
                 self.advance()?;
@@ -164,7 +599,9 @@ impl<'a> Parser<'a> {
 
                 let op = match self.curr() {
                     Op(ch) => ch,
-                    _ => return Err("Expected operator in custom operator declaration."),
+                    _ => {
+                        return Err(self.error("Expected operator in custom operator declaration."))
+                    }
                 };
 
                 self.advance()?;
@@ -181,7 +618,7 @@ impl<'a> Parser<'a> {
                     0
                 };
 
-                self.prec.insert(op, prec as i32);
+                self.ops.define_binary(op, prec as i32);
 
                 (name, true, prec)
             }
@@ -191,50 +628,61 @@ impl<'a> Parser<'a> {
 
                 let op = match self.curr() {
                     Op(ch) => ch,
-                    _ => return Err("Expected operator in custom operator declaration."),
+                    _ => {
+                        return Err(self.error("Expected operator in custom operator declaration."))
+                    }
                 };
 
                 let mut name = String::from("unary");
 
                 name.push(op);
 
+                self.ops.define_unary(op);
                 self.advance()?;
 
                 (name, true, 0)
             }
 
-            _ => return Err("Expected identifier in prototype declaration."),
+            _ => return Err(self.error("Expected identifier in prototype declaration.")),
         };
 
         match self.curr() {
             LParen => (),
-            _ => return Err("Expected '(' character in prototype declaration."),
+            _ => return Err(self.error("Expected '(' character in prototype declaration.")),
         }
 
         self.advance()?;
 
+        let mut args = vec![];
+        let mut arg_types = vec![];
+
         if let RParen = self.curr() {
             self.advance();
 
+            let ret_type = self.parse_return_type()?;
+
             return Ok(Prototype {
                 name: id,
-                args: vec![],
+                args: args,
+                arg_types: arg_types,
+                ret_type: ret_type,
                 is_op: is_operator,
                 prec: precedence,
+                span: start.to(self.prev_span()),
             });
         }
 
-        let mut args = vec![];
-
         // パラメータ宣言
         loop {
             match self.curr() {
                 Ident(name) => args.push(name),
-                _ => return Err("Expected identifier in parameter declaration."),
+                _ => return Err(self.error("Expected identifier in parameter declaration.")),
             }
 
             self.advance()?;
 
+            arg_types.push(self.parse_type_annotation()?.unwrap_or(Type::F64));
+
             match self.curr() {
                 RParen => {
                     self.advance();
@@ -244,20 +692,115 @@ impl<'a> Parser<'a> {
                 Comma => {
                     self.advance();
                 }
-                _ => return Err("Expected ',' or ')' character in prototype declaration."),
+                _ => {
+                    return Err(
+                        self.error("Expected ',' or ')' character in prototype declaration.")
+                    )
+                }
             }
         }
 
+        let ret_type = self.parse_return_type()?;
+
         Ok(Prototype {
             name: id,
             args: args,
+            arg_types: arg_types,
+            ret_type: ret_type,
             is_op: is_operator,
             prec: precedence,
+            span: start.to(self.prev_span()),
         })
     }
 
+    /// 型の名前を解析
+    /// 組み込みの型でない名前は構造体の名前として扱う
+    fn parse_type(&mut self) -> Result<Type, Diagnostic> {
+        let name = match self.current()? {
+            Ident(name) => name,
+            _ => {
+                return Err(self.error(
+                    "Expected type name ('f64', 'i64', 'i32', 'bool', 'str', 'array', 'fn(...)' or a struct name).",
+                ))
+            }
+        };
+
+        // 'extern f() -> f64'のように、型名が入力の最後のトークンとなる場合がある
+        self.advance();
+
+        if let ("fn", LParen) = (name.as_str(), self.curr()) {
+            return self.parse_fn_type();
+        }
+
+        Ok(Type::from_name(&name).unwrap_or(Type::Struct(name)))
+    }
+
+    /// 'fn'に続く関数値の型'(type, ...) -> type'を解析
+    /// 戻り値の型が省略された場合はf64となる
+    fn parse_fn_type(&mut self) -> Result<Type, Diagnostic> {
+        // eat '(' token
+        self.advance()?;
+
+        let mut args = vec![];
+
+        loop {
+            if let RParen = self.curr() {
+                break;
+            }
+
+            if !args.is_empty() {
+                match self.curr() {
+                    Comma => self.advance()?,
+                    _ => return Err(self.error("Expected ',' or ')' character in function type.")),
+                }
+            }
+
+            args.push(self.parse_type()?);
+        }
+
+        // eat ')' token, which may be the last one
+        self.advance();
+
+        let ret_type = self.parse_return_type()?;
+
+        Ok(Type::Fn(args, Box::new(ret_type)))
+    }
+
+    /// (optional) 型注釈': type'を解析
+    fn parse_type_annotation(&mut self) -> Result<Option<Type>, Diagnostic> {
+        match self.curr() {
+            Op(':') => {
+                self.advance()?;
+
+                self.parse_type().map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// プロトタイプに続く(optional) 戻り値の型'-> type'を解析
+    /// 省略された場合はf64となる
+    fn parse_return_type(&mut self) -> Result<Type, Diagnostic> {
+        let is_arrow = match (self.curr(), self.tokens.get(self.pos + 1)) {
+            (Op('-'), Some(&SpannedToken { token: Op('>'), .. })) => true,
+            _ => false,
+        };
+
+        if !is_arrow {
+            return Ok(Type::F64);
+        }
+
+        // eat '->' tokens
+        self.advance()?;
+        self.advance()?;
+
+        self.parse_type()
+    }
+
     /// ユーザー定義関数を解析
-    fn parse_def(&mut self) -> Result<Function, &'static str> {
+    fn parse_def(&mut self) -> Result<Function, Diagnostic> {
+        let start = self.span();
+
         // 最初の"Def"キーワードは解析せずにすすむ
         self.pos += 1;
 
@@ -271,12 +814,15 @@ impl<'a> Parser<'a> {
             prototype: proto,
             body: Some(body),
             is_anon: false,
+            span: start.to(self.prev_span()),
         })
     }
 
     /// 外部宣言関数の解析
-    fn parse_extern(&mut self) -> Result<Function, &'static str> {
-        // 最初の"Def"キーワードは解析せずにすすむ
+    fn parse_extern(&mut self) -> Result<Function, Diagnostic> {
+        let start = self.span();
+
+        // 最初の"Extern"キーワードは解析せずにすすむ
         self.pos += 1;
 
         // 関数のシグネチャを解析
@@ -286,11 +832,89 @@ impl<'a> Parser<'a> {
             prototype: proto,
             body: None,
             is_anon: false,
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    /// グローバル変数の宣言'global name = expr'を解析
+    fn parse_global(&mut self) -> Result<GlobalVar, Diagnostic> {
+        let start = self.span();
+
+        // eat 'global' token
+        self.advance()?;
+
+        let name = match self.curr() {
+            Ident(name) => name,
+            _ => return Err(self.error("Expected identifier in global declaration.")),
+        };
+
+        self.advance()?;
+
+        match self.curr() {
+            Op('=') => self.advance()?,
+            _ => return Err(self.error("Expected '=' character in global declaration.")),
+        }
+
+        let init = self.parse_expr()?;
+
+        Ok(GlobalVar {
+            name: name,
+            init: init,
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    /// 構造体の宣言'struct Name { field: type, ... }'を解析
+    fn parse_struct(&mut self) -> Result<StructDef, Diagnostic> {
+        let start = self.span();
+
+        // eat 'struct' token
+        self.advance()?;
+
+        let name = match self.curr() {
+            Ident(name) => name,
+            _ => return Err(self.error("Expected identifier in struct declaration.")),
+        };
+
+        self.advance()?;
+
+        match self.curr() {
+            LBrace => self.advance()?,
+            _ => return Err(self.error("Expected '{' character in struct declaration.")),
+        }
+
+        let mut fields = Vec::new();
+
+        loop {
+            let field = match self.curr() {
+                Ident(field) => field,
+                _ => return Err(self.error("Expected field name in struct declaration.")),
+            };
+
+            self.advance()?;
+
+            let field_type = self.parse_type_annotation()?.unwrap_or(Type::F64);
+
+            fields.push((field, field_type));
+
+            match self.curr() {
+                Comma => self.advance()?,
+                RBrace => break,
+                _ => return Err(self.error("Expected ',' or '}' character in struct declaration.")),
+            }
+        }
+
+        self.advance();
+
+        Ok(StructDef {
+            name: name,
+            fields: fields,
+            span: start.to(self.prev_span()),
         })
     }
 
     /// 式の解析
-    fn parse_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_expr(&mut self) -> Result<Expr, Diagnostic> {
         match self.parse_unary_expr() {
             Ok(left) => self.parse_binary_expr(0, left),
             err => err,
@@ -298,22 +922,45 @@ impl<'a> Parser<'a> {
     }
 
     /// リテラルナンバーの式の解析
-    fn parse_nb_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_nb_expr(&mut self) -> Result<Expr, Diagnostic> {
         // NumberをExpr::Numberに変換する
         match self.curr() {
             Number(nb) => {
+                let start = self.span();
+
+                self.advance();
+
+                Ok(self.spanned(start, ExprKind::Number(nb)))
+            }
+            _ => Err(self.error("Expected number literal.")),
+        }
+    }
+
+    /// 文字列リテラルの式の解析
+    fn parse_str_expr(&mut self) -> Result<Expr, Diagnostic> {
+        match self.curr() {
+            Str(value) => {
+                let start = self.span();
+
                 self.advance();
-                Ok(Expr::Number(nb))
+
+                Ok(self.spanned(start, ExprKind::Str(value)))
             }
-            _ => Err("Expected number literal."),
+            _ => Err(self.error("Expected string literal.")),
         }
     }
 
     /// parenで囲まれた式の解析
-    fn parse_paren_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_paren_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
         match self.current()? {
             LParen => (),
-            _ => return Err("Expected '(' character at start of parenthesized expression."),
+            _ => {
+                return Err(
+                    self.error("Expected '(' character at start of parenthesized expression.")
+                )
+            }
         }
 
         self.advance()?;
@@ -322,37 +969,54 @@ impl<'a> Parser<'a> {
 
         match self.current()? {
             RParen => (),
-            _ => return Err("Expected ')' character at end of parenthesized expression."),
+            _ => {
+                return Err(self.error("Expected ')' character at end of parenthesized expression."))
+            }
         }
 
         self.advance();
 
-        Ok(expr)
+        // カッコも含めた範囲を式の位置とする
+        Ok(Expr {
+            kind: expr.kind,
+            span: start.to(self.prev_span()),
+            ty: expr.ty,
+        })
     }
 
     /// 識別子(変数か関数呼び出し)で始まる式の解析
-    fn parse_id_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_id_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
         let id = match self.curr() {
             Ident(id) => id,
-            _ => return Err("Expected identifier."),
+            _ => return Err(self.error("Expected identifier.")),
         };
 
         // 後に続くものがなかった場合は変数
         if self.advance().is_err() {
-            return Ok(Expr::Variable(id));
+            return Ok(self.spanned(start, ExprKind::Variable(id)));
         }
 
         // それ以外は関数のため、LParenが続く
+        // 'field:'か'}'が後に続くLBraceの場合は構造体の作成で、それ以外の'{'はブロックの始まり
         match self.curr() {
+            LBrace if self.at_construct() => self.parse_construct_expr(start, id),
+
             LParen => {
                 self.advance()?;
 
                 // 引数なし
                 if let RParen = self.curr() {
-                    return Ok(Expr::Call {
-                        fn_name: id,
-                        args: vec![],
-                    });
+                    self.advance();
+
+                    return Ok(self.spanned(
+                        start,
+                        ExprKind::Call {
+                            fn_name: id,
+                            args: vec![],
+                            local: false,
+                        },
+                    ));
                 }
 
                 // RParenが続かない場合は引数を確保していく
@@ -365,7 +1029,7 @@ impl<'a> Parser<'a> {
                     match self.current()? {
                         Comma => (),
                         RParen => break,
-                        _ => return Err("Expected ',' character in function call."),
+                        _ => return Err(self.error("Expected ',' character in function call.")),
                     }
 
                     self.advance()?;
@@ -373,18 +1037,135 @@ impl<'a> Parser<'a> {
 
                 self.advance();
 
-                Ok(Expr::Call {
-                    fn_name: id,
-                    args: args,
-                })
+                Ok(self.spanned(
+                    start,
+                    ExprKind::Call {
+                        fn_name: id,
+                        args: args,
+                        local: false,
+                    },
+                ))
             }
 
-            _ => Ok(Expr::Variable(id)),
+            _ => Ok(self.spanned(start, ExprKind::Variable(id))),
+        }
+    }
+
+    /// 現在の'{'が構造体の作成の始まりであるかを返す
+    fn at_construct(&self) -> bool {
+        let next = |offset: usize| self.tokens.get(self.pos + offset).map(|tok| &tok.token);
+
+        match (next(1), next(2)) {
+            (Some(RBrace), _) | (Some(Ident(_)), Some(Op(':'))) => true,
+            _ => false,
         }
     }
 
+    /// 構造体名に続く'{ field: value, ... }'を解析し、構造体の作成式を返す
+    fn parse_construct_expr(&mut self, start: Span, name: String) -> Result<Expr, Diagnostic> {
+        // eat '{' token
+        self.advance()?;
+
+        let mut fields = vec![];
+
+        if let RBrace = self.current()? {
+            self.advance();
+
+            return Ok(self.spanned(
+                start,
+                ExprKind::Construct {
+                    name: name,
+                    fields: fields,
+                },
+            ));
+        }
+
+        loop {
+            let field = match self.curr() {
+                Ident(field) => field,
+                _ => return Err(self.error("Expected field name in struct expression.")),
+            };
+
+            self.advance()?;
+
+            match self.curr() {
+                Op(':') => self.advance()?,
+                _ => return Err(self.error("Expected ':' character after field name.")),
+            }
+
+            fields.push((field, self.parse_expr()?));
+
+            match self.current()? {
+                Comma => self.advance()?,
+                RBrace => break,
+                _ => return Err(self.error("Expected ',' or '}' character in struct expression.")),
+            }
+        }
+
+        self.advance();
+
+        Ok(self.spanned(
+            start,
+            ExprKind::Construct {
+                name: name,
+                fields: fields,
+            },
+        ))
+    }
+
+    /// 無名関数'\x, y: type -> body'の解析
+    /// 本体はできるだけ右まで続く式となる
+    fn parse_lambda_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat '\' token
+        self.advance()?;
+
+        let mut params = vec![];
+
+        loop {
+            if let (Op('-'), Some(&SpannedToken { token: Op('>'), .. })) =
+                (self.curr(), self.tokens.get(self.pos + 1))
+            {
+                break;
+            }
+
+            if !params.is_empty() {
+                match self.curr() {
+                    Comma => self.advance()?,
+                    _ => return Err(self.error("Expected ',' or '->' in lambda expression.")),
+                }
+            }
+
+            let name = match self.curr() {
+                Ident(name) => name,
+                _ => return Err(self.error("Expected parameter name in lambda expression.")),
+            };
+
+            self.advance()?;
+
+            params.push((name, self.parse_type_annotation()?));
+        }
+
+        // eat '->' tokens
+        self.advance()?;
+        self.advance()?;
+
+        let body = self.parse_expr()?;
+
+        Ok(self.spanned(
+            start,
+            ExprKind::Lambda {
+                params: params,
+                body: Box::new(body),
+                captures: vec![],
+            },
+        ))
+    }
+
     /// 単項式の解析
-    fn parse_unary_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_unary_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
         let op = match self.current()? {
             Op(ch) => {
                 self.advance()?;
@@ -397,14 +1178,20 @@ impl<'a> Parser<'a> {
 
         name.push(op);
 
-        Ok(Expr::Call {
-            fn_name: name,
-            args: vec![self.parse_unary_expr()?],
-        })
+        let operand = self.parse_unary_expr()?;
+
+        Ok(self.spanned(
+            start,
+            ExprKind::Call {
+                fn_name: name,
+                args: vec![operand],
+                local: false,
+            },
+        ))
     }
 
     /// 左の式を指定して、バイナリ式を解析
-    fn parse_binary_expr(&mut self, prec: i32, mut left: Expr) -> Result<Expr, &'static str> {
+    fn parse_binary_expr(&mut self, prec: i32, mut left: Expr) -> Result<Expr, Diagnostic> {
         loop {
             let curr_prec = self.get_tok_precedence();
 
@@ -414,7 +1201,7 @@ impl<'a> Parser<'a> {
 
             let op = match self.curr() {
                 Op(op) => op,
-                _ => return Err("Invalid operator."),
+                _ => return Err(self.error("Invalid operator.")),
             };
 
             self.advance()?;
@@ -427,16 +1214,24 @@ impl<'a> Parser<'a> {
                 right = self.parse_binary_expr(curr_prec + 1, right)?;
             }
 
-            left = Expr::Binary {
-                op: op,
-                left: Box::new(left),
-                right: Box::new(right),
+            let span = left.span.to(right.span);
+
+            left = Expr {
+                kind: ExprKind::Binary {
+                    op: op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                span: span,
+                ty: Type::F64,
             };
         }
     }
 
     /// conditional if..then..else式を解析
-    fn parse_conditional_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_conditional_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
         // eat 'if' token
         self.advance()?;
 
@@ -445,7 +1240,7 @@ impl<'a> Parser<'a> {
         // eat 'then' token
         match self.current() {
             Ok(Then) => self.advance()?,
-            _ => return Err("Expected 'then' keyword."),
+            _ => return Err(self.error("Expected 'then' keyword.")),
         }
 
         let then_result = self.parse_expr()?;
@@ -453,35 +1248,42 @@ impl<'a> Parser<'a> {
         // eat 'else' token
         match self.current() {
             Ok(Else) => self.advance()?,
-            _ => return Err("Expected 'else' keyword."),
+            _ => return Err(self.error("Expected 'else' keyword.")),
         }
 
         let else_result = self.parse_expr()?;
 
-        Ok(Expr::Conditional {
-            cond: Box::new(cond),
-            consequence: Box::new(then_result),
-            alternative: Box::new(else_result),
-        })
+        Ok(self.spanned(
+            start,
+            ExprKind::Conditional {
+                cond: Box::new(cond),
+                consequence: Box::new(then_result),
+                alternative: Box::new(else_result),
+            },
+        ))
     }
 
     /// forループ式の解析
-    fn parse_for_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_for_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let for_start = self.span();
+
         // eat 'for' token
         self.advance()?;
 
         let name = match self.curr() {
             Ident(n) => n,
-            _ => return Err("Expected identifier in for loop."),
+            _ => return Err(self.error("Expected identifier in for loop.")),
         };
 
         // eat identifier
         self.advance()?;
 
+        let var_type = self.parse_type_annotation()?;
+
         // eat '=' token
         match self.curr() {
             Op('=') => self.advance()?,
-            _ => return Err("Expected '=' character in for loop."),
+            _ => return Err(self.error("Expected '=' character in for loop.")),
         }
 
         let start = self.parse_expr()?;
@@ -489,7 +1291,7 @@ impl<'a> Parser<'a> {
         // eat ',' token
         match self.current()? {
             Comma => self.advance()?,
-            _ => return Err("Expected ',' character in for loop."),
+            _ => return Err(self.error("Expected ',' character in for loop.")),
         }
 
         let end = self.parse_expr()?;
@@ -508,22 +1310,111 @@ impl<'a> Parser<'a> {
         // eat 'in' token
         match self.current()? {
             In => self.advance()?,
-            _ => return Err("Expected 'in' keyword in for loop."),
+            _ => return Err(self.error("Expected 'in' keyword in for loop.")),
         }
 
         let body = self.parse_expr()?;
 
-        Ok(Expr::For {
-            var_name: name,
-            start: Box::new(start),
-            end: Box::new(end),
-            step: step.map(Box::new),
-            body: Box::new(body),
-        })
+        Ok(self.spanned(
+            for_start,
+            ExprKind::For {
+                var_name: name,
+                var_type: var_type,
+                start: Box::new(start),
+                end: Box::new(end),
+                step: step.map(Box::new),
+                body: Box::new(body),
+            },
+        ))
+    }
+
+    /// 'while cond do body'ループの解析
+    fn parse_while_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat 'while' token
+        self.advance()?;
+
+        let cond = self.parse_expr()?;
+
+        // eat 'do' token
+        match self.current()? {
+            Do => self.advance()?,
+            _ => return Err(self.error("Expected 'do' keyword in while loop.")),
+        }
+
+        let body = self.parse_expr()?;
+
+        Ok(self.spanned(
+            start,
+            ExprKind::While {
+                cond: Box::new(cond),
+                body: Box::new(body),
+            },
+        ))
+    }
+
+    /// 'break'と、(optional) ループの値となる式の解析
+    /// 値は'break'と同じ行に続けて書く
+    fn parse_break_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat 'break' token
+        self.advance();
+
+        let has_value = match self.curr() {
+            Ident(_) | Number(_) | Str(_) | LParen | LBracket | LBrace | If | For | Var | While
+            | Lambda | Break | Continue | Return => self.span().line == start.line,
+            _ => false,
+        };
+
+        let value = if has_value {
+            Some(Box::new(self.parse_expr()?))
+        } else {
+            None
+        };
+
+        Ok(self.spanned(start, ExprKind::Break(value)))
+    }
+
+    /// 'return expr'の解析
+    fn parse_return_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat 'return' token
+        self.advance()?;
+
+        let value = self.parse_expr()?;
+
+        Ok(self.spanned(start, ExprKind::Return(Box::new(value))))
     }
 
     /// var..in式の解析
-    fn parse_var_expr(&mut self) -> Result<Expr, &'static str> {
+    fn parse_var_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+        let variables = self.parse_var_bindings()?;
+
+        match self.curr() {
+            In => self.advance()?,
+            _ => return Err(self.error("Expected comma or 'in' keyword in variable declaration.")),
+        }
+
+        // parse body
+        let body = self.parse_expr()?;
+
+        Ok(self.spanned(
+            start,
+            ExprKind::VarIn {
+                variables: variables,
+                body: Box::new(body),
+            },
+        ))
+    }
+
+    /// 'var'に続く、カンマで区切られた変数の宣言の解析
+    fn parse_var_bindings(
+        &mut self,
+    ) -> Result<Vec<(String, Option<Type>, Option<Expr>)>, Diagnostic> {
         // eat 'var' token
         self.advance()?;
 
@@ -533,11 +1424,13 @@ impl<'a> Parser<'a> {
         loop {
             let name = match self.curr() {
                 Ident(name) => name,
-                _ => return Err("Expected identifier in 'var..in' declaration."),
+                _ => return Err(self.error("Expected identifier in 'var..in' declaration.")),
             };
 
             self.advance()?;
 
+            let var_type = self.parse_type_annotation()?;
+
             // read (optional) initializer
             let initializer = match self.curr() {
                 Op('=') => Some({
@@ -548,53 +1441,210 @@ impl<'a> Parser<'a> {
                 _ => None,
             };
 
-            variables.push((name, initializer));
+            variables.push((name, var_type, initializer));
 
             match self.curr() {
-                Comma => {
-                    self.advance()?;
-                }
-                In => {
+                Comma => self.advance()?,
+                _ => return Ok(variables),
+            }
+        }
+    }
+
+    /// ブロック'{ e1; e2; ... }'の解析
+    fn parse_block_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat '{' token
+        self.advance()?;
+
+        self.parse_block_rest(start)
+    }
+
+    /// ブロックの残りの式を'}'まで解析
+    /// 'in'のない'var'の宣言は、ブロックの残りをスコープとするvar..in式になる
+    fn parse_block_rest(&mut self, start: Span) -> Result<Expr, Diagnostic> {
+        let mut exprs = vec![];
+
+        loop {
+            if let RBrace = self.current()? {
+                break;
+            }
+
+            if let Var = self.curr() {
+                let var_start = self.span();
+                let variables = self.parse_var_bindings()?;
+
+                if let In = self.curr() {
                     self.advance()?;
-                    break;
+
+                    let body = self.parse_expr()?;
+
+                    exprs.push(self.spanned(
+                        var_start,
+                        ExprKind::VarIn {
+                            variables: variables,
+                            body: Box::new(body),
+                        },
+                    ));
+                } else {
+                    if let Semicolon = self.current()? {
+                        self.advance()?;
+                    }
+
+                    let rest_start = self.span();
+                    let body = self.parse_block_rest(rest_start)?;
+
+                    exprs.push(self.spanned(
+                        var_start,
+                        ExprKind::VarIn {
+                            variables: variables,
+                            body: Box::new(body),
+                        },
+                    ));
+
+                    return Ok(self.spanned(start, ExprKind::Block(exprs)));
                 }
-                _ => return Err("Expected comma or 'in' keyword in variable declaration."),
+            } else {
+                exprs.push(self.parse_expr()?);
+            }
+
+            // 最後の式の後の';'は省略できる
+            match self.current()? {
+                Semicolon => self.advance()?,
+                RBrace => break,
+                _ => return Err(self.error("Expected ';' or '}' character in block.")),
             }
         }
 
-        // parse body
-        let body = self.parse_expr()?;
+        // eat '}' token
+        self.advance();
 
-        Ok(Expr::VarIn {
-            variables: variables,
-            body: Box::new(body),
-        })
+        Ok(self.spanned(start, ExprKind::Block(exprs)))
     }
 
-    /// プライマリ式(識別子、数値、またはカッコで囲まれた式)の解析
-    fn parse_primary(&mut self) -> Result<Expr, &'static str> {
-        match self.curr() {
+    /// 配列リテラル'[e1, e2, ...]'の解析
+    fn parse_array_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+
+        // eat '[' token
+        self.advance()?;
+
+        let mut elements = vec![];
+
+        if let RBracket = self.current()? {
+            self.advance();
+
+            return Ok(self.spanned(start, ExprKind::Array(elements)));
+        }
+
+        loop {
+            elements.push(self.parse_expr()?);
+
+            match self.current()? {
+                Comma => self.advance()?,
+                RBracket => break,
+                _ => return Err(self.error("Expected ',' or ']' character in array literal.")),
+            }
+        }
+
+        self.advance();
+
+        Ok(self.spanned(start, ExprKind::Array(elements)))
+    }
+
+    /// プライマリ式(識別子、数値、文字列、配列、ブロック、またはカッコで囲まれた式)の解析
+    /// 空白を挟まずに続く添字'[index]'と、フィールドアクセス'.field'もここで解析する
+    fn parse_primary(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.span();
+        let mut expr = match self.curr() {
             Ident(_) => self.parse_id_expr(),
             Number(_) => self.parse_nb_expr(),
+            Str(_) => self.parse_str_expr(),
             LParen => self.parse_paren_expr(),
+            LBracket => self.parse_array_expr(),
             If => self.parse_conditional_expr(),
             For => self.parse_for_expr(),
             Var => self.parse_var_expr(),
-            _ => Err("Unknown expression."),
+            Lambda => self.parse_lambda_expr(),
+            LBrace => self.parse_block_expr(),
+            While => self.parse_while_expr(),
+            Break => self.parse_break_expr(),
+            Return => self.parse_return_expr(),
+            Continue => {
+                self.advance();
+
+                Ok(self.spanned(start, ExprKind::Continue))
+            }
+            _ => Err(self.error("Unknown expression.")),
+        }?;
+
+        loop {
+            match self.curr() {
+                // 次の行から始まる配列リテラルと区別するため、添字は直前のトークンに続けて書く
+                LBracket if self.span().start == self.prev_span().end => (),
+
+                Dot => {
+                    self.advance()?;
+
+                    let field = match self.curr() {
+                        Ident(field) => field,
+                        _ => return Err(self.error("Expected field name after '.'.")),
+                    };
+
+                    self.advance();
+
+                    expr = self.spanned(
+                        start,
+                        ExprKind::Field {
+                            value: Box::new(expr),
+                            field: field,
+                            index: 0,
+                        },
+                    );
+
+                    continue;
+                }
+
+                _ => break,
+            }
+
+            self.advance()?;
+
+            let index = self.parse_expr()?;
+
+            match self.current()? {
+                RBracket => self.advance(),
+                _ => return Err(self.error("Expected ']' character after array index.")),
+            };
+
+            expr = self.spanned(
+                start,
+                ExprKind::Index {
+                    array: Box::new(expr),
+                    index: Box::new(index),
+                },
+            );
         }
+
+        Ok(expr)
     }
 
     /// トップレベルの式を解析し、匿名関数を作成する。
     /// コンパイルを容易にするために存在する
-    fn parse_toplevel_expr(&mut self) -> Result<Function, &'static str> {
+    fn parse_toplevel_expr(&mut self) -> Result<Function, Diagnostic> {
         match self.parse_expr() {
             Ok(expr) => Ok(Function {
                 prototype: Prototype {
                     name: ANONYMOUS_FUNCTION_NAME.to_string(),
                     args: vec![],
+                    // 匿名関数の戻り値の型は、型検査で本体の型から推論される
+                    arg_types: vec![],
+                    ret_type: Type::F64,
                     is_op: false,
                     prec: 0,
+                    span: expr.span,
                 },
+                span: expr.span,
                 body: Some(expr),
                 is_anon: true,
             }),
@@ -603,3 +1653,56 @@ impl<'a> Parser<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 入力全体を解析し、要素と診断を返す
+    fn parse(text: &str) -> (Vec<Item>, Vec<Diagnostic>) {
+        let mut operators = OperatorTable::new();
+
+        Parser::new(text.to_string(), &mut operators).parse_program()
+    }
+
+    /// 入力の最後にある戻り値の型
+    #[test]
+    fn trailing_return_type() {
+        let cases = vec![
+            ("extern sqrt(x: f64) -> f64", Type::F64),
+            ("extern count(xs: array) -> i64", Type::I64),
+            ("extern point() -> Point", Type::Struct("Point".to_string())),
+            (
+                "extern adder(k) -> fn(f64)",
+                Type::Fn(vec![Type::F64], Box::new(Type::F64)),
+            ),
+            (
+                "extern compose() -> fn(fn(i64) -> i64) -> bool",
+                Type::Fn(
+                    vec![Type::Fn(vec![Type::I64], Box::new(Type::I64))],
+                    Box::new(Type::Bool),
+                ),
+            ),
+        ];
+
+        for (text, expected) in cases {
+            let (items, diagnostics) = parse(text);
+
+            assert!(diagnostics.is_empty(), "{}: {:?}", text, diagnostics);
+
+            match items.as_slice() {
+                [Item::Function(fun)] => assert_eq!(fun.prototype.ret_type, expected, "{}", text),
+                _ => panic!("{}: expected a single function, found {:?}", text, items),
+            }
+        }
+    }
+
+    /// 型名の前で入力が終わる場合は、終端のエラーとなる
+    #[test]
+    fn missing_return_type() {
+        let (_, diagnostics) = parse("extern sqrt(x: f64) ->");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Unexpected end of file.");
+    }
+}
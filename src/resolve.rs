@@ -0,0 +1,465 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::*;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// コード生成が直接扱う組み込みの二項演算子
+const BUILTIN_BINARY_OPERATORS: [char; 7] = ['=', '<', '>', '+', '-', '*', '/'];
+
+/// 名前解決で参照される、トップレベルで定義された関数、グローバル変数と構造体
+#[derive(Debug, Default)]
+pub struct Symbols {
+    /// 関数名と、その引数の数
+    functions: HashMap<String, usize>,
+    globals: HashSet<String>,
+    structs: HashSet<String>,
+}
+
+impl Symbols {
+    /// 空の表を作成
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// 関数のプロトタイプを追加
+    pub fn add_function(&mut self, proto: &Prototype) {
+        self.functions.insert(proto.name.clone(), proto.args.len());
+    }
+
+    /// グローバル変数を追加
+    pub fn add_global(&mut self, name: &str) {
+        self.globals.insert(name.to_string());
+    }
+
+    /// 構造体を追加
+    pub fn add_struct(&mut self, name: &str) {
+        self.structs.insert(name.to_string());
+    }
+
+    /// トップレベルの要素が定義する名前を追加
+    /// 匿名関数は呼び出されることがないため追加しない
+    pub fn add_item(&mut self, item: &Item) {
+        match *item {
+            Item::Function(ref fun) if !fun.is_anon => self.add_function(&fun.prototype),
+            Item::Function(_) => (),
+            Item::Global(ref global) => self.add_global(&global.name),
+            Item::Struct(ref def) => self.add_struct(&def.name),
+        }
+    }
+}
+
+/// 名前解決を行うパスの定義
+/// 変数、関数呼び出し、演算子を定義と結びつけ、見つかった全ての問題を診断として集める
+pub struct Resolver<'a> {
+    symbols: &'a Symbols,
+    /// 現在のスコープで見えるローカル変数
+    /// 内側の束縛ほど後ろに積まれ、スコープを抜けると取り除かれる
+    locals: Vec<String>,
+    /// 現在の位置を囲むループの数
+    loop_depth: usize,
+    /// 'return'で抜けられる関数の中にいるかどうか
+    in_function: bool,
+    diagnostics: Vec<Diagnostic>,
+    /// トップレベルの関数に解決された変数と呼び出しの、関数名と位置
+    function_refs: Vec<(String, Span)>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(symbols: &'a Symbols) -> Resolver<'a> {
+        Resolver {
+            symbols: symbols,
+            locals: Vec::new(),
+            loop_depth: 0,
+            in_function: false,
+            diagnostics: Vec::new(),
+            function_refs: Vec::new(),
+        }
+    }
+
+    /// トップレベルの要素の名前を解決し、発生した全ての診断を返す
+    pub fn resolve_item(symbols: &'a Symbols, item: &Item) -> Vec<Diagnostic> {
+        let mut resolver = Resolver::new(symbols);
+
+        resolver.resolve_top_level(item);
+        resolver.diagnostics
+    }
+
+    /// 要素の中で、トップレベルの関数'name'に解決される変数と呼び出しの位置を返す
+    /// 同じ名前のローカル変数を参照する式は含まれない
+    pub fn function_references(symbols: &'a Symbols, item: &Item, name: &str) -> Vec<Span> {
+        let mut resolver = Resolver::new(symbols);
+
+        resolver.resolve_top_level(item);
+        resolver
+            .function_refs
+            .into_iter()
+            .filter(|&(ref fn_name, _)| fn_name == name)
+            .map(|(_, span)| span)
+            .collect()
+    }
+
+    /// トップレベルの要素に含まれる全ての名前を解決
+    fn resolve_top_level(&mut self, item: &Item) {
+        match *item {
+            Item::Function(ref fun) => {
+                let proto = &fun.prototype;
+
+                for ty in proto.arg_types.iter().chain(Some(&proto.ret_type)) {
+                    self.resolve_type(ty, proto.span);
+                }
+
+                if let Some(ref body) = fun.body {
+                    self.in_function = true;
+                    self.locals.extend(fun.prototype.args.iter().cloned());
+                    self.resolve(body);
+                }
+            }
+            Item::Global(ref global) => self.resolve(&global.init),
+            Item::Struct(ref def) => {
+                for &(_, ref ty) in &def.fields {
+                    self.resolve_type(ty, def.span);
+                }
+            }
+        }
+    }
+
+    /// 名前がローカル変数として定義されているかを返す
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|local| local == name)
+    }
+
+    /// 変数が現在のスコープ、またはグローバル変数として定義されているかを返す
+    /// 関数の名前は関数値として参照できる
+    fn is_defined(&self, name: &str) -> bool {
+        self.is_local(name)
+            || self.symbols.globals.contains(name)
+            || self.symbols.functions.contains_key(name)
+    }
+
+    /// 変数の参照を解決
+    /// 型検査と同じく、ローカル変数、グローバル変数、関数値の順に探す
+    fn resolve_variable(&mut self, name: &str, span: Span) {
+        if !self.is_local(name)
+            && !self.symbols.globals.contains(name)
+            && self.symbols.functions.contains_key(name)
+        {
+            self.function_refs.push((name.to_string(), span));
+        }
+
+        if !self.is_defined(name) {
+            self.diagnostics.push(
+                Diagnostic::error("Could not find a matching variable.")
+                    .with_code("E0300")
+                    .with_primary(span, "not found in this scope"),
+            );
+        }
+    }
+
+    /// 型注釈に書かれた構造体の名前を解決
+    fn resolve_type(&mut self, ty: &Type, span: Span) {
+        match *ty {
+            Type::Struct(ref name) => self.resolve_struct(name, span),
+            Type::Fn(ref args, ref ret) => {
+                for ty in args.iter().chain(Some(&**ret)) {
+                    self.resolve_type(ty, span);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// 構造体の名前を解決
+    fn resolve_struct(&mut self, name: &str, span: Span) {
+        if !self.symbols.structs.contains(name) {
+            self.diagnostics.push(
+                Diagnostic::error(format!("Unknown type '{}'.", name))
+                    .with_code("E0308")
+                    .with_primary(span, "no struct with this name")
+                    .with_note("declare it with 'struct Name { field, ... }'"),
+            );
+        }
+    }
+
+    /// 関数呼び出しを解決し、引数の数を検査する
+    fn resolve_call(&mut self, fn_name: &str, arg_count: usize, span: Span) {
+        // 組み込み関数はユーザー定義の関数より優先される
+        let arity =
+            intrinsic_arity(fn_name).or_else(|| self.symbols.functions.get(fn_name).cloned());
+
+        if intrinsic_arity(fn_name).is_none() && self.symbols.functions.contains_key(fn_name) {
+            self.function_refs.push((fn_name.to_string(), span));
+        }
+
+        match arity.as_ref() {
+            Some(&arity) if arity == arg_count => (),
+
+            Some(&arity) => self.diagnostics.push(
+                Diagnostic::error(format!(
+                    "Function '{}' takes {} argument(s) but {} were supplied.",
+                    fn_name, arity, arg_count
+                ))
+                .with_code("E0307")
+                .with_primary(span, "wrong number of arguments"),
+            ),
+
+            None => self.diagnostics.push(unknown_function(fn_name, span)),
+        }
+    }
+
+    /// 'break'か'continue'がループの中にあることを検査する
+    fn resolve_loop_jump(&mut self, keyword: &str, span: Span) {
+        if self.loop_depth == 0 {
+            self.diagnostics.push(
+                Diagnostic::error(format!("'{}' outside of a loop.", keyword))
+                    .with_code("E0309")
+                    .with_primary(span, "cannot be used outside of a loop"),
+            );
+        }
+    }
+
+    /// 式に含まれる全ての名前を解決
+    fn resolve(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Number(_) | ExprKind::Str(_) => (),
+
+            ExprKind::Array(ref elements) => {
+                for element in elements {
+                    self.resolve(element);
+                }
+            }
+
+            ExprKind::Index {
+                ref array,
+                ref index,
+            } => {
+                self.resolve(array);
+                self.resolve(index);
+            }
+
+            ExprKind::Construct {
+                ref name,
+                ref fields,
+            } => {
+                self.resolve_struct(name, expr.span);
+
+                for &(_, ref value) in fields {
+                    self.resolve(value);
+                }
+            }
+
+            ExprKind::Field { ref value, .. } => self.resolve(value),
+
+            ExprKind::Block(ref exprs) => {
+                for expr in exprs {
+                    self.resolve(expr);
+                }
+            }
+
+            ExprKind::Variable(ref name) => self.resolve_variable(name, expr.span),
+
+            ExprKind::Binary {
+                op,
+                ref left,
+                ref right,
+            } => {
+                if op == '=' {
+                    match left.kind {
+                        ExprKind::Variable(ref name) => self.resolve_variable(name, left.span),
+                        ExprKind::Index { .. } | ExprKind::Field { .. } => self.resolve(left),
+                        _ => self.diagnostics.push(
+                            Diagnostic::error(
+                                "Expected variable, array element or field as left-hand operator of assignement.",
+                            )
+                            .with_code("E0301")
+                            .with_primary(left.span, "cannot assign to this expression"),
+                        ),
+                    }
+                } else {
+                    self.resolve(left);
+                }
+
+                self.resolve(right);
+
+                if !BUILTIN_BINARY_OPERATORS.contains(&op) {
+                    self.resolve_call(&format!("binary{}", op), 2, expr.span);
+                }
+            }
+
+            ExprKind::Call {
+                ref fn_name,
+                ref args,
+                ..
+            } => {
+                for arg in args {
+                    self.resolve(arg);
+                }
+
+                // ローカル変数の関数値の呼び出しは、型検査で引数を検査する
+                if intrinsic_arity(fn_name).is_some() || !self.is_local(fn_name) {
+                    self.resolve_call(fn_name, args.len(), expr.span);
+                }
+            }
+
+            ExprKind::Lambda {
+                ref params,
+                ref body,
+                ..
+            } => {
+                let depth = self.locals.len();
+                // 無名関数の本体から、外側のループを抜けることはできない
+                let loop_depth = mem::replace(&mut self.loop_depth, 0);
+                let in_function = mem::replace(&mut self.in_function, true);
+
+                for &(ref name, ref ty) in params {
+                    if let Some(ref ty) = *ty {
+                        self.resolve_type(ty, expr.span);
+                    }
+
+                    self.locals.push(name.clone());
+                }
+
+                self.resolve(body);
+
+                self.locals.truncate(depth);
+                self.loop_depth = loop_depth;
+                self.in_function = in_function;
+            }
+
+            ExprKind::Cast(ref value) => self.resolve(value),
+
+            ExprKind::Conditional {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                self.resolve(cond);
+                self.resolve(consequence);
+                self.resolve(alternative);
+            }
+
+            ExprKind::For {
+                ref var_name,
+                ref var_type,
+                ref start,
+                ref end,
+                ref step,
+                ref body,
+            } => {
+                if let Some(ref ty) = *var_type {
+                    self.resolve_type(ty, expr.span);
+                }
+
+                // 開始値はループ変数のスコープの外で評価される
+                self.resolve(start);
+
+                self.locals.push(var_name.clone());
+                self.loop_depth += 1;
+
+                self.resolve(end);
+
+                if let Some(ref step) = *step {
+                    self.resolve(step);
+                }
+
+                self.resolve(body);
+
+                self.loop_depth -= 1;
+                self.locals.pop();
+            }
+
+            ExprKind::While { ref cond, ref body } => {
+                self.loop_depth += 1;
+
+                self.resolve(cond);
+                self.resolve(body);
+
+                self.loop_depth -= 1;
+            }
+
+            ExprKind::Break(ref value) => {
+                if let Some(ref value) = *value {
+                    self.resolve(value);
+                }
+
+                self.resolve_loop_jump("break", expr.span);
+            }
+
+            ExprKind::Continue => self.resolve_loop_jump("continue", expr.span),
+
+            ExprKind::Return(ref value) => {
+                self.resolve(value);
+
+                // グローバル変数の初期化式からは戻れない
+                if !self.in_function {
+                    self.diagnostics.push(
+                        Diagnostic::error("'return' outside of a function.")
+                            .with_code("E0315")
+                            .with_primary(expr.span, "cannot be used outside of a function"),
+                    );
+                }
+            }
+
+            ExprKind::VarIn {
+                ref variables,
+                ref body,
+            } => {
+                let depth = self.locals.len();
+
+                // 初期化式からは、それより前に宣言された変数が見える
+                for &(ref name, ref ty, ref init) in variables {
+                    if let Some(ref ty) = *ty {
+                        self.resolve_type(ty, expr.span);
+                    }
+
+                    if let Some(ref init) = *init {
+                        self.resolve(init);
+                    }
+
+                    self.locals.push(name.clone());
+                }
+
+                self.resolve(body);
+
+                self.locals.truncate(depth);
+            }
+        }
+    }
+}
+
+/// 未定義の関数、または演算子の呼び出しを示す診断を作成
+fn unknown_function(fn_name: &str, span: Span) -> Diagnostic {
+    // 演算子の関数名は'binary'か'unary'に記号が1文字続く
+    let operator = |prefix: &str| {
+        if !fn_name.starts_with(prefix) {
+            return None;
+        }
+
+        let mut rest = fn_name[prefix.len()..].chars();
+
+        match (rest.next(), rest.next()) {
+            (Some(op), None) if !op.is_alphanumeric() && op != '_' => Some(op),
+            _ => None,
+        }
+    };
+
+    if let Some(op) = operator("binary") {
+        Diagnostic::error("Undefined binary operator.")
+            .with_code("E0323")
+            .with_primary(span, "no operator definition found")
+            .with_note(format!(
+                "define it with 'def binary{} <precedence> (lhs, rhs)'",
+                op
+            ))
+    } else if let Some(op) = operator("unary") {
+        Diagnostic::error("Undefined unary operator.")
+            .with_code("E0323")
+            .with_primary(span, "no operator definition found")
+            .with_note(format!("define it with 'def unary{} (value)'", op))
+    } else {
+        Diagnostic::error("Unknown function.")
+            .with_code("E0302")
+            .with_primary(span, "no function with this name")
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * Kaleidoscopeの実行ファイル用ランタイム
+ *
+ * REPLではRustバイナリ内の関数(main.rs)が組み込み関数として利用されるが、
+ * '--emit=exe'で生成される実行ファイルにはこのファイルがリンクされる。
+ */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* ユーザーが定義した'def main()'はこの名前に変更されてコンパイルされる */
+extern double ks_main(void);
+
+/* 数値をRustの'{}'と同じく、往復可能な最短の表現で'buf'に書き込む */
+static void format_number(char *buf, size_t size, double x) {
+    int precision;
+
+    if (isnan(x)) {
+        snprintf(buf, size, "NaN");
+        return;
+    }
+
+    if (isinf(x)) {
+        snprintf(buf, size, x < 0 ? "-inf" : "inf");
+        return;
+    }
+
+    if (x == floor(x) && fabs(x) < 1e17) {
+        snprintf(buf, size, "%.0f", x);
+        return;
+    }
+
+    for (precision = 1; precision < 17; precision++) {
+        snprintf(buf, size, "%.*g", precision, x);
+
+        if (strtod(buf, NULL) == x) {
+            return;
+        }
+    }
+
+    snprintf(buf, size, "%.17g", x);
+}
+
+double putchard(double x) {
+    putchar((char) x);
+    fflush(stdout);
+
+    return x;
+}
+
+double printd(double x) {
+    char buf[32];
+
+    format_number(buf, sizeof(buf), x);
+    printf("%s\n", buf);
+
+    return x;
+}
+
+/* 文字列を改行せずに出力する。'strlen'はCライブラリの関数がそのまま使われる */
+double prints(const char *s) {
+    fputs(s, stdout);
+    fflush(stdout);
+
+    return 0;
+}
+
+/* 2つの文字列を連結した新しい文字列を返す。返された文字列は解放されない */
+char *concat(const char *a, const char *b) {
+    size_t len_a = strlen(a);
+    size_t len_b = strlen(b);
+    char *result = malloc(len_a + len_b + 1);
+
+    memcpy(result, a, len_a);
+    memcpy(result + len_a, b, len_b + 1);
+
+    return result;
+}
+
+/* 数値をprintdと同じ形式で文字列に変換する。返された文字列は解放されない */
+char *tostr(double x) {
+    char *buf = malloc(32);
+
+    format_number(buf, 32, x);
+
+    return buf;
+}
+
+/* 長さ'len'の配列を確保し、要素を0で初期化する。配列は長さの後に要素が続き、解放されない */
+void *ks_array_new(long long len) {
+    long long *array;
+
+    if (len < 0) {
+        len = 0;
+    }
+
+    array = calloc(len + 1, sizeof(double));
+
+    if (array == NULL) {
+        fprintf(stderr, "error: could not allocate an array of %lld elements\n", len);
+        exit(1);
+    }
+
+    array[0] = len;
+
+    return array;
+}
+
+/* '--checks'でコンパイルされた場合に、関数呼び出しの深さを数える */
+long long ks_call_depth = 0;
+
+/* 実行時検査に失敗した場所を表示し、プロセスを異常終了する */
+void ks_trap(const char *message, const char *function, int line, int column) {
+    fflush(stdout);
+    fprintf(stderr, "runtime error: %s\n  --> in function '%s' at %d:%d\n", message, function, line, column);
+    abort();
+}
+
+/* 'def main()'の結果を終了コードに変換するエントリーポイント */
+int main(void) {
+    double result = ks_main();
+
+    fflush(stdout);
+
+    if (isnan(result)) {
+        return 1;
+    }
+
+    return (int) result;
+}
@@ -0,0 +1,1180 @@
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Span;
+use crate::parser::*;
+
+use inkwell::module::Module;
+use inkwell::types::{AnyTypeEnum, BasicTypeEnum, StructType};
+use inkwell::values::FunctionValue;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// 関数の引数と戻り値の型
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub args: Vec<Type>,
+    pub ret: Type,
+}
+
+/// LLVMの型から対応する型を取得
+pub fn type_of(ty: BasicTypeEnum) -> Option<Type> {
+    match ty {
+        BasicTypeEnum::FloatType(_) => Some(Type::F64),
+        // 文字列はi8へのポインタ、配列は長さと要素を持つ名前のない構造体へのポインタ
+        BasicTypeEnum::PointerType(ptr) => match ptr.get_element_type() {
+            AnyTypeEnum::IntType(_) => Some(Type::Str),
+            AnyTypeEnum::StructType(st) => match st.get_name() {
+                Some(name) => Some(Type::Struct(name.to_str().ok()?.to_string())),
+                None => Some(fn_type_of(st).unwrap_or(Type::Array)),
+            },
+            _ => None,
+        },
+        BasicTypeEnum::IntType(int) => match int.get_bit_width() {
+            1 => Some(Type::Bool),
+            32 => Some(Type::I32),
+            64 => Some(Type::I64),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// 関数値を表す構造体'{ code*, i8* env }'から、関数値の型を取得
+/// 関数へのポインタの最初の引数は環境のため、関数値の引数には含まれない
+fn fn_type_of(st: StructType) -> Option<Type> {
+    let code = match st.get_field_types().first() {
+        Some(&BasicTypeEnum::PointerType(ptr)) => ptr.get_element_type(),
+        _ => return None,
+    };
+
+    let fn_type = match code {
+        AnyTypeEnum::FunctionType(fn_type) => fn_type,
+        _ => return None,
+    };
+
+    let mut args = Vec::new();
+
+    for ty in fn_type.get_param_types().into_iter().skip(1) {
+        args.push(type_of(ty)?);
+    }
+
+    Some(Type::Fn(
+        args,
+        Box::new(type_of(fn_type.get_return_type()?)?),
+    ))
+}
+
+/// モジュールに宣言された関数のシグネチャを取得
+pub fn signature(fn_val: FunctionValue) -> Option<Signature> {
+    let fn_type = fn_val.get_type();
+    let mut args = Vec::new();
+
+    for ty in fn_type.get_param_types() {
+        args.push(type_of(ty)?);
+    }
+
+    Some(Signature {
+        args: args,
+        ret: type_of(fn_type.get_return_type()?)?,
+    })
+}
+
+/// LLVMのモジュールを使わずに型検査するための、関数とグローバル変数の宣言
+/// グローバル変数はJITと同じく全てf64となる
+#[derive(Debug, Default)]
+pub struct DeclTable {
+    functions: HashMap<String, Signature>,
+    globals: HashSet<String>,
+}
+
+impl DeclTable {
+    /// 空の表を作成
+    pub fn new() -> DeclTable {
+        DeclTable::default()
+    }
+
+    /// 関数のプロトタイプを追加
+    pub fn add_function(&mut self, proto: &Prototype) {
+        let sig = Signature {
+            args: proto.arg_types.clone(),
+            ret: proto.ret_type.clone(),
+        };
+
+        self.functions.insert(proto.name.clone(), sig);
+    }
+
+    /// グローバル変数を追加
+    pub fn add_global(&mut self, name: &str) {
+        self.globals.insert(name.to_string());
+    }
+
+    /// トップレベルの要素が定義する関数とグローバル変数を追加
+    /// 匿名関数は呼び出されることがないため追加しない
+    pub fn add_item(&mut self, item: &Item) {
+        match *item {
+            Item::Function(ref fun) if !fun.is_anon => self.add_function(&fun.prototype),
+            Item::Global(ref global) => self.add_global(&global.name),
+            Item::Function(_) | Item::Struct(_) => (),
+        }
+    }
+}
+
+/// 型検査で参照される、関数とグローバル変数の宣言
+/// JITはコンパイル先のモジュールを、インタプリタとバイトコードコンパイラは宣言の表を参照する
+#[derive(Clone, Copy)]
+pub enum Declarations<'a, 'ctx> {
+    Module(&'a Module<'ctx>),
+    Table(&'a DeclTable),
+}
+
+impl<'a, 'ctx> Declarations<'a, 'ctx> {
+    /// グローバル変数が宣言されているかを返す
+    fn has_global(self, name: &str) -> bool {
+        match self {
+            Declarations::Module(module) => module.get_global(name).is_some(),
+            Declarations::Table(table) => table.globals.contains(name),
+        }
+    }
+
+    /// 関数のシグネチャを返す
+    /// 関数が宣言されていない場合はNone、LLVMの型から変換できない場合はSome(None)となる
+    fn function(self, name: &str) -> Option<Option<Signature>> {
+        match self {
+            Declarations::Module(module) => module.get_function(name).map(signature),
+            Declarations::Table(table) => table.functions.get(name).cloned().map(Some),
+        }
+    }
+}
+
+/// トップレベルの要素を型検査し、全ての式に型を設定した要素を返す
+/// インタプリタとバイトコードコンパイラは、JITと同じプログラムのみを受け付けるためにこれを使う
+pub fn check_item(
+    decls: &DeclTable,
+    structs: &StructTable,
+    item: &Item,
+) -> Result<Item, Diagnostic> {
+    let decls = Declarations::Table(decls);
+
+    match *item {
+        Item::Function(ref fun) => {
+            TypeChecker::check_function(decls, structs, fun).map(Item::Function)
+        }
+        Item::Global(ref global) => {
+            TypeChecker::check_global(decls, structs, global).map(Item::Global)
+        }
+        Item::Struct(ref def) => Ok(Item::Struct(def.clone())),
+    }
+}
+
+/// 宣言された全ての構造体
+#[derive(Debug, Default)]
+pub struct StructTable {
+    structs: HashMap<String, StructDef>,
+}
+
+impl StructTable {
+    /// 空の表を作成
+    pub fn new() -> StructTable {
+        StructTable::default()
+    }
+
+    /// 構造体を追加
+    /// 同じ名前の構造体が既に宣言されている場合はエラーとなる
+    pub fn add(&mut self, def: &StructDef) -> Result<(), Diagnostic> {
+        if self.structs.contains_key(&def.name) {
+            return Err(
+                Diagnostic::error(format!("Struct '{}' is already declared.", def.name))
+                    .with_code("E0311")
+                    .with_primary(def.span, "redeclared here"),
+            );
+        }
+
+        self.structs.insert(def.name.clone(), def.clone());
+
+        Ok(())
+    }
+
+    /// 指定された名前の構造体を取得
+    pub fn get(&self, name: &str) -> Option<&StructDef> {
+        self.structs.get(name)
+    }
+
+    /// 全ての構造体を返す
+    pub fn iter(&self) -> impl Iterator<Item = &StructDef> {
+        self.structs.values()
+    }
+}
+
+/// 型検査器の定義
+/// 関数とグローバル変数の型は、'decls'に宣言されたものを参照する
+pub struct TypeChecker<'a, 'ctx> {
+    decls: Declarations<'a, 'ctx>,
+    structs: &'a StructTable,
+    variables: HashMap<String, Type>,
+    /// 検査中の関数の戻り値の型
+    /// 推論される場合は、最初の'return'の値の型となるまでNone
+    ret_type: Option<Type>,
+}
+
+impl<'a, 'ctx> TypeChecker<'a, 'ctx> {
+    /// 関数を型検査し、全ての式に型を設定した関数を返す
+    /// 暗黙の型変換が必要な箇所にはExprKind::Castが挿入される
+    pub fn check_function(
+        decls: Declarations<'a, 'ctx>,
+        structs: &'a StructTable,
+        function: &Function,
+    ) -> Result<Function, Diagnostic> {
+        let mut checker = TypeChecker {
+            decls: decls,
+            structs: structs,
+            variables: HashMap::new(),
+            ret_type: None,
+        };
+
+        let mut prototype = function.prototype.clone();
+        let mut body = function.body.clone();
+
+        if let Some(ref mut body) = body {
+            for (name, ty) in prototype.args.iter().zip(prototype.arg_types.iter()) {
+                checker.variables.insert(name.clone(), ty.clone());
+            }
+
+            if function.is_anon {
+                // 匿名関数は'return'の値か、本体の型をそのまま返す
+                let body_type = checker.check(body, None)?;
+
+                prototype.ret_type = match checker.ret_type.take() {
+                    Some(ty) => {
+                        checker.coerce(body, &ty)?;
+
+                        ty
+                    }
+                    None => body_type,
+                };
+            } else {
+                checker.ret_type = Some(prototype.ret_type.clone());
+                checker.check(body, Some(&prototype.ret_type))?;
+                checker.coerce(body, &prototype.ret_type)?;
+            }
+        }
+
+        Ok(Function {
+            prototype: prototype,
+            body: body,
+            is_anon: function.is_anon,
+            span: function.span,
+        })
+    }
+
+    /// グローバル変数の初期化式を型検査し、型を設定したグローバル変数を返す
+    /// グローバル変数はf64のため、初期化式はf64に変換できなければならない
+    pub fn check_global(
+        decls: Declarations<'a, 'ctx>,
+        structs: &'a StructTable,
+        global: &GlobalVar,
+    ) -> Result<GlobalVar, Diagnostic> {
+        let mut checker = TypeChecker {
+            decls: decls,
+            structs: structs,
+            variables: HashMap::new(),
+            ret_type: Some(Type::F64),
+        };
+
+        let mut init = global.init.clone();
+
+        checker.check(&mut init, Some(&Type::F64))?;
+        checker
+            .coerce(&mut init, &Type::F64)
+            .map_err(|err| err.with_note("global variables always hold 'f64' values"))?;
+
+        Ok(GlobalVar {
+            name: global.name.clone(),
+            init: init,
+            span: global.span,
+        })
+    }
+
+    /// 変数の型を取得
+    /// ローカル変数が見つからない場合は、モジュールのグローバル変数、関数の順に探す
+    /// 関数は関数値として参照される
+    fn lookup_variable(&self, name: &str) -> Option<Type> {
+        if let Some(ty) = self.variables.get(name) {
+            return Some(ty.clone());
+        }
+
+        if self.decls.has_global(name) {
+            return Some(Type::F64);
+        }
+
+        let sig = self.decls.function(name)??;
+
+        Some(Type::Fn(sig.args, Box::new(sig.ret)))
+    }
+
+    /// 式を型検査し、その型を返す
+    /// 'expected'は数値リテラルの型を決めるために使われる
+    fn check(&mut self, expr: &mut Expr, expected: Option<&Type>) -> Result<Type, Diagnostic> {
+        let span = expr.span;
+
+        let ty = match expr.kind {
+            ExprKind::Number(nb) => match expected {
+                Some(ty) if ty.is_integer() && nb.fract() == 0.0 => ty.clone(),
+                _ => Type::F64,
+            },
+
+            ExprKind::Str(_) => Type::Str,
+
+            ExprKind::Variable(ref name) => match self.lookup_variable(name) {
+                Some(ty) => ty,
+                None => {
+                    return Err(Diagnostic::error("Could not find a matching variable.")
+                        .with_code("E0300")
+                        .with_primary(span, "not found in this scope"))
+                }
+            },
+
+            ExprKind::Binary {
+                op,
+                ref mut left,
+                ref mut right,
+            } => match op {
+                '=' => {
+                    let var_type = match left.kind {
+                        ExprKind::Variable(ref name) => self.lookup_variable(name),
+                        // 配列の要素、または構造体のフィールドへの代入
+                        ExprKind::Index { .. } | ExprKind::Field { .. } => {
+                            Some(self.check(left, None)?)
+                        }
+                        _ => {
+                            return Err(Diagnostic::error(
+                                "Expected variable, array element or field as left-hand operator of assignement.",
+                            )
+                            .with_code("E0301")
+                            .with_primary(left.span, "cannot assign to this expression"))
+                        }
+                    };
+
+                    let var_type = var_type.ok_or_else(|| {
+                        Diagnostic::error("Undefined variable.")
+                            .with_code("E0300")
+                            .with_primary(left.span, "not found in this scope")
+                    })?;
+
+                    left.ty = var_type.clone();
+
+                    self.check(right, Some(&var_type))?;
+                    self.coerce(right, &var_type)?;
+
+                    var_type
+                }
+
+                '+' | '-' | '*' | '/' => {
+                    let ty = self.check_operands(left, right, expected)?;
+
+                    if !ty.is_numeric() {
+                        return Err(Diagnostic::error(format!(
+                            "Cannot apply operator '{}' to type '{}'.",
+                            op, ty
+                        ))
+                        .with_code("E0316")
+                        .with_primary(span, "in this operation"));
+                    }
+
+                    ty
+                }
+
+                '<' | '>' => {
+                    let ty = self.check_operands(left, right, None)?;
+
+                    if !ty.is_numeric() {
+                        return Err(Diagnostic::error(format!(
+                            "Cannot compare values of type '{}' with '{}'.",
+                            ty, op
+                        ))
+                        .with_code("E0316")
+                        .with_primary(span, "in this comparison"));
+                    }
+
+                    Type::Bool
+                }
+
+                custom => {
+                    let name = format!("binary{}", custom);
+
+                    self.check_call(&name, &mut [&mut **left, &mut **right], span)?
+                }
+            },
+
+            ExprKind::Call {
+                ref fn_name,
+                ref mut args,
+                ref mut local,
+            } => {
+                let mut args: Vec<&mut Expr> = args.iter_mut().collect();
+
+                // コード生成が関数値の呼び出しを選べるように、解決結果を残す
+                *local = self.variables.contains_key(fn_name);
+
+                if intrinsic_arity(fn_name).is_some() {
+                    self.check_intrinsic(fn_name, &mut args, span)?
+                } else {
+                    self.check_call(fn_name, &mut args, span)?
+                }
+            }
+
+            ExprKind::Array(ref mut elements) => {
+                for element in elements.iter_mut() {
+                    self.check(element, Some(&Type::F64))?;
+                    self.coerce(element, &Type::F64)?;
+                }
+
+                Type::Array
+            }
+
+            ExprKind::Index {
+                ref mut array,
+                ref mut index,
+            } => {
+                self.check_array(array)?;
+                self.check_index(index)?;
+
+                Type::F64
+            }
+
+            ExprKind::Construct {
+                ref name,
+                ref mut fields,
+            } => self.check_construct(name, fields, span)?,
+
+            ExprKind::Field {
+                ref mut value,
+                ref field,
+                ref mut index,
+            } => {
+                let (field_index, field_type) = self.check_field(value, field, span)?;
+
+                *index = field_index;
+
+                field_type
+            }
+
+            ExprKind::Lambda {
+                ref mut params,
+                ref mut body,
+                ref mut captures,
+            } => self.check_lambda(params, body, captures, expected)?,
+
+            ExprKind::Cast(_) => expr.ty.clone(),
+
+            ExprKind::Conditional {
+                ref mut cond,
+                ref mut consequence,
+                ref mut alternative,
+            } => {
+                // 条件はboolか、0と比較される数値
+                self.check_condition(cond)?;
+
+                let then_type = self.check(consequence, expected)?;
+                let else_type = self.check(alternative, Some(&then_type))?;
+
+                if self.coerce(alternative, &then_type).is_ok() {
+                    then_type
+                } else {
+                    self.coerce(consequence, &else_type).map_err(|err| {
+                        err.with_secondary(
+                            alternative.span,
+                            format!("'else' branch has type '{}'", else_type),
+                        )
+                    })?;
+
+                    else_type
+                }
+            }
+
+            ExprKind::For {
+                ref var_name,
+                ref var_type,
+                ref mut start,
+                ref mut end,
+                ref mut step,
+                ref mut body,
+            } => {
+                let var_type = match *var_type {
+                    Some(ref ty) => {
+                        self.check(start, Some(ty))?;
+                        self.coerce(start, ty)?;
+
+                        ty.clone()
+                    }
+                    None => self.check(start, None)?,
+                };
+
+                if !var_type.is_numeric() {
+                    return Err(Diagnostic::error(format!(
+                        "Loop variable cannot be of type '{}'.",
+                        var_type
+                    ))
+                    .with_code("E0317")
+                    .with_primary(start.span, format!("this has type '{}'", var_type)));
+                }
+
+                let old_type = self.variables.insert(var_name.clone(), var_type.clone());
+
+                if let Some(ref mut step) = *step {
+                    self.check(step, Some(&var_type))?;
+                    self.coerce(step, &var_type)?;
+                }
+
+                self.check_condition(end)?;
+                self.check(body, None)?;
+
+                self.restore(var_name, old_type);
+
+                Type::F64
+            }
+
+            ExprKind::Block(ref mut exprs) => match exprs.split_last_mut() {
+                Some((last, init)) => {
+                    for expr in init {
+                        self.check(expr, None)?;
+                    }
+
+                    self.check(last, expected)?
+                }
+                None => Type::F64,
+            },
+
+            ExprKind::While {
+                ref mut cond,
+                ref mut body,
+            } => {
+                self.check_condition(cond)?;
+                self.check(body, None)?;
+
+                Type::F64
+            }
+
+            // ループの値はf64となる
+            ExprKind::Break(ref mut value) => {
+                if let Some(ref mut value) = *value {
+                    self.check(value, Some(&Type::F64))?;
+                    self.coerce(value, &Type::F64)?;
+                }
+
+                expected.cloned().unwrap_or(Type::F64)
+            }
+
+            // 'break'と'continue'は値を返さないため、どの型としても扱える
+            ExprKind::Continue => expected.cloned().unwrap_or(Type::F64),
+
+            ExprKind::Return(ref mut value) => {
+                match self.ret_type.clone() {
+                    Some(ret_type) => {
+                        self.check(value, Some(&ret_type))?;
+                        self.coerce(value, &ret_type)?;
+                    }
+                    None => self.ret_type = Some(self.check(value, None)?),
+                }
+
+                expected.cloned().unwrap_or(Type::F64)
+            }
+
+            ExprKind::VarIn {
+                ref mut variables,
+                ref mut body,
+            } => {
+                let mut old_types = Vec::new();
+
+                for &mut (ref name, ref mut var_type, ref mut init) in variables.iter_mut() {
+                    let ty = match *init {
+                        Some(ref mut init) => {
+                            let init_type = self.check(init, var_type.as_ref())?;
+
+                            match *var_type {
+                                Some(ref ty) => {
+                                    self.coerce(init, ty)?;
+
+                                    ty.clone()
+                                }
+                                None => init_type,
+                            }
+                        }
+                        // 構造体と関数値には既定値がないため、初期化式が必要となる
+                        None => match *var_type {
+                            Some(ref ty @ Type::Struct(_)) | Some(ref ty @ Type::Fn(..)) => {
+                                return Err(Diagnostic::error(format!(
+                                    "Variable '{}' of type '{}' must be initialized.",
+                                    name, ty
+                                ))
+                                .with_code("E0318")
+                                .with_primary(span, "in this declaration"))
+                            }
+                            Some(ref ty) => ty.clone(),
+                            None => Type::F64,
+                        },
+                    };
+
+                    // コード生成のために、推論された型を注釈として残す
+                    *var_type = Some(ty.clone());
+
+                    old_types.push((name.clone(), self.variables.insert(name.clone(), ty)));
+                }
+
+                let ty = self.check(body, expected)?;
+
+                for (name, old_type) in old_types.into_iter().rev() {
+                    self.restore(&name, old_type);
+                }
+
+                ty
+            }
+        };
+
+        // 型の変換は、変換される式を包むExprKind::Castに置き換える
+        let converted = match expr.kind {
+            ExprKind::Call {
+                ref fn_name,
+                ref mut args,
+                ..
+            } if conversion_type(fn_name).is_some() => args.pop(),
+            _ => None,
+        };
+
+        if let Some(value) = converted {
+            expr.kind = ExprKind::Cast(Box::new(value));
+        }
+
+        expr.ty = ty.clone();
+
+        Ok(ty)
+    }
+
+    /// 条件として使われる式を検査する
+    /// 条件はboolか、0と比較される数値でなければならない
+    fn check_condition(&mut self, cond: &mut Expr) -> Result<(), Diagnostic> {
+        let ty = self.check(cond, None)?;
+
+        if ty == Type::Bool || ty.is_numeric() {
+            Ok(())
+        } else {
+            Err(mismatch(cond.span, &Type::Bool, &ty))
+        }
+    }
+
+    /// 二項演算子の両辺を同じ型として検査し、その型を返す
+    /// 数値リテラルはもう一方の辺の型に合わせられ、整数とf64の組み合わせは整数がf64に広げられる
+    fn check_operands(
+        &mut self,
+        left: &mut Expr,
+        right: &mut Expr,
+        expected: Option<&Type>,
+    ) -> Result<Type, Diagnostic> {
+        let is_literal = |expr: &Expr| match expr.kind {
+            ExprKind::Number(_) => true,
+            _ => false,
+        };
+
+        let (left_type, right_type) = if is_literal(left) && !is_literal(right) {
+            let right_type = self.check(right, expected)?;
+
+            (self.check(left, Some(&right_type))?, right_type)
+        } else {
+            let left_type = self.check(left, expected)?;
+            let right_type = self.check(right, Some(&left_type))?;
+
+            (left_type, right_type)
+        };
+
+        if left_type == right_type {
+            return Ok(left_type);
+        }
+
+        if left_type == Type::F64 && right_type.is_integer() {
+            cast(right, Type::F64);
+        } else if left_type.is_integer() && right_type == Type::F64 {
+            cast(left, Type::F64);
+        } else {
+            return Err(mismatch(right.span, &left_type, &right_type)
+                .with_secondary(left.span, format!("this has type '{}'", left_type)));
+        }
+
+        Ok(Type::F64)
+    }
+
+    /// 関数呼び出しの引数を検査し、戻り値の型を返す
+    /// ローカル変数の名前の場合は、その関数値の呼び出しとなる
+    fn check_call(
+        &mut self,
+        fn_name: &str,
+        args: &mut [&mut Expr],
+        span: Span,
+    ) -> Result<Type, Diagnostic> {
+        if let Some(ty) = self.variables.get(fn_name).cloned() {
+            return match ty {
+                Type::Fn(params, ret) => {
+                    self.check_args(fn_name, &params, args, span)?;
+
+                    Ok(*ret)
+                }
+                ty => Err(
+                    Diagnostic::error(format!("'{}' is not a function.", fn_name))
+                        .with_code("E0303")
+                        .with_primary(span, format!("'{}' has type '{}'", fn_name, ty)),
+                ),
+            };
+        }
+
+        let sig = match self.decls.function(fn_name) {
+            Some(sig) => sig.ok_or_else(|| {
+                Diagnostic::error("Function has an unsupported signature.")
+                    .with_code("E0321")
+                    .with_primary(span, "in this call")
+            })?,
+            None => {
+                return Err(Diagnostic::error("Unknown function.")
+                    .with_code("E0302")
+                    .with_primary(span, "no function with this name"))
+            }
+        };
+
+        self.check_args(fn_name, &sig.args, args, span)?;
+
+        Ok(sig.ret)
+    }
+
+    /// 関数呼び出しの引数の数と型を検査する
+    fn check_args(
+        &mut self,
+        fn_name: &str,
+        params: &[Type],
+        args: &mut [&mut Expr],
+        span: Span,
+    ) -> Result<(), Diagnostic> {
+        if params.len() != args.len() {
+            return Err(Diagnostic::error(format!(
+                "Function '{}' takes {} argument(s) but {} were supplied.",
+                fn_name,
+                params.len(),
+                args.len()
+            ))
+            .with_code("E0307")
+            .with_primary(span, "wrong number of arguments"));
+        }
+
+        for (arg, ty) in args.iter_mut().zip(params.iter()) {
+            self.check(arg, Some(ty))?;
+            self.coerce(arg, ty)?;
+        }
+
+        Ok(())
+    }
+
+    /// 無名関数を検査し、その関数値の型を返す
+    /// 型注釈のない引数と戻り値の型は、期待される関数値の型から決まる
+    fn check_lambda(
+        &mut self,
+        params: &mut [(String, Option<Type>)],
+        body: &mut Expr,
+        captures: &mut Vec<(String, Type)>,
+        expected: Option<&Type>,
+    ) -> Result<Type, Diagnostic> {
+        let (expected_args, expected_ret) = match expected {
+            Some(&Type::Fn(ref args, ref ret)) if args.len() == params.len() => {
+                (Some(args.clone()), Some((**ret).clone()))
+            }
+            _ => (None, None),
+        };
+
+        // 本体から参照される外側のローカル変数は、作成時の値がキャプチャされる
+        let mut bound = params.iter().map(|&(ref name, _)| name.clone()).collect();
+        let mut free = Vec::new();
+
+        free_variables(body, &mut bound, &mut free);
+
+        *captures = free
+            .into_iter()
+            .filter_map(|name| self.variables.get(&name).map(|ty| (name, ty.clone())))
+            .collect();
+
+        let mut variables: HashMap<String, Type> = captures.iter().cloned().collect();
+        let mut arg_types = Vec::with_capacity(params.len());
+
+        for (i, &mut (ref name, ref mut ty)) in params.iter_mut().enumerate() {
+            let arg_type = match (ty.clone(), expected_args.as_ref()) {
+                (Some(ty), _) => ty,
+                (None, Some(args)) => args[i].clone(),
+                (None, None) => Type::F64,
+            };
+
+            // コード生成のために、決定した型を注釈として残す
+            *ty = Some(arg_type.clone());
+
+            variables.insert(name.clone(), arg_type.clone());
+            arg_types.push(arg_type);
+        }
+
+        let outer = mem::replace(&mut self.variables, variables);
+        let outer_ret = mem::replace(&mut self.ret_type, expected_ret.clone());
+        let body_type = self.check(body, expected_ret.as_ref());
+
+        self.variables = outer;
+
+        let returned = mem::replace(&mut self.ret_type, outer_ret);
+        let body_type = body_type?;
+        let ret_type = match (expected_ret, returned) {
+            (Some(ret), _) if self.coerce(body, &ret).is_ok() => ret,
+            (None, Some(ret)) => {
+                self.coerce(body, &ret)?;
+
+                ret
+            }
+            _ => body_type,
+        };
+
+        Ok(Type::Fn(arg_types, Box::new(ret_type)))
+    }
+
+    /// 組み込み関数の呼び出しを検査し、戻り値の型を返す
+    fn check_intrinsic(
+        &mut self,
+        fn_name: &str,
+        args: &mut [&mut Expr],
+        span: Span,
+    ) -> Result<Type, Diagnostic> {
+        let arity = intrinsic_arity(fn_name).unwrap_or(0);
+
+        if args.len() != arity {
+            return Err(Diagnostic::error(format!(
+                "Builtin '{}' takes {} argument(s) but {} were supplied.",
+                fn_name,
+                arity,
+                args.len()
+            ))
+            .with_code("E0307")
+            .with_primary(span, "wrong number of arguments"));
+        }
+
+        if let Some(target) = conversion_type(fn_name) {
+            // 変換される式の型は、変換先の型に関係なく決まる
+            let ty = self.check(args[0], None)?;
+
+            if ty != Type::Bool && !ty.is_numeric() {
+                return Err(Diagnostic::error(format!(
+                    "Cannot convert type '{}' to '{}'.",
+                    ty, target
+                ))
+                .with_code("E0319")
+                .with_primary(args[0].span, format!("this has type '{}'", ty)));
+            }
+
+            return Ok(target);
+        }
+
+        match fn_name {
+            "array" => {
+                self.check_index(args[0])?;
+
+                Ok(Type::Array)
+            }
+            _ => {
+                self.check_array(args[0])?;
+
+                Ok(Type::I64)
+            }
+        }
+    }
+
+    /// 構造体の作成式を検査し、フィールドを宣言された順に並べ替える
+    fn check_construct(
+        &mut self,
+        name: &str,
+        fields: &mut Vec<(String, Expr)>,
+        span: Span,
+    ) -> Result<Type, Diagnostic> {
+        let structs = self.structs;
+        let def = structs.get(name).ok_or_else(|| {
+            Diagnostic::error(format!("Unknown type '{}'.", name))
+                .with_code("E0308")
+                .with_primary(span, "no struct with this name")
+        })?;
+
+        let mut values: Vec<Option<(String, Expr)>> = vec![None; def.fields.len()];
+
+        for (field, mut value) in fields.drain(..) {
+            let index = def
+                .field_index(&field)
+                .ok_or_else(|| unknown_field(value.span, name, &field))?;
+
+            if let Some((_, ref previous)) = values[index] {
+                return Err(Diagnostic::error(format!(
+                    "Field '{}' is specified more than once.",
+                    field
+                ))
+                .with_code("E0312")
+                .with_primary(value.span, "field already specified")
+                .with_secondary(previous.span, "first specified here"));
+            }
+
+            let field_type = &def.fields[index].1;
+
+            self.check(&mut value, Some(field_type))?;
+            self.coerce(&mut value, field_type)?;
+
+            values[index] = Some((field, value));
+        }
+
+        for (value, &(ref field, _)) in values.into_iter().zip(def.fields.iter()) {
+            match value {
+                Some(value) => fields.push(value),
+                None => {
+                    return Err(Diagnostic::error(format!(
+                        "Missing field '{}' in struct '{}'.",
+                        field, name
+                    ))
+                    .with_code("E0313")
+                    .with_primary(span, "in this struct expression"))
+                }
+            }
+        }
+
+        Ok(Type::Struct(name.to_string()))
+    }
+
+    /// フィールドアクセスを検査し、フィールドの位置と型を返す
+    fn check_field(
+        &mut self,
+        value: &mut Expr,
+        field: &str,
+        span: Span,
+    ) -> Result<(usize, Type), Diagnostic> {
+        let struct_name = match self.check(value, None)? {
+            Type::Struct(name) => name,
+            ty => {
+                return Err(
+                    Diagnostic::error(format!("Type '{}' does not have fields.", ty))
+                        .with_code("E0320")
+                        .with_primary(value.span, format!("this has type '{}'", ty)),
+                )
+            }
+        };
+
+        let def = self.structs.get(&struct_name).ok_or_else(|| {
+            Diagnostic::error(format!("Unknown type '{}'.", struct_name))
+                .with_code("E0308")
+                .with_primary(value.span, "no struct with this name")
+        })?;
+
+        match def.field_index(field) {
+            Some(index) => Ok((index, def.fields[index].1.clone())),
+            None => Err(unknown_field(span, &struct_name, field)),
+        }
+    }
+
+    /// 配列として使われる式を検査する
+    fn check_array(&mut self, array: &mut Expr) -> Result<(), Diagnostic> {
+        match self.check(array, None)? {
+            Type::Array => Ok(()),
+            ty => Err(mismatch(array.span, &Type::Array, &ty)),
+        }
+    }
+
+    /// 配列の添字や長さとして使われる式を検査し、i64に変換する
+    /// f64のループ変数などをそのまま使えるように、全ての数値型を受け付ける
+    fn check_index(&mut self, index: &mut Expr) -> Result<(), Diagnostic> {
+        let ty = self.check(index, Some(&Type::I64))?;
+
+        if !ty.is_numeric() {
+            return Err(mismatch(index.span, &Type::I64, &ty));
+        }
+
+        if ty != Type::I64 {
+            cast(index, Type::I64);
+        }
+
+        Ok(())
+    }
+
+    /// 検査済みの式を指定された型に合わせる
+    /// boolと整数からf64への暗黙の変換のみが許され、その場合は式をExprKind::Castで包む
+    fn coerce(&self, expr: &mut Expr, ty: &Type) -> Result<(), Diagnostic> {
+        if expr.ty == *ty {
+            return Ok(());
+        }
+
+        // 制御を移す式は値を返さないため、どの型にも合わせられる
+        if expr.diverges() {
+            set_diverging_type(expr, ty);
+
+            return Ok(());
+        }
+
+        if *ty != Type::F64 || (expr.ty != Type::Bool && !expr.ty.is_integer()) {
+            return Err(mismatch(expr.span, ty, &expr.ty));
+        }
+
+        cast(expr, Type::F64);
+
+        Ok(())
+    }
+
+    /// スコープを抜けた変数の型を、外側の束縛に戻す
+    fn restore(&mut self, name: &str, old_type: Option<Type>) {
+        match old_type {
+            Some(ty) => self.variables.insert(name.to_string(), ty),
+            None => self.variables.remove(name),
+        };
+    }
+}
+
+/// 式の中で参照される、'bound'に含まれない変数の名前を'free'に集める
+/// 関数値として呼び出される変数の名前も含まれる
+fn free_variables(expr: &mut Expr, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    fn note(name: &str, bound: &[String], free: &mut Vec<String>) {
+        if !bound.iter().any(|var| var == name) && !free.iter().any(|var| var == name) {
+            free.push(name.to_string());
+        }
+    }
+
+    match expr.kind {
+        ExprKind::Variable(ref name) => note(name, bound, free),
+
+        ExprKind::Call {
+            ref fn_name,
+            ref mut args,
+            ..
+        } => {
+            note(fn_name, bound, free);
+
+            for arg in args {
+                free_variables(arg, bound, free);
+            }
+        }
+
+        ExprKind::For {
+            ref var_name,
+            ref mut start,
+            ref mut end,
+            ref mut step,
+            ref mut body,
+            ..
+        } => {
+            free_variables(start, bound, free);
+
+            bound.push(var_name.clone());
+
+            free_variables(end, bound, free);
+
+            if let Some(ref mut step) = *step {
+                free_variables(step, bound, free);
+            }
+
+            free_variables(body, bound, free);
+
+            bound.pop();
+        }
+
+        ExprKind::VarIn {
+            ref mut variables,
+            ref mut body,
+        } => {
+            let depth = bound.len();
+
+            for &mut (ref name, _, ref mut init) in variables.iter_mut() {
+                if let Some(ref mut init) = *init {
+                    free_variables(init, bound, free);
+                }
+
+                bound.push(name.clone());
+            }
+
+            free_variables(body, bound, free);
+
+            bound.truncate(depth);
+        }
+
+        ExprKind::Lambda {
+            ref params,
+            ref mut body,
+            ..
+        } => {
+            let depth = bound.len();
+
+            bound.extend(params.iter().map(|&(ref name, _)| name.clone()));
+
+            free_variables(body, bound, free);
+
+            bound.truncate(depth);
+        }
+
+        _ => {
+            for child in expr.children_mut() {
+                free_variables(child, bound, free);
+            }
+        }
+    }
+}
+
+/// 制御を移す式と、その値となる子の式の型を設定する
+fn set_diverging_type(expr: &mut Expr, ty: &Type) {
+    match expr.kind {
+        ExprKind::Block(ref mut exprs) => {
+            if let Some(last) = exprs.last_mut() {
+                set_diverging_type(last, ty);
+            }
+        }
+        ExprKind::VarIn { ref mut body, .. } => set_diverging_type(body, ty),
+        ExprKind::Conditional {
+            ref mut consequence,
+            ref mut alternative,
+            ..
+        } => {
+            set_diverging_type(consequence, ty);
+            set_diverging_type(alternative, ty);
+        }
+        _ => (),
+    }
+
+    expr.ty = ty.clone();
+}
+
+/// 型変換の組み込み関数であれば、変換先の型を返す
+fn conversion_type(name: &str) -> Option<Type> {
+    match name {
+        "f64" => Some(Type::F64),
+        "i32" => Some(Type::I32),
+        "i64" => Some(Type::I64),
+        _ => None,
+    }
+}
+
+/// 式をExprKind::Castで包み、指定された型に変換する
+fn cast(expr: &mut Expr, ty: Type) {
+    let span = expr.span;
+    let value = std::mem::replace(
+        expr,
+        Expr {
+            kind: ExprKind::Number(0.0),
+            span: span,
+            ty: ty,
+        },
+    );
+
+    expr.kind = ExprKind::Cast(Box::new(value));
+}
+
+/// 構造体に存在しないフィールドを示す診断を作成
+fn unknown_field(span: Span, struct_name: &str, field: &str) -> Diagnostic {
+    Diagnostic::error(format!("No field '{}' on struct '{}'.", field, struct_name))
+        .with_code("E0314")
+        .with_primary(span, "unknown field")
+}
+
+/// 型が一致しないことを示す診断を作成
+fn mismatch(span: Span, expected: &Type, found: &Type) -> Diagnostic {
+    Diagnostic::error(format!(
+        "Mismatched types: expected '{}', found '{}'.",
+        expected, found
+    ))
+    .with_code("E0306")
+    .with_primary(span, format!("expected '{}'", expected))
+}
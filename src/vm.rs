@@ -0,0 +1,427 @@
+use crate::bytecode::{Entry, Instr, Program};
+use crate::diagnostic::Diagnostic;
+
+use std::io::{self, Write};
+
+/// VMで許される、関数呼び出しの最大の深さ
+/// 呼び出しはRustのスタックを使わないため、JITの実行時検査と同じ上限とする
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+/// 外部関数として呼び出せる組み込み関数
+/// REPLがJITに提供する出力関数と、Cライブラリの数学関数のうちf64のみを扱うもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Putchard,
+    Printd,
+    Sin,
+    Cos,
+    Tan,
+    Atan2,
+    Sqrt,
+    Exp,
+    Log,
+    Pow,
+    Fabs,
+    Floor,
+    Ceil,
+    Fmod,
+}
+
+impl Builtin {
+    /// 外部宣言された名前から組み込み関数を取得
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        let builtin = match name {
+            "putchard" => Builtin::Putchard,
+            "printd" => Builtin::Printd,
+            "sin" => Builtin::Sin,
+            "cos" => Builtin::Cos,
+            "tan" => Builtin::Tan,
+            "atan2" => Builtin::Atan2,
+            "sqrt" => Builtin::Sqrt,
+            "exp" => Builtin::Exp,
+            "log" => Builtin::Log,
+            "pow" => Builtin::Pow,
+            "fabs" => Builtin::Fabs,
+            "floor" => Builtin::Floor,
+            "ceil" => Builtin::Ceil,
+            "fmod" => Builtin::Fmod,
+            _ => return None,
+        };
+
+        Some(builtin)
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Builtin::Atan2 | Builtin::Pow | Builtin::Fmod => 2,
+            _ => 1,
+        }
+    }
+
+    /// 引数を受け取って組み込み関数を実行する
+    fn call(self, args: &[f64], out: &mut dyn Write) -> io::Result<f64> {
+        let x = args[0];
+
+        let value = match self {
+            Builtin::Putchard => {
+                write!(out, "{}", x as u8 as char)?;
+                out.flush()?;
+
+                x
+            }
+            Builtin::Printd => {
+                writeln!(out, "{}", x)?;
+                out.flush()?;
+
+                x
+            }
+            Builtin::Sin => x.sin(),
+            Builtin::Cos => x.cos(),
+            Builtin::Tan => x.tan(),
+            Builtin::Atan2 => x.atan2(args[1]),
+            Builtin::Sqrt => x.sqrt(),
+            Builtin::Exp => x.exp(),
+            Builtin::Log => x.ln(),
+            Builtin::Pow => x.powf(args[1]),
+            Builtin::Fabs => x.abs(),
+            Builtin::Floor => x.floor(),
+            Builtin::Ceil => x.ceil(),
+            Builtin::Fmod => x % args[1],
+        };
+
+        Ok(value)
+    }
+}
+
+/// 呼び出し中の関数
+struct Frame {
+    chunk: usize,
+    /// 戻り先の命令の位置
+    ip: usize,
+    /// スタック上のローカル変数の開始位置
+    base: usize,
+}
+
+/// バイトコードを実行するスタックマシン
+///
+/// ローカル変数とオペランドはひとつのスタックに置かれ、関数呼び出しはRustの
+/// スタックを使わずにフレームを積んで行う。
+pub struct Vm {
+    program: Program,
+    externs: Vec<Builtin>,
+    globals: Vec<f64>,
+    stack: Vec<f64>,
+    frames: Vec<Frame>,
+    /// 組み込み関数の出力先
+    out: Box<dyn Write>,
+}
+
+impl Vm {
+    /// 標準出力に出力するVMを作成
+    /// プログラムが検証に失敗した場合や、利用できない外部関数を参照する場合はErrとなる
+    pub fn new(program: Program) -> Result<Vm, String> {
+        Vm::with_output(program, Box::new(io::stdout()))
+    }
+
+    /// 組み込み関数の出力先を指定してVMを作成
+    pub fn with_output(program: Program, out: Box<dyn Write>) -> Result<Vm, String> {
+        program.verify()?;
+
+        let mut externs = Vec::with_capacity(program.externs.len());
+
+        for &(ref name, arity) in &program.externs {
+            match Builtin::from_name(name) {
+                Some(builtin) if builtin.arity() == arity as usize => externs.push(builtin),
+                _ => {
+                    return Err(format!(
+                        "external function '{}' taking {} argument(s) is not available",
+                        name, arity
+                    ))
+                }
+            }
+        }
+
+        Ok(Vm {
+            globals: vec![0.0; program.globals.len()],
+            program: program,
+            externs: externs,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            out: out,
+        })
+    }
+
+    /// トップレベルの処理を順に実行し、式の値を'on_value'に渡す
+    pub fn run<F: FnMut(f64)>(&mut self, mut on_value: F) -> Result<(), Diagnostic> {
+        for i in 0..self.program.entries.len() {
+            match self.program.entries[i] {
+                Entry::Expr(chunk) => on_value(self.execute(chunk as usize)?),
+                Entry::Global { chunk, global } => {
+                    self.globals[global as usize] = self.execute(chunk as usize)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 引数のない関数を実行し、その戻り値を返す
+    /// エラーの場合は、呼び出し中の関数を全て破棄する
+    fn execute(&mut self, entry: usize) -> Result<f64, Diagnostic> {
+        let result = self.run_frames(entry);
+
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+        }
+
+        result
+    }
+
+    fn run_frames(&mut self, entry: usize) -> Result<f64, Diagnostic> {
+        let Vm {
+            ref program,
+            ref externs,
+            ref mut globals,
+            ref mut stack,
+            ref mut frames,
+            ref mut out,
+        } = *self;
+
+        let mut current = entry;
+        let mut chunk = &program.chunks[current];
+        let mut ip = 0;
+        let mut base = stack.len();
+
+        stack.resize(base + chunk.locals as usize, 0.0);
+
+        // 検証済みのプログラムでは、スタックが空の状態で値を取り出すことはない
+        macro_rules! pop {
+            () => {
+                stack
+                    .pop()
+                    .expect("operand stack underflow in verified bytecode")
+            };
+        }
+
+        loop {
+            let instr = chunk.code[ip];
+
+            ip += 1;
+
+            match instr {
+                Instr::Const(value) => stack.push(value),
+                Instr::Load(slot) => {
+                    let value = stack[base + slot as usize];
+
+                    stack.push(value);
+                }
+                Instr::Store(slot) => stack[base + slot as usize] = pop!(),
+                Instr::LoadGlobal(global) => stack.push(globals[global as usize]),
+                Instr::StoreGlobal(global) => globals[global as usize] = pop!(),
+                Instr::Dup => {
+                    let value = stack[stack.len() - 1];
+
+                    stack.push(value);
+                }
+                Instr::Pop(count) => {
+                    let len = stack.len() - count as usize;
+
+                    stack.truncate(len);
+                }
+                Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Lt | Instr::Gt => {
+                    let rhs = pop!();
+                    let lhs = pop!();
+
+                    stack.push(match instr {
+                        Instr::Add => lhs + rhs,
+                        Instr::Sub => lhs - rhs,
+                        Instr::Mul => lhs * rhs,
+                        Instr::Div => lhs / rhs,
+                        Instr::Lt => (!(lhs >= rhs)) as u8 as f64,
+                        _ => (!(rhs >= lhs)) as u8 as f64,
+                    });
+                }
+                Instr::Jump(target) => ip = target as usize,
+                Instr::JumpIfFalse(target) => {
+                    let cond = pop!();
+
+                    if cond.is_nan() || cond == 0.0 {
+                        ip = target as usize;
+                    }
+                }
+                Instr::Call(callee) => {
+                    if frames.len() >= MAX_CALL_DEPTH {
+                        return Err(Diagnostic::error("Maximum call depth exceeded.")
+                            .with_code("E0400")
+                            .with_note(format!("in function '{}'", chunk.name)));
+                    }
+
+                    frames.push(Frame {
+                        chunk: current,
+                        ip: ip,
+                        base: base,
+                    });
+
+                    current = callee as usize;
+                    chunk = &program.chunks[current];
+                    ip = 0;
+                    base = stack.len() - chunk.arity as usize;
+
+                    stack.resize(base + chunk.locals as usize, 0.0);
+                }
+                Instr::CallExtern(index) => {
+                    let builtin = externs[index as usize];
+                    let args = stack.len() - builtin.arity();
+                    let value = builtin.call(&stack[args..], &mut **out).map_err(|err| {
+                        Diagnostic::error(format!("Could not write output: {}", err))
+                            .with_code("E0400")
+                            .with_note(format!("in function '{}'", chunk.name))
+                    })?;
+
+                    stack.truncate(args);
+                    stack.push(value);
+                }
+                Instr::Return => {
+                    let value = pop!();
+
+                    stack.truncate(base);
+
+                    match frames.pop() {
+                        Some(frame) => {
+                            current = frame.chunk;
+                            chunk = &program.chunks[current];
+                            ip = frame.ip;
+                            base = frame.base;
+
+                            stack.push(value);
+                        }
+                        None => return Ok(value),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ProgramBuilder;
+    use crate::interp::Interpreter;
+    use crate::operators::OperatorTable;
+    use crate::parser::{Item, Parser};
+    use std::time::{Duration, Instant};
+
+    const FIB: &str = "def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2)";
+
+    fn parse(text: &str, operators: &mut OperatorTable) -> Vec<Item> {
+        let (items, diagnostics) = Parser::new(text.to_string(), operators).parse_program();
+
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+        items
+    }
+
+    /// プログラムをバイトコードにコンパイルする
+    fn build(text: &str) -> Result<Program, Diagnostic> {
+        let items = parse(text, &mut OperatorTable::new());
+        let mut builder = ProgramBuilder::new();
+
+        for item in &items {
+            builder.declare(item)?;
+        }
+
+        for item in &items {
+            builder.compile(item)?;
+        }
+
+        Ok(builder.finish())
+    }
+
+    /// プログラムをバイトコードにコンパイルしてVMで実行し、式の値と実行時間を返す
+    fn run_vm(text: &str) -> (Vec<f64>, Duration) {
+        let mut vm = Vm::with_output(build(text).unwrap(), Box::new(io::sink())).unwrap();
+        let mut values = Vec::new();
+        let start = Instant::now();
+
+        vm.run(|value| values.push(value)).unwrap();
+
+        (values, start.elapsed())
+    }
+
+    /// プログラムをインタプリタで評価し、式の値と実行時間を返す
+    fn run_interpreter(text: &str) -> (Vec<f64>, Duration) {
+        let mut interpreter = Interpreter::with_output(Box::new(io::sink()));
+        let items = parse(text, &mut interpreter.operators);
+        let mut values = Vec::new();
+        let start = Instant::now();
+
+        for item in &items {
+            if let Some(value) = interpreter.eval(item).unwrap() {
+                values.extend(value.as_f64());
+            }
+        }
+
+        (values, start.elapsed())
+    }
+
+    #[test]
+    fn fib_matches_interpreter() {
+        let text = format!("{}\nfib(15)\nfib(1) + fib(0)", FIB);
+
+        let (values, _) = run_vm(&text);
+
+        assert_eq!(values, vec![610.0, 1.0]);
+        assert_eq!(values, run_interpreter(&text).0);
+    }
+
+    /// 全ての関数を実行前にコンパイルするVMでは、再定義の前の呼び出しがインタプリタと異なる
+    /// 関数を呼んでしまうため、再定義はコンパイル時に拒否される
+    #[test]
+    fn redefinition_is_rejected() {
+        let text = "def f() 1\nf()\ndef f() 2\nf()";
+
+        assert_eq!(run_interpreter(text).0, vec![1.0, 2.0]);
+
+        let err = build(text).unwrap_err();
+
+        assert_eq!(err.code, Some("E0310"));
+        assert_eq!(err.primary.map(|label| label.span.line), Some(3));
+    }
+
+    /// VMが構文木をたどるインタプリタより、少なくとも'MIN_SPEEDUP'倍速いことを確かめる
+    /// 時間がかかるため、'cargo test --release -- --ignored --nocapture'で明示的に実行する
+    #[test]
+    #[ignore]
+    fn fib_timing() {
+        // 計測した値はリリースビルドで約10倍、デバッグビルドで約7倍
+        const MIN_SPEEDUP: u32 = 3;
+
+        let text = format!("{}\nfib(25)", FIB);
+
+        // ばらつきを抑えるため、それぞれ3回のうち最短の時間で比較する
+        let mut vm_time = Duration::from_secs(u64::max_value());
+        let mut interp_time = vm_time;
+
+        for _ in 0..3 {
+            let (vm_values, vm) = run_vm(&text);
+            let (interp_values, interp) = run_interpreter(&text);
+
+            assert_eq!(vm_values, interp_values);
+
+            vm_time = vm_time.min(vm);
+            interp_time = interp_time.min(interp);
+        }
+
+        println!("fib(25): vm {:?}, interpreter {:?}", vm_time, interp_time);
+
+        assert!(
+            vm_time * MIN_SPEEDUP < interp_time,
+            "the VM ({:?}) is not {} times faster than the interpreter ({:?})",
+            vm_time,
+            MIN_SPEEDUP,
+            interp_time
+        );
+    }
+}